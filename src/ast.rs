@@ -1,91 +1,210 @@
+use std::collections::HashMap;
+
+use crate::error::{LispError, Span};
 use crate::lispobject::{LispObject, LispType};
 
 #[derive(Debug, Clone, PartialEq)]
 struct WorkingLispObject {
-    finished: bool,
+    span: Span,
     objects: Vec<LispObject>,
+    /// Whether the object was opened with `#(`.
+    vector: bool,
+    /// Whether the object was opened with a quote, as in `'(a b)`.
+    quoted: bool,
+    /// The label written in front of the object, as in `#1=(a b)`.
+    label: Option<String>,
 }
 
 impl WorkingLispObject {
-    fn new() -> Self {
+    fn new(span: Span, open: &str, label: Option<String>) -> Self {
         Self {
-            finished: false,
+            span,
             objects: vec![],
+            vector: open.ends_with("#("),
+            quoted: open.starts_with('\''),
+            label,
         }
     }
 
-    fn merge(&mut self, obj: Self) -> Result<(), Self> {
-        if !self.finished {
-            self.objects.push(obj.into());
-            return Ok(());
-        }
-        Err(obj)
+    fn push(&mut self, obj: LispObject) {
+        self.objects.push(obj);
     }
+}
 
-    fn set_done(&mut self) {
-        self.finished = true;
-    }
+impl TryFrom<WorkingLispObject> for LispObject {
+    type Error = LispError;
 
-    fn push(&mut self, obj: LispObject) {
-        self.objects.push(obj);
+    /// A `.` before the last element of a list makes it the cdr of the last
+    /// cons pair, as in `(a . b)`.
+    fn try_from(mut this: WorkingLispObject) -> Result<Self, LispError> {
+        let dot = this.objects.iter().position(|o| o.as_symbol() == Some("."));
+        let obj = match dot {
+            None if this.vector => LispObject::vector(this.objects),
+            None if this.objects.is_empty() => LispObject::nil(),
+            None => LispObject::new_with(LispType::list(this.objects), false),
+            Some(i) if !this.vector && i > 0 && i + 2 == this.objects.len() => {
+                let tail = this.objects.pop().unwrap();
+                this.objects.pop();
+                match tail.ltype() {
+                    LispType::Bool(false) => {
+                        LispObject::new_with(LispType::list(this.objects), false)
+                    }
+                    LispType::List(l) if !tail.is_quoted() => {
+                        this.objects.extend(l.iter().cloned());
+                        LispObject::new_with(LispType::list(this.objects), false)
+                    }
+                    _ => this
+                        .objects
+                        .into_iter()
+                        .rev()
+                        .fold(tail, |cdr, car| LispObject::cons(car, cdr)),
+                }
+            }
+            Some(_) => return Err(LispError::parsing_error("Misplaced .", this.span)),
+        };
+        Ok(LispObject::new_with(obj.into_type(), this.quoted).with_span(this.span))
     }
 }
 
-impl From<WorkingLispObject> for LispObject {
-    fn from(this: WorkingLispObject) -> Self {
-        if this.objects.is_empty() {
-            LispObject::nil()
+/// The number of a label definition `#1=` or a reference `#1#`.
+fn label(token: &str, end: char) -> Option<&str> {
+    let n = token.strip_prefix('#')?.strip_suffix(end)?;
+    (!n.is_empty() && n.chars().all(|c| c.is_ascii_digit())).then_some(n)
+}
+
+/// Splits the code into parens and atoms and remembers where each token
+/// starts. A string literal is a single token, quotes included, and so are
+/// the `#(` that opens a vector, a quote in front of a paren and a label
+/// `#1=`.
+fn tokenize(code: &str) -> Result<Vec<(String, Span)>, LispError> {
+    let mut tokens = vec![];
+    let mut current: Option<(String, Span)> = None;
+    let mut span = Span { line: 1, column: 1 };
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in code.chars() {
+        if in_string {
+            let (token, _) = current.as_mut().unwrap();
+            token.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                tokens.extend(current.take());
+            }
+        } else if c == '('
+            && matches!(&current, Some((token, _)) if ["#", "'", "'#"].contains(&token.as_str()))
+        {
+            let (token, start) = current.take().unwrap();
+            tokens.push((token + "(", start));
+        } else if c.is_whitespace() || c == '(' || c == ')' {
+            tokens.extend(current.take());
+            if !c.is_whitespace() {
+                tokens.push((c.to_string(), span));
+            }
         } else {
-            LispObject::new_with(LispType::List(this.objects), false)
+            match current.as_mut() {
+                Some((token, _)) => {
+                    // A string may only follow the quote of a literal.
+                    in_string = c == '"' && token == "'";
+                    token.push(c);
+                    if label(token, '=').is_some() {
+                        tokens.extend(current.take());
+                    }
+                }
+                None => {
+                    in_string = c == '"';
+                    current = Some((c.to_string(), span));
+                }
+            }
         }
+
+        if c == '\n' {
+            span.line += 1;
+            span.column = 1;
+        } else {
+            span.column += 1;
+        }
+    }
+    if in_string {
+        let (_, start) = current.unwrap();
+        return Err(LispError::parsing_error("Unclosed string", start));
     }
+    tokens.extend(current);
+    Ok(tokens)
 }
 
-pub fn ast(code: &str) -> Result<Vec<LispObject>, &'static str> {
-    let tokens = code
-        .replace('(', " ( ")
-        .replace(')', " ) ")
-        .split(' ')
-        .map(|e| e.to_string())
-        .filter(|x| !x.is_empty())
-        .filter(|x| !x.contains('\n'))
-        .collect::<Vec<String>>();
-
+/// Reads the forms of the code. An object labelled with `#1=` can be
+/// referred to with `#1#` after it was read, which shares it.
+pub fn ast(code: &str) -> Result<Vec<LispObject>, LispError> {
+    let mut forms = vec![];
     let mut stack: Vec<WorkingLispObject> = vec![];
+    let mut labels: HashMap<String, LispObject> = HashMap::new();
+    let mut pending: Option<(String, Span)> = None;
 
-    for token in tokens {
-        if token == "(" {
-            stack.push(WorkingLispObject::new());
+    for (token, span) in tokenize(code)? {
+        let (obj, label) = if let Some(n) = label(&token, '=') {
+            if pending.is_some() {
+                return Err(LispError::parsing_error("Label without an object", span));
+            }
+            pending = Some((n.to_string(), span));
+            continue;
+        } else if token.ends_with('(') {
+            let label = pending.take().map(|(n, _)| n);
+            stack.push(WorkingLispObject::new(span, &token, label));
+            continue;
         } else if token == ")" {
-            let mut elem = stack.pop().unwrap();
-            elem.set_done();
-            match stack.iter_mut().last() {
-                Some(merger) => {
-                    let merge_status = merger.merge(elem);
-                    if merge_status.is_err() {
-                        stack.push(merge_status.err().unwrap())
-                    }
+            let elem = match stack.pop() {
+                Some(elem) => elem,
+                None => return Err(LispError::parsing_error("Unexpected )", span)),
+            };
+            if pending.is_some() {
+                return Err(LispError::parsing_error("Label without an object", span));
+            }
+            let label = elem.label.clone();
+            (LispObject::try_from(elem)?, label)
+        } else if let Some(n) = label(token.trim_start_matches('\''), '#') {
+            // The object is not read completely while it is inside itself,
+            // so circular structure can not be read.
+            match labels.get(n) {
+                Some(obj) => {
+                    let obj = LispObject::new_with(obj.get_type(), token.starts_with('\''));
+                    (obj, pending.take().map(|(n, _)| n))
+                }
+                None => {
+                    let message = format!("Undefined label {}", token);
+                    return Err(LispError::parsing_error(message, span));
                 }
-                None => stack.push(elem),
             }
         } else {
-            stack
-                .iter_mut()
-                .last()
-                .unwrap()
-                .push(LispObject::new(token.as_str()));
+            let obj = LispObject::new(token.as_str()).with_span(span);
+            (obj, pending.take().map(|(n, _)| n))
+        };
+
+        if let Some(n) = label {
+            labels.insert(n, obj.clone());
+        }
+        match stack.last_mut() {
+            Some(parent) => parent.push(obj),
+            None => forms.push(obj),
         }
     }
 
-    Ok(stack
-        .iter()
-        .cloned()
-        .map(|e| e.into())
-        .collect::<Vec<LispObject>>())
+    if let Some((_, span)) = pending {
+        return Err(LispError::parsing_error("Label without an object", span));
+    }
+    match stack.pop() {
+        Some(unclosed) => Err(LispError::parsing_error("Unclosed (", unclosed.span)),
+        None => Ok(forms),
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::Span;
     use crate::evaluator::eval;
     use crate::lispobject::{LispObject, LispType};
     use crate::objectmanager::Manager;
@@ -96,17 +215,17 @@ mod tests {
     fn test_ast_gen() {
         let test = "(test (+ 1 1) 2)";
         let exp = LispObject::new_with(
-            LispType::List(vec![
+            LispType::list(vec![
                 LispObject::symbol("test"),
                 LispObject::new_with(
-                    LispType::List(vec![
+                    LispType::list(vec![
                         LispObject::symbol("+"),
-                        LispObject::number(1.),
-                        LispObject::number(1.),
+                        LispObject::integer(1),
+                        LispObject::integer(1),
                     ]),
                     false,
                 ),
-                LispObject::number(2.),
+                LispObject::integer(2),
             ]),
             false,
         );
@@ -117,17 +236,17 @@ mod tests {
     fn test_ast_gen_multiple() {
         let test = "(+ (+ 2 3) 4)";
         let exp = LispObject::new_with(
-            LispType::List(vec![
+            LispType::list(vec![
                 LispObject::symbol("+"),
                 LispObject::new_with(
-                    LispType::List(vec![
+                    LispType::list(vec![
                         LispObject::symbol("+"),
-                        LispObject::number(2.),
-                        LispObject::number(3.),
+                        LispObject::integer(2),
+                        LispObject::integer(3),
                     ]),
                     false,
                 ),
-                LispObject::number(4.),
+                LispObject::integer(4),
             ]),
             false,
         );
@@ -135,7 +254,96 @@ mod tests {
         assert_eq!(res[0], exp);
         assert_eq!(
             eval(res[0].clone(), &mut Manager::default()).unwrap(),
-            LispObject::number(9.)
+            LispObject::integer(9)
         )
     }
+
+    #[test]
+    fn test_ast_multiple_forms() {
+        let res = ast("(a b)\n  c\n(d)").unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[1], LispObject::symbol("c"));
+        assert_eq!(res[0].get_string(), "(a b)");
+        assert_eq!(res[2].get_string(), "(d)");
+    }
+
+    #[test]
+    fn test_ast_spans() {
+        let res = ast("(a\n  (b c))").unwrap();
+        assert_eq!(res[0].span(), Some(Span { line: 1, column: 1 }));
+        if let LispType::List(l) = res[0].get_type() {
+            assert_eq!(l[0].span(), Some(Span { line: 1, column: 2 }));
+            assert_eq!(l[1].span(), Some(Span { line: 2, column: 3 }));
+        } else {
+            panic!("Expected a list.");
+        }
+    }
+
+    #[test]
+    fn test_ast_errors() {
+        let err = ast("(a))").unwrap_err();
+        assert_eq!(err.span(), Some(Span { line: 1, column: 4 }));
+        let err = ast("(a\n (b)").unwrap_err();
+        assert_eq!(err.span(), Some(Span { line: 1, column: 1 }));
+        assert_eq!(err.to_string(), "1:1: parse-error: Unclosed (");
+        let err = ast("(a \"b)").unwrap_err();
+        assert_eq!(err.to_string(), "1:4: parse-error: Unclosed string");
+    }
+
+    #[test]
+    fn test_ast_strings() {
+        let res = ast("(a \"b (c) \\\" d\" '\"e\")").unwrap();
+        let expected = [
+            LispObject::symbol("a"),
+            LispObject::string("b (c) \" d"),
+            LispObject::string("e").move_quoted(),
+        ];
+        assert_eq!(res[0].as_list().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_ast_vectors() {
+        let res = ast("#(1 (a) #()) (f #(b))").unwrap();
+        let expected = LispObject::vector(vec![
+            LispObject::integer(1),
+            LispObject::list(&[LispObject::symbol("a")]),
+            LispObject::vector(vec![]),
+        ]);
+        assert_eq!(res[0], expected);
+        assert_eq!(res[0].span(), Some(Span { line: 1, column: 1 }));
+        assert_eq!(res[1].to_string(), "(f #(b))");
+    }
+
+    #[test]
+    fn test_ast_dotted_and_quoted() {
+        let res = ast("(a . b) (a b . (c)) (a . nil) '(1 2) '#(x)").unwrap();
+        let pair = LispObject::cons(LispObject::symbol("a"), LispObject::symbol("b"));
+        assert_eq!(res[0], pair);
+        assert_eq!(res[1].to_string(), "(a b c)");
+        assert_eq!(res[2].to_string(), "(a)");
+        assert_eq!(res[3].to_string(), "'(1 2)");
+        assert!(res[3].is_quoted());
+        assert_eq!(res[4].to_string(), "'#(x)");
+        for code in ["(. a)", "(a . b c)", "#(a . b)"] {
+            let err = ast(code).unwrap_err();
+            assert_eq!(err.to_string(), "1:1: parse-error: Misplaced .", "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_ast_labels() {
+        let res = ast("(#1=(a) #1# '#1# #2=b #2#)").unwrap();
+        let list = res[0].as_list().unwrap();
+        match (list[0].ltype(), list[1].ltype()) {
+            (LispType::List(a), LispType::List(b)) => assert!(a.ptr_eq(b)),
+            _ => panic!("Expected lists."),
+        }
+        assert!(list[2].is_quoted());
+        assert_eq!(list[4], LispObject::symbol("b"));
+
+        let err = ast("#1=(a #1#)").unwrap_err();
+        assert_eq!(err.to_string(), "1:7: parse-error: Undefined label #1#");
+        let err = ast("(a #1=)").unwrap_err();
+        assert_eq!(err.to_string(), "1:7: parse-error: Label without an object");
+    }
 }
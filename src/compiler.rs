@@ -0,0 +1,341 @@
+use std::rc::Rc;
+
+use crate::error::Span;
+use crate::evaluator::{parse_params, SPECIAL_FORMS};
+use crate::lispobject::{Address, LispObject, LispType, Params};
+use crate::resolver::MAX_NESTING;
+
+/// An instruction of the virtual machine. Operands index into the tables of
+/// the `Code` they belong to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Pushes a constant.
+    Const(usize),
+    /// Pushes the local variable at the address. The constant is its name,
+    /// which is used if the address can not be followed.
+    Local(Address, usize),
+    /// Pushes the variable named by the constant, looked up by name.
+    Global(usize),
+    /// Pushes the function named by the constant `name`. A macro is expanded
+    /// by evaluating the call form in the constant `form` instead, after
+    /// which execution continues at `skip`.
+    Function {
+        name: usize,
+        form: usize,
+        skip: usize,
+    },
+    /// Calls a function with that many arguments. The function is below the
+    /// arguments on the stack.
+    Call(usize),
+    Pop,
+    Jump(usize),
+    /// Pops a value and jumps if it is false.
+    JumpIfNot(usize),
+    /// Pushes a closure over the current frame.
+    Closure(usize),
+    /// Defines the function or macro of the proto from a closure over the
+    /// current frame and pushes nil.
+    Define {
+        proto: usize,
+        is_macro: bool,
+    },
+    /// Runs the code of a block in a new frame.
+    Block(usize),
+    /// Hands the constant form to the evaluator, for forms the compiler
+    /// does not know.
+    Eval(usize),
+    /// Returns the value on top of the stack.
+    Return,
+}
+
+/// A compiled function body.
+#[derive(Debug, Default, PartialEq)]
+pub struct Code {
+    pub ops: Vec<Op>,
+    /// The position of the form each instruction was compiled from.
+    pub spans: Vec<Option<Span>>,
+    pub constants: Vec<LispObject>,
+    pub protos: Vec<Rc<Proto>>,
+    /// The name and the body of every block.
+    pub blocks: Vec<(String, Rc<Code>)>,
+}
+
+/// A lambda without its environment, from which `Op::Closure` creates
+/// closures.
+#[derive(Debug, PartialEq)]
+pub struct Proto {
+    pub name: Option<String>,
+    pub params: Params,
+    pub body: Vec<LispObject>,
+    pub code: Rc<Code>,
+}
+
+/// Compiles the forms of a body. The code returns the value of the last one.
+pub fn compile(body: &[LispObject]) -> Code {
+    compile_nested(body, 0)
+}
+
+/// Forms nested deeper than `MAX_NESTING` are handed to the evaluator, so
+/// the recursion of the compiler stays off deeply nested code.
+fn compile_nested(body: &[LispObject], nesting: usize) -> Code {
+    let mut code = Code::default();
+    code.body(body, None, nesting);
+    code.emit(Op::Return, None);
+    code
+}
+
+impl Code {
+    fn emit(&mut self, op: Op, span: Option<Span>) -> usize {
+        self.ops.push(op);
+        self.spans.push(span);
+        self.ops.len() - 1
+    }
+
+    fn constant(&mut self, obj: LispObject) -> usize {
+        self.constants.push(obj);
+        self.constants.len() - 1
+    }
+
+    /// Points the jump at index `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.ops.len();
+        match &mut self.ops[at] {
+            Op::Jump(t) | Op::JumpIfNot(t) | Op::Function { skip: t, .. } => *t = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn body(&mut self, body: &[LispObject], span: Option<Span>, nesting: usize) {
+        if body.is_empty() {
+            let nil = self.constant(LispObject::nil());
+            self.emit(Op::Const(nil), span);
+        }
+        for (i, form) in body.iter().enumerate() {
+            if i > 0 {
+                self.emit(Op::Pop, span);
+            }
+            self.form(form, nesting);
+        }
+    }
+
+    fn form(&mut self, obj: &LispObject, nesting: usize) {
+        let span = obj.span();
+        if obj.is_quoted() {
+            let i = self.constant(obj.clone());
+            self.emit(Op::Const(i), span);
+            return;
+        }
+
+        let list = match obj.ltype() {
+            LispType::Symbol(_) => {
+                let name = self.constant(LispObject::symbol(obj.as_symbol().unwrap()));
+                match obj.address() {
+                    Some(address) => self.emit(Op::Local(address, name), span),
+                    None => self.emit(Op::Global(name), span),
+                };
+                return;
+            }
+            LispType::List(l) if !l.is_empty() => l,
+            _ => {
+                let i = self.constant(LispObject::new_with(obj.get_type(), false));
+                self.emit(Op::Const(i), span);
+                return;
+            }
+        };
+        if nesting > MAX_NESTING {
+            return self.eval(obj);
+        }
+        let nesting = nesting + 1;
+
+        let name = match list[0].as_symbol() {
+            Some(name) => name,
+            None => return self.eval(obj),
+        };
+        let args = &list[1..];
+        match name {
+            "quote" if !args.is_empty() => {
+                let i = self.constant(args[0].clone().move_quoted());
+                self.emit(Op::Const(i), span);
+            }
+            "progn" => self.body(args, span, nesting),
+            "if" if args.len() >= 2 => {
+                self.form(&args[0], nesting);
+                let otherwise = self.emit(Op::JumpIfNot(0), span);
+                self.form(&args[1], nesting);
+                let end = self.emit(Op::Jump(0), span);
+                self.patch(otherwise);
+                self.body(&args[2..], span, nesting);
+                self.patch(end);
+            }
+            "lambda" if !args.is_empty() => match parse_params(args[0].clone()) {
+                Ok(params) => {
+                    let proto = self.proto(None, params, args[1..].to_vec(), nesting);
+                    self.emit(Op::Closure(proto), span);
+                }
+                Err(_) => self.eval(obj),
+            },
+            "defun" | "defmacro" if args.len() >= 2 => {
+                let fn_name = match args[0].as_symbol() {
+                    Some(fn_name) => fn_name.to_string(),
+                    None => return self.eval(obj),
+                };
+                let params = match parse_params(args[1].clone()) {
+                    Ok(params) => params,
+                    Err(_) => return self.eval(obj),
+                };
+                // Like the evaluator, the body is wrapped in a block.
+                let mut block = vec![LispObject::symbol("block"), LispObject::symbol(&fn_name)];
+                block.extend(args[2..].iter().cloned());
+                let body = vec![LispObject::new_with(LispType::list(block), false)];
+                let proto = self.proto(Some(fn_name), params, body, nesting);
+                let is_macro = name == "defmacro";
+                self.emit(Op::Define { proto, is_macro }, span);
+            }
+            "block" if !args.is_empty() => {
+                let block_name = match args[0].ltype() {
+                    LispType::Symbol(block_name) => block_name.clone(),
+                    LispType::Bool(false) => "nil".to_string(),
+                    _ => return self.eval(obj),
+                };
+                let code = Rc::new(compile_nested(&args[1..], nesting));
+                self.blocks.push((block_name, code));
+                self.emit(Op::Block(self.blocks.len() - 1), span);
+            }
+            _ if SPECIAL_FORMS.contains(&name) => self.eval(obj),
+            _ => {
+                let name = self.constant(LispObject::symbol(name));
+                let form = self.constant(obj.clone());
+                let function = self.emit(
+                    Op::Function {
+                        name,
+                        form,
+                        skip: 0,
+                    },
+                    span,
+                );
+                for arg in args {
+                    self.form(arg, nesting);
+                }
+                self.emit(Op::Call(args.len()), span);
+                self.patch(function);
+            }
+        }
+    }
+
+    fn proto(
+        &mut self,
+        name: Option<String>,
+        params: Params,
+        body: Vec<LispObject>,
+        nesting: usize,
+    ) -> usize {
+        let code = Rc::new(compile_nested(&body, nesting));
+        self.protos.push(Rc::new(Proto {
+            name,
+            params,
+            body,
+            code,
+        }));
+        self.protos.len() - 1
+    }
+
+    fn eval(&mut self, obj: &LispObject) {
+        let i = self.constant(obj.clone());
+        self.emit(Op::Eval(i), obj.span());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ast;
+    use crate::resolver::resolve;
+
+    fn compile_str(code: &str) -> Code {
+        let body = resolve(ast(code).unwrap().remove(0)).into_list().unwrap();
+        // The body of `(lambda (x) ...)`.
+        compile(&body[2..])
+    }
+
+    #[test]
+    fn test_compile_call() {
+        let code = compile_str("(lambda (x) (add x 1))");
+        let local = Address { depth: 0, index: 0 };
+        assert_eq!(
+            code.ops,
+            [
+                Op::Function {
+                    name: 0,
+                    form: 1,
+                    skip: 4
+                },
+                Op::Local(local, 2),
+                Op::Const(3),
+                Op::Call(2),
+                Op::Return,
+            ]
+        );
+        assert_eq!(code.constants[0], LispObject::symbol("add"));
+    }
+
+    #[test]
+    fn test_compile_if() {
+        let code = compile_str("(lambda (x) (if x 'yes 'no 'other))");
+        assert_eq!(
+            code.ops,
+            [
+                Op::Local(Address { depth: 0, index: 0 }, 0),
+                Op::JumpIfNot(4),
+                Op::Const(1),
+                Op::Jump(7),
+                Op::Const(2),
+                Op::Pop,
+                Op::Const(3),
+                Op::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_nested() {
+        let code = compile_str("(lambda (x) (block b (lambda (y) y)) (handler-case x))");
+        assert_eq!(code.ops, [Op::Block(0), Op::Pop, Op::Eval(0), Op::Return]);
+        let (name, block) = &code.blocks[0];
+        assert_eq!(name, "b");
+        assert_eq!(block.ops, [Op::Closure(0), Op::Return]);
+        let local = Address { depth: 0, index: 0 };
+        assert_eq!(block.protos[0].code.ops, [Op::Local(local, 0), Op::Return]);
+    }
+
+    #[test]
+    fn test_compile_deep_nesting() {
+        let depth = 20_000;
+        let body = "(add 1 ".repeat(depth) + "x" + &")".repeat(depth);
+        let code = compile_str(&format!("(lambda (x) {})", body));
+        // The calls below the limit are compiled, the rest is evaluated.
+        let count = |f: fn(&Op) -> bool| code.ops.iter().filter(|op| f(op)).count();
+        assert_eq!(count(|op| matches!(op, Op::Call(_))), MAX_NESTING + 1);
+        assert_eq!(count(|op| matches!(op, Op::Eval(_))), 1);
+    }
+
+    #[test]
+    fn test_compile_defun() {
+        let body = [resolve(ast("(defmacro m (x) x)").unwrap().remove(0))];
+        let code = compile(&body);
+        let proto = 0;
+        assert_eq!(
+            code.ops,
+            [
+                Op::Define {
+                    proto,
+                    is_macro: true
+                },
+                Op::Return
+            ]
+        );
+        // The body is wrapped in a block named like the function.
+        assert_eq!(code.protos[0].name.as_deref(), Some("m"));
+        assert_eq!(code.protos[0].code.ops, [Op::Block(0), Op::Return]);
+        assert_eq!(code.protos[0].code.blocks[0].0, "m");
+    }
+}
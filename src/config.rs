@@ -1,19 +1,38 @@
 use std::{fs, process};
 
-const HELP_MSG: &'static str = "dlisp [FLAGS] [LISP]
+use crate::error::LispError;
+use crate::objectmanager::{Engine, Namespace, DEFAULT_MAX_DEPTH};
+
+const HELP_MSG: &str = "dlisp [FLAGS] [LISP]
     -f FILE     Eval specified file.
+    -d DEPTH    Maximum depth of the evaluation stack.
+    -s          Keep functions and variables in separate namespaces.
+    -b          Compile functions to bytecode and run them on a virtual machine.
+    --compile   Compile the file given with -f to a .dlc file instead of running it.
+    --dump-optimized
+                Print every form of the file after optimizing it.
     -v          Print version.
     -h          Show this help message.";
 
 pub struct Config {
     pub eval_input: bool,
     pub file: Option<String>,
+    pub max_depth: usize,
+    pub namespace: Namespace,
+    pub engine: Engine,
+    pub compile: bool,
+    pub dump_optimized: bool,
 }
 
 impl Config {
     pub fn new(args: Vec<String>) -> Self {
         let eval_input = false;
         let mut file = None;
+        let mut max_depth = DEFAULT_MAX_DEPTH;
+        let mut namespace = Namespace::Unified;
+        let mut engine = Engine::Tree;
+        let mut compile = false;
+        let mut dump_optimized = false;
         let mut args = args.iter();
         while let Some(arg) = args.next() {
             match arg.as_str() {
@@ -28,17 +47,73 @@ impl Config {
                     println!("{}", HELP_MSG);
                     process::exit(0);
                 }
-                "-f" => file = Some(args.next().unwrap().to_string()),
+                "-f" => {
+                    file = match args.next() {
+                        Some(file) => Some(file.to_string()),
+                        None => {
+                            eprintln!("[ERROR] {} expects a file.", arg);
+                            process::exit(-1);
+                        }
+                    }
+                }
+                "-d" | "--max-depth" => {
+                    let depth = args.next().and_then(|d| d.parse().ok());
+                    max_depth = match depth.filter(|depth| *depth > 0) {
+                        Some(depth) => depth,
+                        None => {
+                            eprintln!("[ERROR] {} expects a positive number.", arg);
+                            process::exit(-1);
+                        }
+                    }
+                }
+                "-s" | "--separate-namespaces" => namespace = Namespace::Separate,
+                "-b" | "--bytecode" => engine = Engine::Vm,
+                "--compile" => compile = true,
+                "--dump-optimized" => dump_optimized = true,
                 _ => {}
             }
         }
-        Self { eval_input, file }
+        Self {
+            eval_input,
+            file,
+            max_depth,
+            namespace,
+            engine,
+            compile,
+            dump_optimized,
+        }
+    }
+
+    /// Whether the file was compiled with `--compile`.
+    pub fn is_compiled_file(&self) -> bool {
+        self.file
+            .as_ref()
+            .is_some_and(|file| file.ends_with(".dlc"))
+    }
+
+    pub fn get_file_bytes(&self) -> Result<Option<Vec<u8>>, LispError> {
+        match &self.file {
+            Some(file) => match fs::read(file) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(err) => Err(LispError::runtime_error(format!(
+                    "Could not read {}: {}",
+                    file, err
+                ))),
+            },
+            None => Ok(None),
+        }
     }
 
-    pub fn get_file_string(&self) -> Option<String> {
-        if self.file.is_some() {
-            return Some(fs::read_to_string(self.file.as_ref().unwrap()).unwrap());
+    pub fn get_file_string(&self) -> Result<Option<String>, LispError> {
+        match &self.file {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(code) => Ok(Some(code)),
+                Err(err) => Err(LispError::runtime_error(format!(
+                    "Could not read {}: {}",
+                    file, err
+                ))),
+            },
+            None => Ok(None),
         }
-        None
     }
 }
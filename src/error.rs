@@ -1,57 +1,221 @@
 use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-struct LispError {
-    explanation: Option<String>,
+use crate::lispobject::LispObject;
+
+/// Position in the source code. Lines and columns are counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A call of a function that was active when an error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub name: String,
+    pub args: Vec<LispObject>,
+    /// Position of the call form.
+    pub span: Option<Span>,
+}
+
+impl Display for CallFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(f, "({}", self.name)?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg)?;
+        }
+        write!(f, ")")?;
+        if let Some(span) = self.span {
+            write!(f, " at {}", span)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LispError {
     err_type: ErrorType,
+    message: String,
+    /// The values that caused the error.
+    objects: Vec<LispObject>,
+    span: Option<Span>,
+    /// The calls that were active, from the innermost to the outermost.
+    backtrace: Vec<CallFrame>,
 }
 
 impl Error for LispError {}
 
 impl Display for LispError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
-        if self.explanation.is_some() {
-            write!(
-                f,
-                "{} => {}",
-                self.err_type,
-                self.explanation.clone().unwrap()
-            )
-        } else {
-            write!(f, "{}", self.err_type)
+        if let Some(span) = self.span {
+            write!(f, "{}: ", span)?;
         }
+        write!(f, "{}", self.err_type)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        if !self.objects.is_empty() {
+            let objects = self
+                .objects
+                .iter()
+                .map(|o| o.to_string())
+                .collect::<Vec<String>>();
+            write!(f, ": {}", objects.join(", "))?;
+        }
+        Ok(())
     }
 }
 
 impl LispError {
-    pub fn parsing_error(line: usize, file_name: String) -> Self {
+    pub fn new<T: ToString>(err_type: ErrorType, message: T) -> Self {
         Self {
-            explanation: None,
-            err_type: ErrorType::ParsingError {
-                line,
-                file: file_name,
-            },
+            err_type,
+            message: message.to_string(),
+            objects: vec![],
+            span: None,
+            backtrace: vec![],
         }
     }
 
-    pub fn runtime_error<T: ToString>(obj: T) -> Self {
-        Self {
-            explanation: None,
-            err_type: ErrorType::RuntimeError(obj.to_string()),
+    pub fn parsing_error<T: ToString>(message: T, span: Span) -> Self {
+        Self::new(ErrorType::ParsingError, message).with_span(Some(span))
+    }
+
+    pub fn runtime_error<T: ToString>(message: T) -> Self {
+        Self::new(ErrorType::RuntimeError, message)
+    }
+
+    pub fn type_error<T: ToString>(message: T, obj: LispObject) -> Self {
+        Self::new(ErrorType::TypeError, message).with_object(obj)
+    }
+
+    pub fn undefined_function<T: ToString>(name: T) -> Self {
+        Self::new(ErrorType::UndefinedFunction, "No function with that name")
+            .with_object(LispObject::symbol(name))
+    }
+
+    pub fn wrong_number_of_arguments<T: ToString>(name: T, got: usize) -> Self {
+        Self::new(
+            ErrorType::WrongNumberOfArguments,
+            format!("{} got {} arguments", name.to_string(), got),
+        )
+    }
+
+    pub fn not_enough_arguments() -> Self {
+        Self::new(ErrorType::WrongNumberOfArguments, "Not enough arguments")
+    }
+
+    pub fn division_by_zero(obj: LispObject) -> Self {
+        Self::new(ErrorType::DivisionByZero, "Division by zero").with_object(obj)
+    }
+
+    pub fn stack_overflow() -> Self {
+        Self::new(
+            ErrorType::StackOverflow,
+            "Maximum evaluation depth exceeded",
+        )
+    }
+
+    pub fn with_object(mut self, obj: LispObject) -> Self {
+        self.objects.push(obj);
+        self
+    }
+
+    /// Sets the span, unless the error already knows where it happened.
+    pub fn with_span(mut self, span: Option<Span>) -> Self {
+        if self.span.is_none() {
+            self.span = span;
         }
+        self
     }
 
-    pub fn add_reason<T: ToString>(mut self, reason: T) -> Self {
-        self.explanation = Some(reason.to_string());
+    pub fn with_backtrace(mut self, backtrace: Vec<CallFrame>) -> Self {
+        self.backtrace = backtrace;
         self
     }
+
+    pub fn err_type(&self) -> &ErrorType {
+        &self.err_type
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn objects(&self) -> &[LispObject] {
+        &self.objects
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn backtrace(&self) -> &[CallFrame] {
+        &self.backtrace
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
-enum ErrorType {
-    ParsingError { line: usize, file: String },
-    RuntimeError(String),
+/// The type of a condition. The types form a hierarchy with `condition` at
+/// its root, see `ErrorType::parent`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorType {
+    Condition,
+    Warning,
+    Error,
+    ParsingError,
+    /// An error of the interpreter that has no more specific type.
+    RuntimeError,
+    /// An error signaled with a message, as in `(error "message")`.
+    SimpleError,
+    TypeError,
+    WrongNumberOfArguments,
+    UndefinedFunction,
+    StackOverflow,
+    ControlError,
+    ArithmeticError,
+    DivisionByZero,
+    /// A type defined in lisp with `define-condition`, or a type that is
+    /// only named by `error` or `signal`.
+    Custom(String),
+}
+
+impl ErrorType {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "condition" => ErrorType::Condition,
+            "warning" => ErrorType::Warning,
+            "error" => ErrorType::Error,
+            "parse-error" => ErrorType::ParsingError,
+            "runtime-error" => ErrorType::RuntimeError,
+            "simple-error" => ErrorType::SimpleError,
+            "type-error" => ErrorType::TypeError,
+            "wrong-number-of-arguments" => ErrorType::WrongNumberOfArguments,
+            "undefined-function" => ErrorType::UndefinedFunction,
+            "stack-overflow" => ErrorType::StackOverflow,
+            "control-error" => ErrorType::ControlError,
+            "arithmetic-error" => ErrorType::ArithmeticError,
+            "division-by-zero" => ErrorType::DivisionByZero,
+            _ => ErrorType::Custom(name.to_string()),
+        }
+    }
+
+    /// The direct supertype. Custom types default to `error`; the manager
+    /// knows the parents of types created with `define-condition`.
+    pub fn parent(&self) -> Option<ErrorType> {
+        match self {
+            ErrorType::Condition => None,
+            ErrorType::Warning | ErrorType::Error => Some(ErrorType::Condition),
+            ErrorType::DivisionByZero => Some(ErrorType::ArithmeticError),
+            _ => Some(ErrorType::Error),
+        }
+    }
 }
 
 impl Display for ErrorType {
@@ -60,8 +224,20 @@ impl Display for ErrorType {
             f,
             "{}",
             match self {
-                ErrorType::ParsingError { line, file } => format!("{}: {}", line, file),
-                ErrorType::RuntimeError(err) => err.to_string(),
+                ErrorType::Condition => "condition",
+                ErrorType::Warning => "warning",
+                ErrorType::Error => "error",
+                ErrorType::ParsingError => "parse-error",
+                ErrorType::RuntimeError => "runtime-error",
+                ErrorType::SimpleError => "simple-error",
+                ErrorType::TypeError => "type-error",
+                ErrorType::WrongNumberOfArguments => "wrong-number-of-arguments",
+                ErrorType::UndefinedFunction => "undefined-function",
+                ErrorType::StackOverflow => "stack-overflow",
+                ErrorType::ControlError => "control-error",
+                ErrorType::ArithmeticError => "arithmetic-error",
+                ErrorType::DivisionByZero => "division-by-zero",
+                ErrorType::Custom(name) => name,
             }
         )
     }
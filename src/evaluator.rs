@@ -1,172 +1,2089 @@
-use crate::functions::call_builtin;
-use crate::lispobject::{LispObject, LispType};
-use crate::objectmanager::Manager;
+use std::rc::Rc;
+use std::vec::IntoIter;
 
-pub fn eval(obj: LispObject, manager: &mut Manager) -> Result<LispObject, &'static str> {
+use crate::compiler::{compile, Code, Op};
+use crate::error::{CallFrame, ErrorType, LispError, Span};
+use crate::functions::{call_builtin, is_builtin};
+use crate::higherorder::{Iteration, Next, HIGHER_ORDER};
+use crate::lispobject::{Function, Lambda, LispObject, LispType, Params};
+use crate::objectmanager::{Engine, Manager, Namespace};
+use crate::optimizer::optimize;
+use crate::resolver::resolve;
+
+/// Builtins that need access to the evaluator and are therefore not part of
+/// `functions::call_builtin`.
+const EVAL_BUILTINS: &[&str] = &[
+    "set",
+    "funcall",
+    "apply",
+    "eval",
+    "error",
+    "signal",
+    "invoke-restart",
+    "throw",
+    "backtrace",
+    "gc",
+    "gc-stats",
+];
+
+/// The forms `eval_list` handles itself instead of calling a function.
+pub const SPECIAL_FORMS: &[&str] = &[
+    "quote",
+    "defun",
+    "defmacro",
+    "lambda",
+    "function",
+    "if",
+    "progn",
+    "handler-case",
+    "handler-bind",
+    "unwind-protect",
+    "restart-case",
+    "catch",
+    "block",
+    "return-from",
+    "return",
+    "define-condition",
+];
+
+/// The next thing the evaluation loop has to do.
+enum Step {
+    /// Evaluate an object.
+    Eval(LispObject),
+    /// Run compiled code from the start.
+    Run(Rc<Code>),
+    /// Hand a finished value to the innermost continuation.
+    Return(LispObject),
+    /// Nothing handled the error and the stack is unwound.
+    Fail(LispError),
+}
+
+/// Work that is left to do once the value of a subform is known. The
+/// continuations are kept in a `Vec` on the heap, so the nesting depth of a
+/// program is limited by `Manager::max_depth` instead of the Rust stack.
+enum Cont {
+    /// The arguments of a call are evaluated from left to right. `done` holds
+    /// the values of the arguments that were already evaluated.
+    Args {
+        func: LispObject,
+        pending: IntoIter<LispObject>,
+        done: Vec<LispObject>,
+        span: Option<Span>,
+    },
+    /// The head of a call is not a symbol and has to be evaluated to find the
+    /// function that is called with `args`.
+    Head {
+        args: Vec<LispObject>,
+        span: Option<Span>,
+    },
+    /// The remaining forms of a body. The last one determines its value.
+    Body(IntoIter<LispObject>),
+    /// Chooses the branch of an `if` once the condition is known.
+    If {
+        then: LispObject,
+        otherwise: Vec<LispObject>,
+    },
+    /// A call of a lambda. Pops the frames of the call once it finished.
+    Call { frame: CallFrame, depth: usize },
+    /// Evaluates the code a macro returned.
+    Expand,
+    /// Restores the local frames that were hidden for a call to `eval`.
+    LeaveGlobal,
+    /// Runs the clause of the first matching condition type when a condition
+    /// is signaled while its form is evaluated.
+    HandlerCase {
+        clauses: Vec<(LispObject, Lambda)>,
+        depth: usize,
+    },
+    /// Evaluates the handler functions of a `handler-bind` before its body.
+    BindHandlers {
+        types: Vec<LispObject>,
+        pending: IntoIter<LispObject>,
+        done: Vec<LispObject>,
+        body: Vec<LispObject>,
+    },
+    /// Handlers that are called on top of the stack, without unwinding it.
+    HandlerBind {
+        handlers: Vec<(LispObject, LispObject)>,
+    },
+    /// A handler of the `HandlerBind` at index `next` runs. The handlers from
+    /// there upwards are disabled until it returns, which declines the
+    /// condition and continues the search below `next`.
+    Signal {
+        condition: LispError,
+        next: usize,
+        is_error: bool,
+    },
+    /// Restarts that `invoke-restart` can transfer control to.
+    RestartCase { restarts: Vec<Lambda>, depth: usize },
+    /// Cleanup forms that run however the protected form is left.
+    UnwindProtect {
+        cleanup: Vec<LispObject>,
+        depth: usize,
+    },
+    /// Returns a saved value once the cleanup forms finished.
+    Value(LispObject),
+    /// Continues unwinding after cleanup forms finished.
+    Unwind { target: usize, exit: Exit },
+    /// Evaluates the body of a `catch` once its tag is known.
+    CatchTag(Vec<LispObject>),
+    /// The target of a `throw` with a matching tag.
+    Catch { tag: LispObject, depth: usize },
+    /// The target of `return-from`. Pops the frame of the block when left.
+    Block { id: usize, depth: usize },
+    /// Returns from the block with that id once the value is known.
+    ReturnFrom(usize),
+    /// A higher-order builtin that waits for the value of a call it made.
+    Iterate {
+        iteration: Box<Iteration>,
+        span: Option<Span>,
+    },
+    /// Compiled code that waits for a value, which is pushed on its stack of
+    /// values before execution continues at `pc`.
+    Vm {
+        code: Rc<Code>,
+        pc: usize,
+        values: Vec<LispObject>,
+    },
+}
+
+/// What happens once the stack is unwound to its target.
+enum Exit {
+    /// Calls a handler or restart clause in the frames it was established in.
+    Call {
+        lambda: Lambda,
+        args: Vec<LispObject>,
+        depth: usize,
+    },
+    /// Returns the value to the continuation below the target.
+    Return { value: LispObject, depth: usize },
+    /// The whole stack is unwound and evaluation fails.
+    Fail(LispError),
+}
+
+impl Cont {
+    /// The position of the call form the continuation belongs to.
+    fn span(&self) -> Option<Span> {
+        match self {
+            Cont::Args { span, .. } | Cont::Head { span, .. } => *span,
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates a form. Every continuation that changes the frames of the
+/// manager is pushed before the frames are changed and restores them when it
+/// is resumed or unwound, so `eval` leaves the frames as it found them, also
+/// when it fails.
+pub fn eval(obj: LispObject, manager: &mut Manager) -> Result<LispObject, LispError> {
+    let depth = manager.depth();
+    run(Step::Eval(resolve(obj)), vec![], depth, manager)
+}
+
+/// Runs compiled code in the global frame, like `eval` runs a form.
+pub fn eval_code(code: Rc<Code>, manager: &mut Manager) -> Result<LispObject, LispError> {
+    let depth = manager.depth();
+    run(Step::Run(code), vec![], depth, manager)
+}
+
+/// The evaluation loop, which takes steps until the stack is empty. `depth`
+/// is the number of frames the manager had before the evaluation started.
+fn run(
+    mut step: Step,
+    mut stack: Vec<Cont>,
+    depth: usize,
+    manager: &mut Manager,
+) -> Result<LispObject, LispError> {
+    loop {
+        let res = match step {
+            Step::Eval(obj) => {
+                let span = obj.span();
+                eval_step(obj, &mut stack, manager).map_err(|e| e.with_span(span))
+            }
+            Step::Run(code) => run_code(code, 0, vec![], &mut stack, manager),
+            Step::Return(val) => match stack.pop() {
+                Some(cont) => {
+                    let span = cont.span();
+                    resume(cont, val, &mut stack, manager).map_err(|e| e.with_span(span))
+                }
+                None => {
+                    debug_assert_eq!(manager.depth(), depth, "eval leaked frames");
+                    return Ok(val);
+                }
+            },
+            Step::Fail(err) => {
+                debug_assert_eq!(manager.depth(), depth, "eval leaked frames");
+                return Err(err);
+            }
+        };
+        step = match res {
+            Ok(step) => step,
+            Err(err) => raise(err, &mut stack, manager),
+        };
+    }
+}
+
+/// Signals an error. Errors that happen while a handler is invoked are
+/// signaled in turn.
+fn raise(mut err: LispError, stack: &mut Vec<Cont>, manager: &mut Manager) -> Step {
+    // A condition that is signaled again keeps the calls it was created in.
+    if err.backtrace().is_empty() {
+        err = err.with_backtrace(backtrace(stack));
+    }
+    loop {
+        match signal(err, stack.len(), true, stack, manager) {
+            Ok(step) => return step,
+            Err(next) => err = next,
+        }
+    }
+}
+
+/// Searches the handlers below index `from` of the stack for one that
+/// accepts the condition. Unhandled errors unwind the whole stack, other
+/// unhandled conditions make `signal` return nil.
+fn signal(
+    condition: LispError,
+    from: usize,
+    is_error: bool,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    let kind = condition.err_type().clone();
+    let mut i = from;
+    while i > 0 {
+        i -= 1;
+        match &stack[i] {
+            Cont::Signal { next, .. } => i = *next,
+            Cont::HandlerCase { clauses, depth } => {
+                let clause = clauses
+                    .iter()
+                    .find(|(spec, _)| matches_condition(spec, &kind, manager));
+                if let Some((_, lambda)) = clause {
+                    let args = if lambda.params.required.is_empty() {
+                        vec![]
+                    } else {
+                        vec![LispObject::condition(condition)]
+                    };
+                    let exit = Exit::Call {
+                        lambda: lambda.clone(),
+                        args,
+                        depth: *depth,
+                    };
+                    return unwind(i, exit, stack, manager);
+                }
+            }
+            Cont::HandlerBind { handlers } => {
+                let handler = handlers
+                    .iter()
+                    .find(|(spec, _)| matches_condition(spec, &kind, manager));
+                if let Some((_, func)) = handler {
+                    let func = func.clone();
+                    let args = vec![LispObject::condition(condition.clone())];
+                    stack.push(Cont::Signal {
+                        condition,
+                        next: i,
+                        is_error,
+                    });
+                    return call_function(func, args, None, stack, manager);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if is_error {
+        unwind(0, Exit::Fail(condition), stack, manager)
+    } else {
+        Ok(Step::Return(LispObject::nil()))
+    }
+}
+
+fn matches_condition(spec: &LispObject, kind: &ErrorType, manager: &Manager) -> bool {
+    match spec.as_symbol() {
+        Some(name) => manager.is_subtype(kind, &ErrorType::from_name(name)),
+        None => spec.ltype() == &LispType::Bool(true),
+    }
+}
+
+/// Pops the stack down to and including index `target`, restoring frames and
+/// running cleanup forms on the way.
+fn unwind(
+    target: usize,
+    exit: Exit,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    while stack.len() > target {
+        match stack.pop().unwrap() {
+            Cont::Call { depth, .. } | Cont::Block { depth, .. } => manager.truncate_frames(depth),
+            Cont::LeaveGlobal => manager.leave_global(),
+            Cont::UnwindProtect { cleanup, depth } => {
+                manager.truncate_frames(depth);
+                stack.push(Cont::Unwind { target, exit });
+                return eval_body(cleanup.into_iter(), stack, manager);
+            }
+            _ => {}
+        }
+    }
+
+    match exit {
+        Exit::Call {
+            lambda,
+            args,
+            depth,
+        } => {
+            manager.truncate_frames(depth);
+            call_lambda(&lambda, args, None, stack, manager)
+        }
+        Exit::Return { value, depth } => {
+            manager.truncate_frames(depth);
+            Ok(Step::Return(value))
+        }
+        Exit::Fail(err) => Ok(Step::Fail(err)),
+    }
+}
+
+fn push(stack: &mut Vec<Cont>, cont: Cont, manager: &Manager) -> Result<(), LispError> {
+    if stack.len() >= manager.max_depth() {
+        return Err(LispError::stack_overflow());
+    }
+    stack.push(cont);
+    Ok(())
+}
+
+fn eval_step(
+    obj: LispObject,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
     if obj.is_quoted() {
-        return Ok(obj);
+        return Ok(Step::Return(obj));
+    }
+
+    if let Some(address) = obj.address() {
+        if let Some(val) = manager.get_local(address) {
+            return Ok(Step::Return(val));
+        }
     }
 
-    match obj.get_type() {
-        LispType::List(l) => eval_list(l, manager),
-        LispType::Symbol(_) if !obj.is_quoted() => Ok(match manager.get_val(obj.clone()) {
-            Some(var) => var,
-            None => obj,
-        }),
-        _ => Ok(obj),
+    let span = obj.span();
+    if let LispType::List(_) = obj.ltype() {
+        return eval_list(obj.into_list().unwrap(), span, stack, manager);
+    }
+    match obj.into_type() {
+        ltype @ LispType::Symbol(_) => {
+            let obj = LispObject::new_with(ltype, false);
+            Ok(Step::Return(match manager.get_val(obj.clone()) {
+                Some(var) => var,
+                None => obj,
+            }))
+        }
+        ltype => Ok(Step::Return(LispObject::new_with(ltype, false))),
     }
 }
 
-fn eval_list(list: Vec<LispObject>, manager: &mut Manager) -> Result<LispObject, &'static str> {
-    manager.new_frame();
+fn eval_list(
+    list: Vec<LispObject>,
+    span: Option<Span>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    let mut list = list.into_iter();
+    let head = match list.next() {
+        Some(head) => head,
+        None => return Ok(Step::Return(LispObject::nil())),
+    };
+    let mut args = list.collect::<Vec<LispObject>>();
 
-    match list[0].get_type() {
-        LispType::Symbol(s) if s == "defun" => {
-            create_defun(manager, list);
-            return Ok(LispObject::nil());
+    let name = match head.as_symbol() {
+        Some(name) => name,
+        None => {
+            push(stack, Cont::Head { args, span }, manager)?;
+            return Ok(Step::Eval(head));
+        }
+    };
+
+    // Special forms decide themselves which of their arguments are evaluated.
+    match name {
+        "quote" => match args.into_iter().next() {
+            Some(arg) => Ok(Step::Return(arg.move_quoted())),
+            None => Err(LispError::not_enough_arguments()),
+        },
+        "defun" | "defmacro" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let fn_name = args.remove(0);
+            let fn_name = match fn_name.as_symbol() {
+                Some(fn_name) => fn_name.to_string(),
+                None => {
+                    return Err(LispError::type_error(
+                        "Function name must be a symbol",
+                        fn_name,
+                    ))
+                }
+            };
+            let mut lambda = make_lambda(Some(fn_name.clone()), args, manager)?;
+            // The body is wrapped in a block, so `return-from` can leave the
+            // function early.
+            let mut block = vec![LispObject::symbol("block"), LispObject::symbol(&fn_name)];
+            block.append(&mut lambda.body);
+            lambda.body = vec![LispObject::new_with(LispType::list(block), false)];
+            compile_lambda(&mut lambda, manager);
+            let func = if name == "defun" {
+                Function::Lambda(lambda)
+            } else {
+                Function::Macro(lambda)
+            };
+            define_function(&fn_name, LispObject::function(func), manager);
+            Ok(Step::Return(LispObject::nil()))
+        }
+        "lambda" => {
+            let mut lambda = make_lambda(None, args, manager)?;
+            compile_lambda(&mut lambda, manager);
+            Ok(Step::Return(LispObject::function(Function::Lambda(lambda))))
+        }
+        "function" => match args.into_iter().next() {
+            Some(arg) => match arg.as_symbol() {
+                Some(fn_name) => lookup_function(fn_name, manager).map(Step::Return),
+                None => Ok(Step::Eval(arg)),
+            },
+            None => Err(LispError::not_enough_arguments()),
+        },
+        "if" => {
+            if args.len() < 2 {
+                return Err(LispError::not_enough_arguments());
+            }
+            let mut args = args.into_iter();
+            let cond = args.next().unwrap();
+            let then = args.next().unwrap();
+            let otherwise = args.collect();
+            push(stack, Cont::If { then, otherwise }, manager)?;
+            Ok(Step::Eval(cond))
+        }
+        "progn" => eval_body(args.into_iter(), stack, manager),
+        "handler-case" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let form = args.remove(0);
+            let clauses = args
+                .into_iter()
+                .map(|clause| make_clause(clause, manager))
+                .collect::<Result<Vec<(LispObject, Lambda)>, LispError>>()?;
+            let depth = manager.depth();
+            push(stack, Cont::HandlerCase { clauses, depth }, manager)?;
+            Ok(Step::Eval(form))
+        }
+        "handler-bind" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let bindings = args.remove(0);
+            let mut types = vec![];
+            let mut handlers = vec![];
+            for binding in list_elements(bindings)? {
+                match binding.as_list() {
+                    Some([kind, handler]) => {
+                        types.push(kind.clone());
+                        handlers.push(handler.clone());
+                    }
+                    _ => return Err(LispError::type_error("Expected (type handler)", binding)),
+                }
+            }
+            let mut pending = handlers.into_iter();
+            match pending.next() {
+                Some(first) => {
+                    let cont = Cont::BindHandlers {
+                        types,
+                        pending,
+                        done: vec![],
+                        body: args,
+                    };
+                    push(stack, cont, manager)?;
+                    Ok(Step::Eval(first))
+                }
+                None => eval_body(args.into_iter(), stack, manager),
+            }
+        }
+        "unwind-protect" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let form = args.remove(0);
+            let depth = manager.depth();
+            let cont = Cont::UnwindProtect {
+                cleanup: args,
+                depth,
+            };
+            push(stack, cont, manager)?;
+            Ok(Step::Eval(form))
+        }
+        "restart-case" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let form = args.remove(0);
+            let restarts = args
+                .into_iter()
+                .map(|clause| make_clause(clause, manager).map(|(_, lambda)| lambda))
+                .collect::<Result<Vec<Lambda>, LispError>>()?;
+            let depth = manager.depth();
+            push(stack, Cont::RestartCase { restarts, depth }, manager)?;
+            Ok(Step::Eval(form))
+        }
+        "catch" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let tag = args.remove(0);
+            push(stack, Cont::CatchTag(args), manager)?;
+            Ok(Step::Eval(tag))
+        }
+        "block" => {
+            let block_name = match args.first().map(|n| n.ltype()) {
+                Some(LispType::Symbol(block_name)) => block_name.clone(),
+                Some(LispType::Bool(false)) => "nil".to_string(),
+                Some(_) => return Err(LispError::type_error("Expected a symbol", args.remove(0))),
+                None => return Err(LispError::not_enough_arguments()),
+            };
+            let depth = manager.depth();
+            let id = manager.new_block_id();
+            push(stack, Cont::Block { id, depth }, manager)?;
+            manager.new_frame();
+            manager.establish_block(&block_name, id);
+            eval_body(args.split_off(1).into_iter(), stack, manager)
+        }
+        "return-from" | "return" => {
+            let block_name = if name == "return" {
+                "nil".to_string()
+            } else {
+                match args.first().map(|n| n.ltype()) {
+                    Some(LispType::Symbol(block_name)) => block_name.clone(),
+                    Some(LispType::Bool(false)) => "nil".to_string(),
+                    Some(_) => {
+                        return Err(LispError::type_error("Expected a symbol", args.remove(0)))
+                    }
+                    None => return Err(LispError::not_enough_arguments()),
+                }
+            };
+            let id = match manager.lookup_block(&block_name) {
+                Some(id) => id,
+                None => {
+                    return Err(
+                        LispError::new(ErrorType::ControlError, "No block with that name")
+                            .with_object(LispObject::symbol(block_name)),
+                    )
+                }
+            };
+            let value = if name == "return" {
+                args.into_iter().next()
+            } else {
+                args.into_iter().nth(1)
+            };
+            push(stack, Cont::ReturnFrom(id), manager)?;
+            Ok(Step::Eval(value.unwrap_or_else(LispObject::nil)))
+        }
+        "define-condition" => {
+            let mut args = args.into_iter();
+            let name = match args.next() {
+                Some(name) => name,
+                None => return Err(LispError::not_enough_arguments()),
+            };
+            let parent = match args.next().map(list_elements).transpose()? {
+                Some(parents) if !parents.is_empty() => parents[0].clone(),
+                _ => LispObject::symbol("error"),
+            };
+            match (name.as_symbol(), parent.as_symbol()) {
+                (Some(name), Some(parent)) => {
+                    let parent = ErrorType::from_name(parent);
+                    if manager.define_condition(name, parent).is_none() {
+                        return Err(LispError::runtime_error(
+                            "Can not redefine this condition type",
+                        )
+                        .with_object(LispObject::symbol(name)));
+                    }
+                    Ok(Step::Return(LispObject::nil()))
+                }
+                (None, _) => Err(LispError::type_error("Expected a symbol", name)),
+                (_, None) => Err(LispError::type_error("Expected a symbol", parent)),
+            }
+        }
+        _ => {
+            let func = lookup_function(name, manager)?;
+            call_form(func, args, span, stack, manager)
         }
-        _ => {}
     }
+}
 
-    let mut parameters = vec![];
-    for element in list {
-        parameters.push(eval(element, manager)?);
+/// Calls `func` with the unevaluated `args` of a call form.
+fn call_form(
+    func: LispObject,
+    args: Vec<LispObject>,
+    span: Option<Span>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    if let LispType::Function(f) = func.ltype() {
+        if let Function::Macro(lambda) = &**f {
+            push(stack, Cont::Expand, manager)?;
+            return call_lambda(lambda, args, span, stack, manager);
+        }
     }
 
-    match parameters[0].get_type() {
-        LispType::Symbol(s) => match s.as_str() {
-            "set" => {
-                manager.set_val(parameters[1].clone(), parameters[2].clone());
-                Ok(LispObject::nil())
+    let mut pending = args.into_iter();
+    match pending.next() {
+        Some(first) => {
+            let cont = Cont::Args {
+                func,
+                pending,
+                done: vec![],
+                span,
+            };
+            push(stack, cont, manager)?;
+            Ok(Step::Eval(first))
+        }
+        None => call_function(func, vec![], span, stack, manager),
+    }
+}
+
+fn eval_body(
+    mut body: IntoIter<LispObject>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    match body.next() {
+        Some(form) => {
+            if body.len() > 0 {
+                push(stack, Cont::Body(body), manager)?;
             }
-            "quote" => {
-                let mut param = parameters[1].clone();
-                param.set_quoted();
-                Ok(param)
+            Ok(Step::Eval(form))
+        }
+        None => Ok(Step::Return(LispObject::nil())),
+    }
+}
+
+fn resume(
+    cont: Cont,
+    val: LispObject,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    match cont {
+        Cont::Args {
+            func,
+            mut pending,
+            mut done,
+            span,
+        } => {
+            done.push(val);
+            match pending.next() {
+                Some(next) => {
+                    let cont = Cont::Args {
+                        func,
+                        pending,
+                        done,
+                        span,
+                    };
+                    push(stack, cont, manager)?;
+                    Ok(Step::Eval(next))
+                }
+                None => call_function(func, done, span, stack, manager),
             }
-            _ => {
-                manager.pop_frame();
-                call_builtin(&s, &parameters[1..])
+        }
+        Cont::Head { args, span } => match val.ltype() {
+            LispType::Function(_) => call_form(val, args, span, stack, manager),
+            _ => Err(LispError::type_error("Not a function", val)),
+        },
+        Cont::Body(body) => eval_body(body, stack, manager),
+        Cont::If { then, otherwise } => {
+            if val.is_true() {
+                Ok(Step::Eval(then))
+            } else {
+                eval_body(otherwise.into_iter(), stack, manager)
             }
+        }
+        Cont::Call { depth, .. } => {
+            manager.truncate_frames(depth);
+            Ok(Step::Return(val))
+        }
+        Cont::Expand => Ok(Step::Eval(optimize(val.into_code(), manager))),
+        Cont::LeaveGlobal => {
+            manager.leave_global();
+            Ok(Step::Return(val))
+        }
+        Cont::HandlerCase { .. } | Cont::HandlerBind { .. } | Cont::RestartCase { .. } => {
+            Ok(Step::Return(val))
+        }
+        Cont::BindHandlers {
+            types,
+            mut pending,
+            mut done,
+            body,
+        } => {
+            done.push(val);
+            match pending.next() {
+                Some(next) => {
+                    let cont = Cont::BindHandlers {
+                        types,
+                        pending,
+                        done,
+                        body,
+                    };
+                    push(stack, cont, manager)?;
+                    Ok(Step::Eval(next))
+                }
+                None => {
+                    let handlers = types.into_iter().zip(done).collect();
+                    push(stack, Cont::HandlerBind { handlers }, manager)?;
+                    eval_body(body.into_iter(), stack, manager)
+                }
+            }
+        }
+        Cont::Signal {
+            condition,
+            next,
+            is_error,
+        } => signal(condition, next, is_error, stack, manager),
+        Cont::UnwindProtect { cleanup, .. } => {
+            push(stack, Cont::Value(val), manager)?;
+            eval_body(cleanup.into_iter(), stack, manager)
+        }
+        Cont::Value(saved) => Ok(Step::Return(saved)),
+        Cont::Unwind { target, exit } => unwind(target, exit, stack, manager),
+        Cont::CatchTag(body) => {
+            let depth = manager.depth();
+            push(stack, Cont::Catch { tag: val, depth }, manager)?;
+            eval_body(body.into_iter(), stack, manager)
+        }
+        Cont::Catch { .. } => Ok(Step::Return(val)),
+        Cont::Block { depth, .. } => {
+            manager.truncate_frames(depth);
+            Ok(Step::Return(val))
+        }
+        Cont::Iterate { iteration, span } => iterate(iteration, Some(val), span, stack, manager),
+        Cont::Vm {
+            code,
+            pc,
+            mut values,
+        } => {
+            values.push(val);
+            run_code(code, pc, values, stack, manager)
+        }
+        Cont::ReturnFrom(id) => {
+            let target = stack
+                .iter()
+                .rposition(|cont| matches!(cont, Cont::Block { id: i, .. } if *i == id));
+            match target {
+                Some(target) => {
+                    let depth = match stack[target] {
+                        Cont::Block { depth, .. } => depth,
+                        _ => unreachable!(),
+                    };
+                    let exit = Exit::Return { value: val, depth };
+                    unwind(target, exit, stack, manager)
+                }
+                None => Err(LispError::new(
+                    ErrorType::ControlError,
+                    "The block was already left",
+                )),
+            }
+        }
+    }
+}
+
+/// Calls a function with already evaluated arguments. `func` is either a
+/// function or a symbol naming one.
+fn call_function(
+    func: LispObject,
+    mut args: Vec<LispObject>,
+    span: Option<Span>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    let func = match func.into_type() {
+        LispType::Function(f) => f,
+        LispType::Symbol(name) => match lookup_function(&name, manager)?.into_type() {
+            LispType::Function(f) => f,
+            _ => unreachable!(),
+        },
+        ltype => {
+            let obj = LispObject::new_with(ltype, false);
+            return Err(LispError::type_error("Not a function", obj));
+        }
+    };
+
+    let name = match &*func {
+        Function::Builtin(name) => name.as_str(),
+        Function::Lambda(lambda) => return call_lambda(lambda, args, span, stack, manager),
+        Function::Macro(_) => {
+            let obj = LispObject::new_with(LispType::Function(func.clone()), false);
+            return Err(LispError::type_error("Macros can not be called", obj));
+        }
+    };
+
+    match name {
+        "set" => match (args.first(), args.get(1)) {
+            (Some(key), Some(val)) => {
+                manager.set_val(key.clone(), val.clone());
+                Ok(Step::Return(LispObject::nil()))
+            }
+            _ => Err(LispError::not_enough_arguments()),
+        },
+        "funcall" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let func = args.remove(0).move_unquoted();
+            call_function(func, args, span, stack, manager)
+        }
+        "apply" => {
+            if args.len() < 2 {
+                return Err(LispError::not_enough_arguments());
+            }
+            let func = args.remove(0).move_unquoted();
+            // The last argument is a list whose elements are passed as
+            // separate arguments.
+            let last = args.pop().unwrap();
+            let mut rest = &last;
+            loop {
+                match rest.ltype() {
+                    LispType::List(l) => args.extend(l.iter().cloned()),
+                    LispType::Bool(false) => {}
+                    LispType::Cons(pair) => {
+                        args.push(pair.0.clone());
+                        rest = &pair.1;
+                        continue;
+                    }
+                    _ => return Err(LispError::type_error("Expected a list", last.clone())),
+                }
+                break;
+            }
+            call_function(func, args, span, stack, manager)
+        }
+        "eval" => match args.into_iter().next() {
+            Some(form) => {
+                push(stack, Cont::LeaveGlobal, manager)?;
+                manager.enter_global();
+                Ok(Step::Eval(form.into_code()))
+            }
+            None => Err(LispError::not_enough_arguments()),
         },
-        LispType::List(_) => eval(parameters[0].clone(), manager),
-        _ => Ok(parameters[0].clone()),
+        "error" => Err(make_condition(args)?),
+        "signal" => {
+            let condition = make_condition(args)?;
+            signal(condition, stack.len(), false, stack, manager)
+        }
+        "throw" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let tag = args.remove(0);
+            let value = args.into_iter().next().unwrap_or_else(LispObject::nil);
+            let target = stack.iter().rposition(
+                |cont| matches!(cont, Cont::Catch { tag: t, .. } if t.ltype() == tag.ltype()),
+            );
+            match target {
+                Some(target) => {
+                    let depth = match stack[target] {
+                        Cont::Catch { depth, .. } => depth,
+                        _ => unreachable!(),
+                    };
+                    unwind(target, Exit::Return { value, depth }, stack, manager)
+                }
+                None => Err(
+                    LispError::new(ErrorType::ControlError, "No catch for that tag")
+                        .with_object(tag),
+                ),
+            }
+        }
+        "backtrace" => {
+            let frames = match args.first().map(|c| c.ltype()) {
+                Some(LispType::Condition(condition)) => condition.backtrace().to_vec(),
+                Some(_) => {
+                    return Err(LispError::type_error(
+                        "Expected a condition",
+                        args.remove(0),
+                    ))
+                }
+                None => backtrace(stack),
+            };
+            let frames = frames.into_iter().map(call_frame_object).collect();
+            Ok(Step::Return(LispObject::new_with(
+                LispType::list(frames),
+                true,
+            )))
+        }
+        "gc" => {
+            let freed = manager.collect_garbage();
+            Ok(Step::Return(LispObject::integer(freed as i64)))
+        }
+        "gc-stats" => {
+            let stats = manager.gc_stats();
+            let stats = [
+                ("collections", stats.collections),
+                ("freed", stats.freed),
+                ("live", stats.live),
+            ]
+            .into_iter()
+            .flat_map(|(name, n)| [LispObject::symbol(name), LispObject::integer(n as i64)])
+            .collect();
+            Ok(Step::Return(LispObject::new_with(
+                LispType::list(stats),
+                true,
+            )))
+        }
+        "invoke-restart" => {
+            if args.is_empty() {
+                return Err(LispError::not_enough_arguments());
+            }
+            let name = args.remove(0);
+            for (i, cont) in stack.iter().enumerate().rev() {
+                if let Cont::RestartCase { restarts, depth } = cont {
+                    let restart = restarts
+                        .iter()
+                        .find(|r| r.name.as_deref() == name.as_symbol());
+                    if let Some(lambda) = restart {
+                        let exit = Exit::Call {
+                            lambda: lambda.clone(),
+                            args,
+                            depth: *depth,
+                        };
+                        return unwind(i, exit, stack, manager);
+                    }
+                }
+            }
+            Err(
+                LispError::new(ErrorType::ControlError, "No restart with that name")
+                    .with_object(name),
+            )
+        }
+        _ if HIGHER_ORDER.contains(&name) => {
+            let iteration = Box::new(Iteration::new(name, args)?);
+            iterate(iteration, None, span, stack, manager)
+        }
+        _ => call_builtin(name, &args).map(Step::Return),
+    }
+}
+
+/// Makes the next call a higher-order builtin asks for, after passing it the
+/// value of the previous one.
+fn iterate(
+    mut iteration: Box<Iteration>,
+    val: Option<LispObject>,
+    span: Option<Span>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    match iteration.next(val)? {
+        Next::Call(func, args) => {
+            push(stack, Cont::Iterate { iteration, span }, manager)?;
+            call_function(func, args, span, stack, manager)
+        }
+        Next::Done(val) => Ok(Step::Return(val)),
+    }
+}
+
+/// Binds the arguments in a new frame inside the environment of the lambda
+/// and evaluates its body.
+fn call_lambda(
+    lambda: &Lambda,
+    args: Vec<LispObject>,
+    span: Option<Span>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    let params = &lambda.params;
+    let max = params.required.len() + params.optional.len();
+    if args.len() < params.required.len() || (params.rest.is_none() && args.len() > max) {
+        return Err(LispError::wrong_number_of_arguments(
+            lambda.name.as_deref().unwrap_or("lambda"),
+            args.len(),
+        ));
+    }
+
+    let frame = CallFrame {
+        name: lambda.name.clone().unwrap_or_else(|| "lambda".to_string()),
+        args: args.clone(),
+        span,
+    };
+    let depth = manager.depth();
+    push(stack, Cont::Call { frame, depth }, manager)?;
+    manager.push_frame(lambda.env.clone());
+
+    let mut args = args.into_iter();
+    for name in params.required.iter().chain(&params.optional) {
+        let val = args.next().unwrap_or_else(LispObject::nil);
+        manager.bind_local(name, val);
+    }
+    if let Some(rest) = &params.rest {
+        let rest_args = args.collect::<Vec<LispObject>>();
+        let val = if rest_args.is_empty() {
+            LispObject::nil()
+        } else {
+            LispObject::new_with(LispType::list(rest_args), true)
+        };
+        manager.bind_local(rest, val);
+    }
+
+    // Compiled code runs in the evaluation loop, so calls from compiled
+    // code do not nest on the Rust stack.
+    match &lambda.code {
+        Some(code) => Ok(Step::Run(code.clone())),
+        None => eval_body(lambda.body.clone().into_iter(), stack, manager),
+    }
+}
+
+/// Runs compiled code from instruction `pc` on. The code hands control back
+/// to the evaluation loop when it calls a lambda or evaluates a form it
+/// could not compile, with a `Cont::Vm` on the stack to continue it.
+fn run_code(
+    mut code: Rc<Code>,
+    mut pc: usize,
+    mut values: Vec<LispObject>,
+    stack: &mut Vec<Cont>,
+    manager: &mut Manager,
+) -> Result<Step, LispError> {
+    loop {
+        let op = code.ops[pc];
+        let span = code.spans[pc];
+        pc += 1;
+        match op {
+            Op::Const(i) => values.push(code.constants[i].clone()),
+            Op::Local(address, name) => {
+                let val = match manager.get_local(address) {
+                    Some(val) => val,
+                    None => variable(code.constants[name].clone(), manager),
+                };
+                values.push(val);
+            }
+            Op::Global(name) => values.push(variable(code.constants[name].clone(), manager)),
+            Op::Function { name, form, skip } => {
+                let name = code.constants[name].as_symbol().unwrap();
+                let func = lookup_function(name, manager).map_err(|e| e.with_span(span))?;
+                if let LispType::Function(f) = func.ltype() {
+                    if let Function::Macro(_) = &**f {
+                        let form = code.constants[form].clone();
+                        push(
+                            stack,
+                            Cont::Vm {
+                                code,
+                                pc: skip,
+                                values,
+                            },
+                            manager,
+                        )?;
+                        return Ok(Step::Eval(form));
+                    }
+                }
+                values.push(func);
+            }
+            Op::Call(argc) => {
+                let args = values.split_off(values.len() - argc);
+                let func = values.pop().unwrap();
+                push(stack, Cont::Vm { code, pc, values }, manager)?;
+                match call_function(func, args, span, stack, manager) {
+                    // The value goes to the code on top of the stack, which
+                    // continues right here instead of in the evaluation loop.
+                    Ok(Step::Return(val)) if matches!(stack.last(), Some(Cont::Vm { .. })) => {
+                        let Some(Cont::Vm {
+                            code: next,
+                            pc: next_pc,
+                            values: next_values,
+                        }) = stack.pop()
+                        else {
+                            unreachable!()
+                        };
+                        code = next;
+                        pc = next_pc;
+                        values = next_values;
+                        values.push(val);
+                    }
+                    res => return res.map_err(|e| e.with_span(span)),
+                }
+            }
+            Op::Pop => {
+                values.pop();
+            }
+            Op::Jump(target) => pc = target,
+            Op::JumpIfNot(target) => {
+                if !values.pop().unwrap().is_true() {
+                    pc = target;
+                }
+            }
+            Op::Closure(i) => {
+                let proto = &code.protos[i];
+                let lambda = Lambda {
+                    name: proto.name.clone(),
+                    params: proto.params.clone(),
+                    body: proto.body.clone(),
+                    env: manager.current_env(),
+                    code: Some(proto.code.clone()),
+                };
+                values.push(LispObject::function(Function::Lambda(lambda)));
+            }
+            Op::Define { proto, is_macro } => {
+                let proto = &code.protos[proto];
+                let lambda = Lambda {
+                    name: proto.name.clone(),
+                    params: proto.params.clone(),
+                    body: proto.body.clone(),
+                    env: manager.current_env(),
+                    code: Some(proto.code.clone()),
+                };
+                let func = if is_macro {
+                    Function::Macro(lambda)
+                } else {
+                    Function::Lambda(lambda)
+                };
+                let fn_name = proto.name.as_deref().unwrap_or_default();
+                define_function(fn_name, LispObject::function(func), manager);
+                values.push(LispObject::nil());
+            }
+            Op::Block(i) => {
+                let (block_name, block) = code.blocks[i].clone();
+                let depth = manager.depth();
+                let id = manager.new_block_id();
+                push(stack, Cont::Vm { code, pc, values }, manager)?;
+                push(stack, Cont::Block { id, depth }, manager)?;
+                manager.new_frame();
+                manager.establish_block(&block_name, id);
+                code = block;
+                pc = 0;
+                values = vec![];
+            }
+            Op::Eval(i) => {
+                let form = code.constants[i].clone();
+                push(stack, Cont::Vm { code, pc, values }, manager)?;
+                return Ok(Step::Eval(form));
+            }
+            Op::Return => return Ok(Step::Return(values.pop().unwrap())),
+        }
+    }
+}
+
+/// The value of a variable, or the symbol itself if it is unbound.
+fn variable(name: LispObject, manager: &mut Manager) -> LispObject {
+    match manager.get_val(name.clone()) {
+        Some(val) => val,
+        None => name,
+    }
+}
+
+/// Compiles the body of the lambda if functions run on the virtual machine.
+fn compile_lambda(lambda: &mut Lambda, manager: &Manager) {
+    if manager.engine() == Engine::Vm {
+        lambda.code = Some(Rc::new(compile(&lambda.body)));
+    }
+}
+
+/// The active calls on the stack, from the innermost to the outermost.
+fn backtrace(stack: &[Cont]) -> Vec<CallFrame> {
+    stack
+        .iter()
+        .rev()
+        .filter_map(|cont| match cont {
+            Cont::Call { frame, .. } => Some(frame.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Turns a call into the list `((name args...) line column)`.
+fn call_frame_object(frame: CallFrame) -> LispObject {
+    let mut call = vec![LispObject::symbol(frame.name)];
+    call.extend(frame.args);
+    let (line, column) = match frame.span {
+        Some(span) => (
+            LispObject::integer(span.line as i64),
+            LispObject::integer(span.column as i64),
+        ),
+        None => (LispObject::nil(), LispObject::nil()),
+    };
+    let call = LispObject::new_with(LispType::list(call), false);
+    LispObject::new_with(LispType::list(vec![call, line, column]), false)
+}
+
+/// Builds a closure over the current frame from `(params body...)`.
+fn make_lambda(
+    name: Option<String>,
+    args: Vec<LispObject>,
+    manager: &Manager,
+) -> Result<Lambda, LispError> {
+    let mut args = args.into_iter();
+    let params = match args.next() {
+        Some(params) => parse_params(params)?,
+        None => return Err(LispError::not_enough_arguments()),
+    };
+    Ok(Lambda {
+        name,
+        params,
+        body: args.collect(),
+        env: manager.current_env(),
+        code: None,
+    })
+}
+
+pub fn parse_params(list: LispObject) -> Result<Params, LispError> {
+    let mut params = Params::default();
+    let mut optional = false;
+    let mut rest = false;
+    for param in list_elements(list)? {
+        let name = match param.as_symbol() {
+            Some(name) => name.to_string(),
+            None => return Err(LispError::type_error("Parameters must be symbols", param)),
+        };
+        match name.as_str() {
+            "&optional" => optional = true,
+            "&rest" => rest = true,
+            _ if rest && params.rest.is_some() => {
+                return Err(LispError::runtime_error(
+                    "Only one parameter may follow &rest",
+                ))
+            }
+            _ if rest => params.rest = Some(name),
+            _ if optional => params.optional.push(name),
+            _ => params.required.push(name),
+        }
     }
+    Ok(params)
+}
+
+/// Builds the condition for `error` and `signal` from a condition type and
+/// the objects that caused it, or from a condition that is signaled again. A
+/// string instead of the type is the message of a `simple-error`.
+fn make_condition(args: Vec<LispObject>) -> Result<LispError, LispError> {
+    let mut args = args.into_iter();
+    let kind = match args.next() {
+        Some(kind) => kind,
+        None => return Err(LispError::not_enough_arguments()),
+    };
+    let err = match kind.ltype() {
+        LispType::Condition(condition) => return Ok((**condition).clone()),
+        LispType::Symbol(name) => LispError::new(ErrorType::from_name(name), ""),
+        LispType::String(message) => LispError::new(ErrorType::SimpleError, message),
+        _ => return Err(LispError::type_error("Expected a condition type", kind)),
+    };
+    Ok(args.fold(err, |err, obj| err.with_object(obj)))
 }
 
-fn create_defun(manager: &mut Manager, parameter: Vec<LispObject>) {
-    manager.set_val(
-        parameter[1].clone().move_quoted(),
-        LispObject::list(&parameter[3..]),
-    );
+/// Splits a clause `(name (params) body...)` of `handler-case` or
+/// `restart-case` into its name and a lambda.
+fn make_clause(clause: LispObject, manager: &Manager) -> Result<(LispObject, Lambda), LispError> {
+    let mut clause = list_elements(clause)?;
+    if clause.is_empty() {
+        return Err(LispError::not_enough_arguments());
+    }
+    let name = clause.remove(0);
+    let lambda = make_lambda(name.as_symbol().map(|s| s.to_string()), clause, manager)?;
+    Ok((name, lambda))
+}
+
+fn list_elements(list: LispObject) -> Result<Vec<LispObject>, LispError> {
+    match list.ltype() {
+        LispType::List(_) => Ok(list.into_list().unwrap()),
+        LispType::Bool(false) => Ok(vec![]),
+        _ => Err(LispError::type_error("Expected a list", list)),
+    }
+}
+
+fn define_function(name: &str, func: LispObject, manager: &mut Manager) {
+    let name = LispObject::symbol(name).move_quoted();
+    match manager.namespace() {
+        Namespace::Unified => manager.set_global(name, func),
+        Namespace::Separate => manager.set_function(name, func),
+    };
+}
+
+/// Finds the function a symbol in the head of a call refers to.
+pub fn lookup_function(name: &str, manager: &mut Manager) -> Result<LispObject, LispError> {
+    let symbol = LispObject::symbol(name);
+    let found = match manager.namespace() {
+        Namespace::Unified => manager.get_val(symbol).filter(|val| val.is_function()),
+        Namespace::Separate => manager.get_function(symbol),
+    };
+
+    match found {
+        Some(func) => Ok(func),
+        None if is_builtin(name)
+            || EVAL_BUILTINS.contains(&name)
+            || HIGHER_ORDER.contains(&name) =>
+        {
+            Ok(LispObject::function(Function::Builtin(name.to_string())))
+        }
+        None => Err(LispError::undefined_function(name)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::eval_str;
+
+    /// The evaluator tests run with both engines.
+    const ENGINES: [Engine; 2] = [Engine::Tree, Engine::Vm];
+
+    fn test_manager(engine: Engine) -> Manager {
+        let mut manager = Manager::default();
+        manager.set_engine(engine);
+        manager
+    }
 
     #[test]
     fn test_eval_simple_built_in() {
-        let eval_obj = LispObject::list(&[
-            LispObject::new("add"),
-            LispObject::new("22"),
-            LispObject::new("33"),
-        ]);
-        let mut obj_manager = Manager::default();
-        let res = eval(eval_obj, &mut obj_manager).unwrap();
-        assert_eq!(LispObject::new_with(LispType::Number(55.), false), res);
+        for engine in ENGINES {
+            let eval_obj = LispObject::list(&[
+                LispObject::new("add"),
+                LispObject::new("22"),
+                LispObject::new("33"),
+            ]);
+            let mut obj_manager = test_manager(engine);
+            let res = eval(eval_obj, &mut obj_manager).unwrap();
+            assert_eq!(LispObject::new_with(LispType::Integer(55), false), res);
+        }
     }
 
     #[test]
     fn test_eval_recursive_built_in() {
-        let eval_obj = LispObject::list(&[
-            LispObject::new("add"),
-            LispObject::list(&[
+        for engine in ENGINES {
+            let eval_obj = LispObject::list(&[
                 LispObject::new("add"),
-                LispObject::new("11"),
-                LispObject::new("22"),
-            ]),
-            LispObject::new("33"),
-        ]);
-        let mut obj_manager = Manager::default();
-        let res = eval(eval_obj, &mut obj_manager).unwrap();
+                LispObject::list(&[
+                    LispObject::new("add"),
+                    LispObject::new("11"),
+                    LispObject::new("22"),
+                ]),
+                LispObject::new("33"),
+            ]);
+            let mut obj_manager = test_manager(engine);
+            let res = eval(eval_obj, &mut obj_manager).unwrap();
 
-        assert_eq!(LispObject::new_with(LispType::Number(66.), false), res);
+            assert_eq!(LispObject::new_with(LispType::Integer(66), false), res);
+        }
     }
 
     #[test]
     fn test_eval_multiple_passes_built_in() {
-        let eval_obj = LispObject::list(&[
-            LispObject::new("add"),
-            LispObject::new("22"),
-            LispObject::new("33"),
-        ]);
-        let mut obj_manager = Manager::default();
-        let res = eval(eval_obj.clone(), &mut obj_manager).unwrap();
-        assert_eq!(LispObject::new_with(LispType::Number(55.), false), res);
-        let res = eval(eval_obj, &mut obj_manager).unwrap();
-        assert_eq!(LispObject::new_with(LispType::Number(55.), false), res);
+        for engine in ENGINES {
+            let eval_obj = LispObject::list(&[
+                LispObject::new("add"),
+                LispObject::new("22"),
+                LispObject::new("33"),
+            ]);
+            let mut obj_manager = test_manager(engine);
+            let res = eval(eval_obj.clone(), &mut obj_manager).unwrap();
+            assert_eq!(LispObject::new_with(LispType::Integer(55), false), res);
+            let res = eval(eval_obj, &mut obj_manager).unwrap();
+            assert_eq!(LispObject::new_with(LispType::Integer(55), false), res);
+        }
+    }
+
+    #[test]
+    fn test_eval_funcall() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str("(funcall '+ 1 2)", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(3));
+            let res = eval_str("(funcall 'funcall 'add 3 4)", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(7));
+            let res = eval_str(
+                "(defun five () (add 2 3)) (funcall (quote five))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(5));
+        }
+    }
+
+    #[test]
+    fn test_eval_apply() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str("(apply '+ 1 (list 2))", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(3));
+            let res = eval_str("(apply '+ (list 5 6))", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(11));
+            let res = eval_str("(apply 'list nil)", &mut obj_manager);
+            assert_eq!(res.get_type(), LispType::list(vec![]));
+            let res = eval(
+                crate::ast::ast("(apply '+ 1 2)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn test_eval_eval() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str("(eval (list '+ 1 (add 2 3)))", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(6));
+            let res = eval_str("(eval (list 'list 1 2))", &mut obj_manager);
+            assert_eq!(res.get_string(), "(1 2)");
+
+            // eval only sees the global environment.
+            let mut obj_manager = test_manager(engine);
+            eval_str("(set 'global 1)", &mut obj_manager);
+            obj_manager.new_frame();
+            obj_manager.set_val(LispObject::symbol("local"), LispObject::integer(2));
+            assert_eq!(
+                eval_str("(eval 'global)", &mut obj_manager),
+                LispObject::integer(1)
+            );
+            assert_eq!(
+                eval_str("(eval 'local)", &mut obj_manager),
+                LispObject::symbol("local")
+            );
+            assert_eq!(
+                eval(LispObject::symbol("local"), &mut obj_manager).unwrap(),
+                LispObject::integer(2)
+            );
+        }
     }
 
     #[test]
     fn test_eval_setq() {
-        let test = vec![
-            LispObject::list(&[
-                LispObject::symbol("set"),
-                LispObject::symbol("test").move_quoted(),
-                LispObject::bool(true),
-            ]),
-            LispObject::symbol("test"),
-        ];
-        let mut obj_manager = Manager::default();
-        let _first = eval(test[0].clone(), &mut obj_manager).unwrap();
-        let res = eval(test[1].clone(), &mut obj_manager).unwrap();
-        assert_eq!(res, LispObject::bool(true));
+        for engine in ENGINES {
+            let test = [
+                LispObject::list(&[
+                    LispObject::symbol("set"),
+                    LispObject::symbol("test").move_quoted(),
+                    LispObject::bool(true),
+                ]),
+                LispObject::symbol("test"),
+            ];
+            let mut obj_manager = test_manager(engine);
+            let _first = eval(test[0].clone(), &mut obj_manager).unwrap();
+            let res = eval(test[1].clone(), &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::bool(true));
+        }
     }
 
     #[test]
     fn test_eval_var_lookup() {
-        let eval_obj = vec![
-            LispObject::list(&[
-                LispObject::symbol("set"),
-                LispObject::symbol("test").move_quoted(),
+        for engine in ENGINES {
+            let eval_obj = [
                 LispObject::list(&[
-                    LispObject::symbol("+"),
-                    LispObject::number(22.),
-                    LispObject::number(23.),
+                    LispObject::symbol("set"),
+                    LispObject::symbol("test").move_quoted(),
+                    LispObject::list(&[
+                        LispObject::symbol("+"),
+                        LispObject::integer(22),
+                        LispObject::integer(23),
+                    ]),
                 ]),
-            ]),
-            LispObject::list(&[
-                LispObject::symbol("set"),
-                LispObject::symbol("test").move_quoted(),
                 LispObject::list(&[
-                    LispObject::symbol("add"),
-                    LispObject::symbol("test"),
-                    LispObject::number(2.),
+                    LispObject::symbol("set"),
+                    LispObject::symbol("test").move_quoted(),
+                    LispObject::list(&[
+                        LispObject::symbol("add"),
+                        LispObject::symbol("test"),
+                        LispObject::integer(2),
+                    ]),
                 ]),
-            ]),
-            LispObject::symbol("test"),
-        ];
-        let mut obj_manager = Manager::default();
-        let _res = eval(eval_obj[0].clone(), &mut obj_manager).unwrap();
-        let _res = eval(eval_obj[1].clone(), &mut obj_manager).unwrap();
-        let res = eval(eval_obj[2].clone(), &mut obj_manager).unwrap();
-        assert_eq!(res, LispObject::number(47.));
+                LispObject::symbol("test"),
+            ];
+            let mut obj_manager = test_manager(engine);
+            let _res = eval(eval_obj[0].clone(), &mut obj_manager).unwrap();
+            let _res = eval(eval_obj[1].clone(), &mut obj_manager).unwrap();
+            let res = eval(eval_obj[2].clone(), &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::integer(47));
+        }
     }
 
     #[test]
     fn test_recursive_no_var() {
-        let eval_obj = LispObject::list(&[
-            LispObject::symbol("+"),
-            LispObject::list(&[
+        for engine in ENGINES {
+            let eval_obj = LispObject::list(&[
                 LispObject::symbol("+"),
-                LispObject::number(2.),
-                LispObject::number(3.),
-            ]),
-            LispObject::number(4.),
-        ]);
+                LispObject::list(&[
+                    LispObject::symbol("+"),
+                    LispObject::integer(2),
+                    LispObject::integer(3),
+                ]),
+                LispObject::integer(4),
+            ]);
+            let mut obj_manager = test_manager(engine);
+            let res = eval(eval_obj, &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::integer(9));
+        }
+    }
+
+    fn nested_code(depth: usize) -> String {
+        format!("{}0{}", "(+ 1 ".repeat(depth), ")".repeat(depth))
+    }
+
+    fn nested_additions(depth: usize) -> LispObject {
+        let code = nested_code(depth);
+        crate::ast::ast(&code).unwrap().into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_eval_deep_nesting() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval(nested_additions(3_000), &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::integer(3_000));
+            // Deep recursion does not nest on the Rust stack either.
+            let code = "(defun down (n) (if (= n 0) 0 (+ 1 (down (- n 1))))) (down 10000)";
+            assert_eq!(
+                eval_str(code, &mut obj_manager),
+                LispObject::integer(10_000)
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_stack_overflow() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            obj_manager.set_max_depth(1_000);
+            let res = eval(nested_additions(2_000), &mut obj_manager);
+            assert_eq!(res.unwrap_err().err_type(), &ErrorType::StackOverflow);
+            // The manager is still usable after the overflow.
+            let res = eval(nested_additions(500), &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::integer(500));
+        }
+    }
+
+    #[test]
+    fn test_eval_defun_arguments() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(defun add3 (a b c) (add a (add b c))) (add3 1 2 3)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(6));
+            let res = eval_str(
+                "(defun opt (a &optional b) (if b (add a b) a)) (list (opt 1) (opt 1 2))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "(1 3)");
+            // Recursion that peels one argument off per call.
+            let res = eval_str(
+                "(defun count-args (first &rest rest)
+                   (if rest (add 1 (apply 'count-args rest)) 1))
+                 (count-args 'a 'b 'c 'd)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(4));
+            let res = eval(
+                crate::ast::ast("(add3 1 2)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn test_eval_closures() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(defun make-adder (n) (lambda (x) (+ x n)))
+                 (set 'add2 (make-adder 2))
+                 (list (funcall add2 3) ((make-adder 10) 1) (apply add2 (list 5)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "(5 11 7)");
+            let res = eval_str("(funcall (function +) 1 2)", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(3));
+        }
+    }
+
+    #[test]
+    fn test_eval_macro() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(defmacro when (cond &rest body) (list 'if cond (apply 'list 'progn body)))
+                 (when t (set 'x 20) (add x 1))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(21));
+            let res = eval_str("(when nil (set 'x 30))", &mut obj_manager);
+            assert_eq!(res, LispObject::nil());
+            assert_eq!(
+                eval(LispObject::symbol("x"), &mut obj_manager).unwrap(),
+                LispObject::integer(20)
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_undefined_function() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval(
+                crate::ast::ast("(no-such-function 1)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            let err = res.unwrap_err();
+            assert_eq!(err.err_type(), &ErrorType::UndefinedFunction);
+            assert_eq!(err.objects(), &[LispObject::symbol("no-such-function")]);
+            assert_eq!(
+                err.to_string(),
+                "1:1: undefined-function: No function with that name: no-such-function"
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_error_span() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let form = crate::ast::ast("(add 1\n  (add 2 (list 3)))")
+                .unwrap()
+                .remove(0);
+            let err = eval(form, &mut obj_manager).unwrap_err();
+            assert_eq!(err.err_type(), &ErrorType::TypeError);
+            assert_eq!(err.span(), Some(Span { line: 2, column: 3 }));
+            assert_eq!(err.objects()[0].get_string(), "(3)");
+        }
+    }
+
+    #[test]
+    fn test_eval_namespaces() {
+        for engine in ENGINES {
+            let code = "(defun f () 1) (set 'f 2)";
+            let call = || crate::ast::ast("(f)").unwrap().remove(0);
+
+            let mut obj_manager = test_manager(engine);
+            eval_str(code, &mut obj_manager);
+            assert!(eval(call(), &mut obj_manager).is_err());
+
+            let mut obj_manager = test_manager(engine);
+            obj_manager.set_namespace(Namespace::Separate);
+            eval_str(code, &mut obj_manager);
+            assert_eq!(
+                eval(call(), &mut obj_manager).unwrap(),
+                LispObject::integer(1)
+            );
+            assert_eq!(
+                eval(LispObject::symbol("f"), &mut obj_manager).unwrap(),
+                LispObject::integer(2)
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_handler_case() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(handler-case (add 1 (list 2)) (type-error (c) (condition-type c)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "type-error");
+            let res = eval_str(
+                "(define-condition my-error (error))
+                 (defun fail (x) (error 'my-error x 2))
+                 (defun outer () (add 1 (fail 1)))
+                 (handler-case (outer)
+                   (type-error () 'wrong)
+                   (error (c) (condition-objects c)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "(1 2)");
+            assert_eq!(obj_manager.depth(), 1);
+            let res = eval_str(
+                "(handler-case (error \"boom\" 1)
+                   (simple-error (c) (list (condition-type c) (condition-objects c))))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "(simple-error (1))");
+            let res = eval_str(
+                "(handler-case (format nil \"~q\")
+                   (simple-error () 'wrong)
+                   (error (c) (condition-type c)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "runtime-error");
+            let err = eval(
+                crate::ast::ast("(error \"boom\")").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(err.unwrap_err().message(), "boom");
+            let res = eval(
+                crate::ast::ast("(handler-case (outer) (type-error () 'wrong))")
+                    .unwrap()
+                    .remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(
+                res.unwrap_err().err_type(),
+                &ErrorType::Custom("my-error".to_string())
+            );
+            assert_eq!(obj_manager.depth(), 1);
+        }
+    }
+
+    #[test]
+    fn test_eval_handle_stack_overflow() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            obj_manager.set_max_depth(1_000);
+            let code = format!(
+                "(handler-case {} (stack-overflow () 'overflow))",
+                nested_code(2_000)
+            );
+            assert_eq!(eval_str(&code, &mut obj_manager).get_string(), "overflow");
+        }
+    }
+
+    #[test]
+    fn test_eval_unwind_protect() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str("(unwind-protect 1 (set 'x 2))", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(1));
+            assert_eq!(
+                eval(LispObject::symbol("x"), &mut obj_manager).unwrap(),
+                LispObject::integer(2)
+            );
+            let res = eval_str(
+                "(handler-case
+                   (unwind-protect (error 'boom) (set 'cleaned t))
+                   (boom () cleaned))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::bool(true));
+        }
+    }
+
+    #[test]
+    fn test_eval_handler_bind() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            eval_str("(set 'seen nil) (set 'noted nil)", &mut obj_manager);
+            // The handler declines by returning, the outer handler-case catches.
+            let res = eval_str(
+                "(handler-case
+                   (handler-bind ((error (lambda (c) (set 'seen (condition-type c)))))
+                     (error 'boom))
+                   (error () seen))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.get_string(), "boom");
+            // Conditions raised with signal are ignored if nobody handles them.
+            let res = eval_str(
+                "(handler-bind ((note (lambda (c) (set 'noted t)))) (signal 'note) 1)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(1));
+            assert_eq!(
+                eval(LispObject::symbol("noted"), &mut obj_manager).unwrap(),
+                LispObject::bool(true)
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_restarts() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(defun checked (x)
+                   (restart-case (error 'bad-value x)
+                     (use-value (v) v)
+                     (skip () 0)))
+                 (handler-bind ((bad-value (lambda (c) (invoke-restart 'use-value 42))))
+                   (add 1 (checked 2)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(43));
+            let res = eval_str(
+                "(handler-bind ((bad-value (lambda (c) (invoke-restart 'skip))))
+                   (add 1 (checked 2)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(1));
+            let res = eval(
+                crate::ast::ast("(invoke-restart 'missing)")
+                    .unwrap()
+                    .remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(res.unwrap_err().err_type(), &ErrorType::ControlError);
+            assert_eq!(obj_manager.depth(), 1);
+        }
+    }
+
+    #[test]
+    fn test_eval_catch_throw() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(set 'cleaned nil)
+                 (defun deep (x) (unwind-protect (throw 'done x) (set 'cleaned t)))
+                 (add 1 (catch 'done (add 100 (deep 41))))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(42));
+            assert_eq!(obj_manager.depth(), 1);
+            let res = eval_str("cleaned", &mut obj_manager);
+            assert_eq!(res, LispObject::bool(true));
+            let res = eval_str(
+                "(catch 'outer (catch 'inner (throw 'outer 1)) 2)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(1));
+            let res = eval(
+                crate::ast::ast("(throw 'nowhere 1)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(res.unwrap_err().err_type(), &ErrorType::ControlError);
+        }
+    }
+
+    #[test]
+    fn test_eval_block_return_from() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str("(block b (add 1 (return-from b 5)) 7)", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(5));
+            let res = eval_str("(block nil (return 3) 4)", &mut obj_manager);
+            assert_eq!(res, LispObject::integer(3));
+            let res = eval_str(
+                "(defun first-big (x y) (if x (return-from first-big 'early)) y)
+                 (first-big t 'late)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::symbol("early").move_quoted());
+            assert_eq!(obj_manager.depth(), 1);
+            // The name is lexical, so a lambda in the block can return from it.
+            let res = eval_str(
+                "(defun call-it (f) (funcall f) 'not-returned)
+                 (defun outer () (call-it (lambda () (return-from outer 'returned))) 'after)
+                 (outer)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::symbol("returned").move_quoted());
+            // The block of the caller is not visible.
+            eval_str(
+                "(defun inner () (return-from caller 1))
+                 (defun caller () (inner) 2)",
+                &mut obj_manager,
+            );
+            let res = eval(
+                crate::ast::ast("(caller)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(res.unwrap_err().err_type(), &ErrorType::ControlError);
+            // Returning from a block that was already left is an error.
+            eval_str(
+                "(set 'escape nil)
+                 (block gone (set 'escape (lambda () (return-from gone 1))))",
+                &mut obj_manager,
+            );
+            let res = eval(
+                crate::ast::ast("(funcall escape)").unwrap().remove(0),
+                &mut obj_manager,
+            );
+            assert_eq!(res.unwrap_err().err_type(), &ErrorType::ControlError);
+            assert_eq!(obj_manager.depth(), 1);
+        }
+    }
+
+    #[test]
+    fn test_eval_backtrace() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let code = "(defun inner (x) (add x 'oops))
+                        (defun outer (y) (inner (add y 1)))
+                        (outer 1)";
+            let mut err = None;
+            for form in crate::ast::ast(code).unwrap() {
+                err = eval(form, &mut obj_manager).err();
+            }
+            let err = err.unwrap();
+            let names = err
+                .backtrace()
+                .iter()
+                .map(|frame| frame.name.as_str())
+                .collect::<Vec<&str>>();
+            assert_eq!(names, ["inner", "outer"]);
+            assert_eq!(err.backtrace()[0].args, [LispObject::integer(2)]);
+            assert_eq!(
+                err.backtrace()[0].span,
+                Some(Span {
+                    line: 2,
+                    column: 42
+                })
+            );
+            assert_eq!(err.backtrace()[0].to_string(), "(inner 2) at 2:42");
+            assert_eq!(obj_manager.depth(), 1);
+
+            let res = eval_str(
+                "(handler-case (outer 1) (error (c) (backtrace c)))",
+                &mut obj_manager,
+            );
+            assert_eq!(res.to_string(), "'(((inner 2) 2 42) ((outer 1) 1 15))");
+            let res = eval_str(
+                "(defun where () (backtrace))
+                 (defun caller () (where))
+                 (caller)",
+                &mut obj_manager,
+            );
+            assert_eq!(res.to_string(), "'(((where) 2 35) ((caller) 3 18))");
+        }
+    }
+
+    #[test]
+    fn test_eval_frames_balanced() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let code = "(set 'x 1)
+                        (quote (a b))
+                        (defun f (a &optional b) (add a 1))
+                        (defmacro m (a) a)
+                        (f (f 1))
+                        (m (f 2))
+                        (funcall (lambda (y) (f y)) 3)
+                        (f 'not-a-number)
+                        (f)
+                        (no-such-function 1)
+                        (eval '(f 4))
+                        (eval '(f 'bad))
+                        (block b (f 1) (return-from b 2))
+                        (catch 'tag (f (throw 'tag 1)))
+                        (handler-case (f 'bad) (error (c) c))
+                        (unwind-protect (f 'bad) (f 1))
+                        (restart-case (f 'bad) (skip () 0))";
+            for form in crate::ast::ast(code).unwrap() {
+                let depth = obj_manager.depth();
+                let _ = eval(form, &mut obj_manager);
+                assert_eq!(obj_manager.depth(), depth);
+            }
+            assert_eq!(obj_manager.depth(), 1);
+        }
+    }
+
+    #[test]
+    fn test_eval_lexical_addresses() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(set 'x 100)
+                 (defun adder (x) (lambda (y) (add x y)))
+                 (defun shadow (x) (funcall (lambda (x) x) (add x 1)))
+                 (add (funcall (adder 1) 2) (shadow 10))",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(14));
+            // Setting a parameter changes the binding its address refers to.
+            let res = eval_str(
+                "(defun counter (n) (lambda () (set 'n (add n 1)) n))
+                 (set 'c (counter 0))
+                 (funcall c)
+                 (funcall c)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(2));
+            // Code from a macro is looked up by name in the scope it expands to.
+            let res = eval_str(
+                "(defmacro with-y (form) (list 'funcall (list 'lambda (quote (y)) form) 5))
+                 (defun use-y (y) (with-y (add y 1)))
+                 (use-y 1)",
+                &mut obj_manager,
+            );
+            assert_eq!(res, LispObject::integer(6));
+            assert_eq!(eval_str("x", &mut obj_manager), LispObject::integer(100));
+        }
+    }
+
+    fn big_list(len: usize) -> LispObject {
+        let elements = (0..len).map(|i| LispObject::integer(i as i64)).collect();
+        LispObject::new_with(LispType::list(elements), true)
+    }
+
+    #[test]
+    fn test_eval_shares_lists() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let big = big_list(10_000);
+            obj_manager.set_val(LispObject::symbol("big"), big.clone());
+            let res = eval_str(
+                "(defun touch (l) (funcall (lambda (x) x) l))
+                 (touch big)",
+                &mut obj_manager,
+            );
+            match (big.ltype(), res.ltype()) {
+                (LispType::List(a), LispType::List(b)) => assert!(a.ptr_eq(b)),
+                _ => panic!("Expected lists"),
+            }
+        }
+    }
+
+    /// Walks a list with 10,000 elements with car and cdr and takes its
+    /// length. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_eval_big_list() {
         let mut obj_manager = Manager::default();
-        let res = eval(eval_obj, &mut obj_manager).unwrap();
-        assert_eq!(res, LispObject::number(9.));
+        obj_manager.set_val(LispObject::symbol("big"), big_list(10_000));
+        eval_str(
+            "(defun sum (l acc) (if l (sum (cdr l) (+ acc (car l))) acc))",
+            &mut obj_manager,
+        );
+        let walk = crate::ast::ast("(sum big 0)").unwrap().remove(0);
+        let start = std::time::Instant::now();
+        for _ in 0..10 {
+            let res = eval(walk.clone(), &mut obj_manager).unwrap();
+            assert_eq!(res, LispObject::integer(49_995_000));
+        }
+        println!("10 walks over a 10000 element list: {:?}", start.elapsed());
+
+        let length = crate::ast::ast("(length big)").unwrap().remove(0);
+        let start = std::time::Instant::now();
+        for _ in 0..10_000 {
+            eval(length.clone(), &mut obj_manager).unwrap();
+        }
+        println!(
+            "10000 lengths of a 10000 element list: {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_eval_compiles_functions() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            let res = eval_str(
+                "(defun f (x) (if x (lambda () x) 'none))
+                 (f 1)",
+                &mut obj_manager,
+            );
+            let compiled = match res.ltype() {
+                LispType::Function(f) => match &**f {
+                    Function::Lambda(lambda) => lambda.code.is_some(),
+                    _ => false,
+                },
+                _ => false,
+            };
+            assert_eq!(compiled, engine == Engine::Vm);
+        }
+    }
+
+    /// Compares the engines on a function body. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_eval_engines() {
+        for engine in ENGINES {
+            let mut obj_manager = test_manager(engine);
+            eval_str(
+                "(defun work (x)
+                   (if x (add (add x 1) (add (add x 2) (add x (add 3 4)))) 0))",
+                &mut obj_manager,
+            );
+            let call = crate::ast::ast("(work 1)").unwrap().remove(0);
+            let start = std::time::Instant::now();
+            for _ in 0..100_000 {
+                eval(call.clone(), &mut obj_manager).unwrap();
+            }
+            println!("{:?}: 100000 calls in {:?}", engine, start.elapsed());
+        }
     }
 }
@@ -0,0 +1,552 @@
+use std::io::Write;
+
+use crate::error::LispError;
+use crate::functions::{arity, proper_list};
+use crate::lispobject::{format_float, LispObject, LispType};
+use crate::printer::{write_out, Printer};
+use crate::resolver::MAX_NESTING;
+use crate::strings::string_arg;
+
+/// Formats the arguments after the control string like Common Lisp's
+/// `format`. With `nil` as destination the result is returned as a string,
+/// with `t` it is written to standard output and `format` returns nil.
+///
+/// The directives are:
+///
+/// - `~a`: the argument as `princ` prints it, `~s`: as `prin1` prints it,
+///   with strings in quotes. `~mincola` pads on the right, `~mincol@a` on
+///   the left.
+/// - `~d`: an integer in decimal, `~x`: in hexadecimal. `~mincol,padchard`
+///   pads on the left, `~@d` always prints the sign and `~:d` separates
+///   groups of three digits with commas.
+/// - `~f`: a number as a float, `~width,digitsf` with that many digits after
+///   the point, padded on the left to the width.
+/// - `~%`: a newline, `~~`: a tilde; `~n%` and `~n~` repeat them.
+/// - `~{...~}`: formats the elements of a list argument with the inner
+///   directives until none are left. `~^` leaves the iteration when no
+///   elements are left.
+/// - `~[...~;...~]`: formats the clause at the index given by an integer
+///   argument, `~:[false~;true~]` chooses by the truth of the argument. A
+///   last clause after `~:;` is formatted if there is no clause at the index.
+///
+/// Arguments that `~d`, `~x` or `~f` can not print are printed like `~a`.
+/// Numeric parameters can be at most `MAX_PARAM`.
+pub fn format(args: &[LispObject]) -> Result<LispObject, LispError> {
+    format_to(args, &mut std::io::stdout())
+}
+
+/// Like `format`, but writes to `stdout` instead of standard output.
+fn format_to(args: &[LispObject], stdout: &mut impl Write) -> Result<LispObject, LispError> {
+    arity("format", args, 2, usize::MAX)?;
+    let control = string_arg("format", args, 1)?;
+    let chars = control.chars().collect::<Vec<char>>();
+    let error = |message: String| {
+        LispError::runtime_error(format!("format: {}", message))
+            .with_object(LispObject::string(control))
+    };
+    let (pieces, _) = parse(&chars, 0, None, 0).map_err(error)?;
+    let mut out = String::new();
+    let mut format_args = Args {
+        items: args,
+        next: 2,
+        top: true,
+    };
+    run(&pieces, &mut format_args, &mut out)?;
+
+    match args[0].ltype() {
+        LispType::Bool(false) => Ok(LispObject::string(out)),
+        LispType::Bool(true) => {
+            write_out(stdout, &out)?;
+            Ok(LispObject::nil())
+        }
+        _ => Err(LispError::type_error(
+            "The destination of format must be nil or t",
+            args[0].clone(),
+        )),
+    }
+}
+
+/// The largest numeric parameter, which keeps a directive like `~1000000000d`
+/// from padding without bound.
+const MAX_PARAM: i64 = 1 << 16;
+
+/// A parameter of a directive, as in `~5,'0d`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Param {
+    Int(i64),
+    Char(char),
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Directive {
+        kind: char,
+        params: Vec<Option<Param>>,
+        colon: bool,
+        at: bool,
+    },
+    Iterate(Vec<Piece>),
+    Conditional {
+        clauses: Vec<Vec<Piece>>,
+        /// The clause after `~:;`.
+        default: Option<Vec<Piece>>,
+        colon: bool,
+    },
+    /// `~^`
+    Escape,
+}
+
+/// Parses the control string from `pos` up to the directive `end`, or to
+/// the end of the string if there is none. A `~;` ends the pieces of a
+/// clause as well. Returns the pieces and the position after them.
+/// `nesting` counts the enclosing `~{` and `~[`, which can be at most
+/// `MAX_NESTING` because parsing and running them recurse.
+fn parse(
+    chars: &[char],
+    mut pos: usize,
+    end: Option<char>,
+    nesting: usize,
+) -> Result<(Vec<Piece>, usize), String> {
+    if nesting > MAX_NESTING {
+        return Err("Directives are nested too deeply".to_string());
+    }
+    let mut pieces = vec![];
+    let mut text = String::new();
+    while pos < chars.len() {
+        if chars[pos] != '~' {
+            text.push(chars[pos]);
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        pos += 1;
+
+        let mut params = vec![];
+        loop {
+            let param = match chars.get(pos) {
+                Some('\'') => {
+                    let c = chars.get(pos + 1).ok_or("Missing parameter character")?;
+                    pos += 2;
+                    Some(Param::Char(*c))
+                }
+                Some(c) if c.is_ascii_digit() || *c == '-' => {
+                    let digits_start = pos;
+                    pos += 1;
+                    while chars.get(pos).is_some_and(|c| c.is_ascii_digit()) {
+                        pos += 1;
+                    }
+                    let digits = chars[digits_start..pos].iter().collect::<String>();
+                    let n = digits
+                        .parse()
+                        .map_err(|_| format!("Invalid parameter {}", digits))?;
+                    if n > MAX_PARAM {
+                        return Err(format!("Parameter {} is too large", digits));
+                    }
+                    Some(Param::Int(n))
+                }
+                _ => None,
+            };
+            let more = chars.get(pos) == Some(&',');
+            if param.is_some() || more {
+                params.push(param);
+            }
+            if !more {
+                break;
+            }
+            pos += 1;
+        }
+        let mut colon = false;
+        let mut at = false;
+        while let Some(c @ (':' | '@')) = chars.get(pos) {
+            colon |= *c == ':';
+            at |= *c == '@';
+            pos += 1;
+        }
+        let kind = match chars.get(pos) {
+            Some(kind) => kind.to_ascii_lowercase(),
+            None => return Err("Control string ends in a directive".to_string()),
+        };
+        pos += 1;
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(std::mem::take(&mut text)));
+        }
+        match kind {
+            '{' => {
+                let (body, next) = parse(chars, pos, Some('}'), nesting + 1)?;
+                pieces.push(Piece::Iterate(body));
+                pos = next;
+            }
+            '[' => {
+                let mut clauses = vec![];
+                let mut default = None;
+                loop {
+                    // A clause after `~:;` is the default.
+                    let is_default = chars[..pos].ends_with(&[':', ';']);
+                    let (clause, next) = parse(chars, pos, Some(']'), nesting + 1)?;
+                    pos = next;
+                    // The clause ended with `~;`, `~:;` or `~]`.
+                    let last = chars[pos - 1] == ']';
+                    match is_default {
+                        true if !last => {
+                            return Err("~:; must come before the last clause".to_string())
+                        }
+                        true => default = Some(clause),
+                        false => clauses.push(clause),
+                    }
+                    if last {
+                        break;
+                    }
+                }
+                pieces.push(Piece::Conditional {
+                    clauses,
+                    default,
+                    colon,
+                });
+            }
+            '}' | ']' if end == Some(kind) => return Ok((pieces, pos)),
+            ';' if end == Some(']') => return Ok((pieces, pos)),
+            '^' => pieces.push(Piece::Escape),
+            'a' | 's' | 'd' | 'x' | 'f' | '%' | '~' => pieces.push(Piece::Directive {
+                kind,
+                params,
+                colon,
+                at,
+            }),
+            _ => {
+                let directive = chars[start..pos].iter().collect::<String>();
+                return Err(format!("Unknown directive {}", directive));
+            }
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    match end {
+        Some(end) => Err(format!("Missing ~{}", end)),
+        None => Ok((pieces, pos)),
+    }
+}
+
+/// The arguments the directives consume. At the top these are the arguments
+/// of `format`, starting after the control string, inside `~{` the elements
+/// of its list.
+struct Args<'a> {
+    items: &'a [LispObject],
+    next: usize,
+    top: bool,
+}
+
+impl Args<'_> {
+    fn pop(&mut self) -> Result<&LispObject, LispError> {
+        let arg = self.items.get(self.next).ok_or_else(|| {
+            LispError::runtime_error("format: Not enough arguments for the directives")
+        })?;
+        self.next += 1;
+        Ok(arg)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.next >= self.items.len()
+    }
+
+    /// The elements of the next argument, which must be a proper list.
+    fn pop_list(&mut self) -> Result<Vec<LispObject>, LispError> {
+        self.pop()?;
+        let index = self.next - 1;
+        if self.top {
+            return proper_list("format", self.items, index);
+        }
+        let list = &self.items[index];
+        proper_list("format", std::slice::from_ref(list), 0)
+            .map_err(|_| LispError::type_error("format: ~{ needs a proper list", list.clone()))
+    }
+}
+
+/// Formats the pieces into `out`. Returns false if a `~^` ended the
+/// formatting early.
+fn run(pieces: &[Piece], args: &mut Args, out: &mut String) -> Result<bool, LispError> {
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => out.push_str(text),
+            Piece::Escape if args.is_empty() => return Ok(false),
+            Piece::Escape => {}
+            Piece::Iterate(body) => {
+                let items = args.pop_list()?;
+                let mut inner = Args {
+                    items: &items,
+                    next: 0,
+                    top: false,
+                };
+                while !inner.is_empty() {
+                    let before = inner.next;
+                    if !run(body, &mut inner, out)? || inner.next == before {
+                        break;
+                    }
+                }
+            }
+            Piece::Conditional {
+                clauses,
+                default,
+                colon,
+            } => {
+                let arg = args.pop()?;
+                let index = match (colon, arg.ltype()) {
+                    (true, _) => arg.is_true() as usize,
+                    (false, LispType::Integer(n)) => usize::try_from(*n).unwrap_or(usize::MAX),
+                    (false, _) => {
+                        return Err(LispError::type_error(
+                            "format: ~[ needs an integer argument",
+                            arg.clone(),
+                        ))
+                    }
+                };
+                if let Some(clause) = clauses.get(index).or(default.as_ref()) {
+                    if !run(clause, args, out)? {
+                        return Ok(false);
+                    }
+                }
+            }
+            Piece::Directive {
+                kind,
+                params,
+                colon,
+                at,
+            } => directive(*kind, params, *colon, *at, args, out)?,
+        }
+    }
+    Ok(true)
+}
+
+fn int_param(params: &[Option<Param>], i: usize) -> Option<i64> {
+    match params.get(i) {
+        Some(Some(Param::Int(n))) => Some(*n),
+        _ => None,
+    }
+}
+
+fn char_param(params: &[Option<Param>], i: usize) -> Option<char> {
+    match params.get(i) {
+        Some(Some(Param::Char(c))) => Some(*c),
+        _ => None,
+    }
+}
+
+/// Pads `s` to `width` characters, on the left or on the right.
+fn pad(s: String, width: Option<i64>, padchar: char, left: bool) -> String {
+    let missing = (width.unwrap_or(0).max(0) as usize).saturating_sub(s.chars().count());
+    let padding = padchar.to_string().repeat(missing);
+    match left {
+        true => padding + &s,
+        false => s + &padding,
+    }
+}
+
+fn directive(
+    kind: char,
+    params: &[Option<Param>],
+    colon: bool,
+    at: bool,
+    args: &mut Args,
+    out: &mut String,
+) -> Result<(), LispError> {
+    let repeat = int_param(params, 0).unwrap_or(1).max(0) as usize;
+    let text = match kind {
+        '%' => "\n".repeat(repeat),
+        '~' => "~".repeat(repeat),
+        'a' => pad(args.pop()?.get_string(), int_param(params, 0), ' ', at),
+        's' => pad(
+            Printer::PRIN1.print(args.pop()?),
+            int_param(params, 0),
+            ' ',
+            at,
+        ),
+        'd' | 'x' => {
+            let arg = args.pop()?;
+            let digits = match arg.ltype() {
+                LispType::Integer(n) => {
+                    let digits = match kind {
+                        'd' => n.unsigned_abs().to_string(),
+                        _ => format!("{:X}", n.unsigned_abs()),
+                    };
+                    let digits = if colon { group(&digits) } else { digits };
+                    match (*n < 0, at) {
+                        (true, _) => format!("-{}", digits),
+                        (false, true) => format!("+{}", digits),
+                        (false, false) => digits,
+                    }
+                }
+                _ => arg.get_string(),
+            };
+            let padchar = char_param(params, 1).unwrap_or(' ');
+            pad(digits, int_param(params, 0), padchar, true)
+        }
+        'f' => {
+            let arg = args.pop()?;
+            let n = match arg.ltype() {
+                LispType::Integer(n) => Some(*n as f64),
+                LispType::Number(n) => Some(*n),
+                _ => None,
+            };
+            let s = match (n, int_param(params, 1)) {
+                (Some(n), Some(digits)) => format!("{:.*}", digits.max(0) as usize, n),
+                (Some(n), None) => format_float(n),
+                (None, _) => arg.get_string(),
+            };
+            pad(s, int_param(params, 0), ' ', true)
+        }
+        _ => unreachable!(),
+    };
+    out.push_str(&text);
+    Ok(())
+}
+
+/// Separates groups of three digits with commas.
+fn group(digits: &str) -> String {
+    let mut res = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            res.push(',');
+        }
+        res.push(c);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorType;
+
+    fn format_str(args: &str) -> Result<LispObject, LispError> {
+        format(&crate::ast::ast(args).unwrap())
+    }
+
+    /// One case per directive and modifier the doc comment of `format`
+    /// lists.
+    #[test]
+    fn test_format_directives() {
+        let cases = [
+            ("nil \"plain\"", "plain"),
+            ("nil \"~a and ~a\" 1 \"two\"", "1 and two"),
+            ("nil \"[~5a]\" \"ab\"", "[ab   ]"),
+            ("nil \"[~5@a]\" \"ab\"", "[   ab]"),
+            ("nil \"~a\" (1 \"b\" c)", "(1 b c)"),
+            ("nil \"~s\" \"say \\\"hi\\\"\"", "\"say \\\"hi\\\"\""),
+            ("nil \"~s\" (1 \"b\")", "(1 \"b\")"),
+            ("nil \"[~6s]\" \"ab\"", "[\"ab\"  ]"),
+            ("nil \"~d\" -42", "-42"),
+            ("nil \"~@d\" 42", "+42"),
+            ("nil \"~:d\" 1234567", "1,234,567"),
+            ("nil \"[~5d]\" 42", "[   42]"),
+            ("nil \"[~5,'0d]\" 42", "[00042]"),
+            ("nil \"~d\" 1.5", "1.5"),
+            ("nil \"~x\" 255", "FF"),
+            ("nil \"~4,'0x\" 10", "000A"),
+            ("nil \"~x\" -255", "-FF"),
+            ("nil \"~f\" 2", "2.0"),
+            ("nil \"~f\" 1.25", "1.25"),
+            ("nil \"~,2f\" 3.14159", "3.14"),
+            ("nil \"[~8,3f]\" -1.5", "[  -1.500]"),
+            ("nil \"~,0f\" 2.5", "2"),
+            ("nil \"~f\" a", "a"),
+            ("nil \"a~%b~2%c\"", "a\nb\n\nc"),
+            ("nil \"~~ ~3~\"", "~ ~~~"),
+            ("nil \"~{<~a>~}\" (1 2 3)", "<1><2><3>"),
+            ("nil \"~{~a~^, ~}\" (1 2 3)", "1, 2, 3"),
+            ("nil \"~{~a=~a~^ ~}\" (a 1 b 2)", "a=1 b=2"),
+            ("nil \"~{~a~}\" nil", ""),
+            ("nil \"~{~{~a~}~^|~}\" ((1 2) (3))", "12|3"),
+            ("nil \"~[zero~;one~;two~]\" 1", "one"),
+            ("nil \"~[zero~;one~]\" 5", ""),
+            ("nil \"~[zero~;one~:;many~]\" 5", "many"),
+            ("nil \"~[zero~;one~:;many~]\" 1", "one"),
+            ("nil \"~[zero~:;~a~]\" -1 x", "x"),
+            ("nil \"~:[no~;yes~]\" nil", "no"),
+            ("nil \"~:[no~;yes ~a~]\" t 3", "yes 3"),
+            ("nil \"~A ~D ~X\" 1 2 255", "1 2 FF"),
+        ];
+        for (args, expected) in cases {
+            let res = format_str(args).unwrap();
+            assert_eq!(res, LispObject::string(expected), "(format {})", args);
+        }
+        let mut stdout = vec![];
+        let args = crate::ast::ast("t \"~a\" 1").unwrap();
+        assert_eq!(format_to(&args, &mut stdout).unwrap(), LispObject::nil());
+        assert_eq!(stdout, b"1");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let cases = [
+            (
+                "nil \"~a\"",
+                ErrorType::RuntimeError,
+                "format: Not enough arguments",
+            ),
+            (
+                "nil \"~q\"",
+                ErrorType::RuntimeError,
+                "format: Unknown directive ~q",
+            ),
+            (
+                "nil \"~{~a\" (1)",
+                ErrorType::RuntimeError,
+                "format: Missing ~}",
+            ),
+            (
+                "nil \"~[a~;b\" 1",
+                ErrorType::RuntimeError,
+                "format: Missing ~]",
+            ),
+            (
+                "nil \"~}\"",
+                ErrorType::RuntimeError,
+                "format: Unknown directive ~}",
+            ),
+            (
+                "nil \"~\"",
+                ErrorType::RuntimeError,
+                "format: Control string ends",
+            ),
+            (
+                "nil \"~a ~{~a~}\" 1 2",
+                ErrorType::TypeError,
+                "Argument 4 of format is not a list",
+            ),
+            (
+                "nil \"~{~{~a~}~}\" (1)",
+                ErrorType::TypeError,
+                "format: ~{ needs a proper list",
+            ),
+            (
+                "nil \"~[a~:;b~;c~]\" 1",
+                ErrorType::RuntimeError,
+                "format: ~:; must come before the last clause",
+            ),
+            (
+                &format!("nil \"{}\"", "~{".repeat(1 << 14)),
+                ErrorType::RuntimeError,
+                "format: Directives are nested too deeply",
+            ),
+            (
+                "nil \"~1000000000d\" 1",
+                ErrorType::RuntimeError,
+                "format: Parameter 1000000000 is too large",
+            ),
+            (
+                "nil \"~[a~]\" x",
+                ErrorType::TypeError,
+                "format: ~[ needs an integer",
+            ),
+            ("1 \"a\"", ErrorType::TypeError, "The destination of format"),
+            ("nil 1", ErrorType::TypeError, "Argument 2 of format"),
+        ];
+        for (args, err_type, message) in cases {
+            let err = format_str(args).unwrap_err();
+            assert_eq!(err.err_type(), &err_type, "(format {})", args);
+            assert!(err.message().starts_with(message), "{}", err.message());
+        }
+    }
+}
@@ -1,55 +1,1088 @@
-use crate::lispobject::{LispObject, LispType};
+use std::cmp::Ordering;
+use std::rc::Rc;
 
-pub fn call_builtin(fn_name: &str, args: &[LispObject]) -> Result<LispObject, &'static str> {
+use crate::error::{ErrorType, LispError};
+use crate::format::format;
+use crate::lispobject::{format_float, LispObject, LispType};
+use crate::printer;
+use crate::regex::call_regex_builtin;
+use crate::sequence;
+use crate::strings::call_string_builtin;
+
+/// Names of the functions `call_builtin` knows that have side effects.
+const BUILTINS: &[&str] = &["print", "prin1", "princ", "pprint", "format"];
+
+/// Builtins without side effects. The optimizer folds calls to them whose
+/// arguments are constants.
+pub const PURE_BUILTINS: &[&str] = &[
+    "cons",
+    "list",
+    "car",
+    "cdr",
+    "caar",
+    "cadr",
+    "cdar",
+    "cddr",
+    "caaar",
+    "caadr",
+    "cadar",
+    "caddr",
+    "cdaar",
+    "cdadr",
+    "cddar",
+    "cdddr",
+    "nth",
+    "append",
+    "last",
+    "butlast",
+    "member",
+    "assoc",
+    "rassoc",
+    "remove",
+    "delete-duplicates",
+    "copy-list",
+    "null",
+    "consp",
+    "equal",
+    "vector",
+    "length",
+    "elt",
+    "subseq",
+    "concatenate",
+    "reverse",
+    "string-length",
+    "substring",
+    "string-append",
+    "string-split",
+    "string-join",
+    "string-trim",
+    "string-upcase",
+    "string-downcase",
+    "string-contains",
+    "string-prefix?",
+    "string-suffix?",
+    "string-replace",
+    "string-index",
+    "string->list",
+    "string->symbol",
+    "symbol->string",
+    "string=",
+    "string<",
+    "prin1-to-string",
+    "princ-to-string",
+    "regex-compile",
+    "regex-match",
+    "regex-search",
+    "regex-replace",
+    "regex-split",
+    "regex-match-groups",
+    "add",
+    "+",
+    "-",
+    "*",
+    "/",
+    "mod",
+    "rem",
+    "quotient",
+    "abs",
+    "min",
+    "max",
+    "=",
+    "<",
+    ">",
+    "<=",
+    ">=",
+    "/=",
+    "sqrt",
+    "expt",
+    "exp",
+    "log",
+    "sin",
+    "cos",
+    "tan",
+    "asin",
+    "acos",
+    "atan",
+    "floor",
+    "ceiling",
+    "round",
+    "truncate",
+    "gcd",
+    "lcm",
+    "number->string",
+    "string->number",
+    "zerop",
+    "evenp",
+    "nanp",
+    "logand",
+    "logior",
+    "logxor",
+    "lognot",
+    "ash",
+    "logcount",
+    "integer-length",
+    "format-radix",
+    "condition-type",
+    "condition-objects",
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name) || PURE_BUILTINS.contains(&name)
+}
+
+pub fn call_builtin(fn_name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
     match fn_name {
         "cons" => cons(args),
         "list" => list(args),
+        "car" => car(args),
+        "cdr" => cdr(args),
+        "caar" | "cadr" | "cdar" | "cddr" | "caaar" | "caadr" | "cadar" | "caddr" | "cdaar"
+        | "cdadr" | "cddar" | "cdddr" => cxr(fn_name, args),
+        "nth" => nth(args),
+        "append" => append(args),
+        "last" => last(args),
+        "butlast" => butlast(args),
+        "member" => member(args),
+        "assoc" => find_entry("assoc", args, false),
+        "rassoc" => find_entry("rassoc", args, true),
+        "remove" => remove(args),
+        "delete-duplicates" => delete_duplicates(args),
+        "copy-list" => copy_list(args),
+        "null" => null(args),
+        "consp" => consp(args),
+        "equal" => equal(args),
+        "vector" => sequence::vector(args),
+        "length" => sequence::length(args),
+        "elt" => sequence::elt(args),
+        "subseq" => sequence::subseq(args),
+        "concatenate" => sequence::concatenate(args),
+        "reverse" => sequence::reverse(args),
+        "string-length" | "substring" | "string-append" | "string-split" | "string-join"
+        | "string-trim" | "string-upcase" | "string-downcase" | "string-contains"
+        | "string-prefix?" | "string-suffix?" | "string-replace" | "string-index"
+        | "string->list" | "string->symbol" | "symbol->string" | "string=" | "string<" => {
+            call_string_builtin(fn_name, args)
+        }
+        "regex-compile" | "regex-match" | "regex-search" | "regex-replace" | "regex-split"
+        | "regex-match-groups" => call_regex_builtin(fn_name, args),
         "add" | "+" => add(args),
-        "print" => print(args),
-        _ => Err("No built in function with that name."),
+        "-" => subtract(args),
+        "*" => multiply(args),
+        "/" => divide(args),
+        "mod" => modulo(args),
+        "rem" => remainder(args),
+        "quotient" => quotient(args),
+        "abs" => abs(args),
+        "min" => min(args),
+        "max" => max(args),
+        "=" => compare("=", args, |o| o == Ordering::Equal),
+        "<" => compare("<", args, |o| o == Ordering::Less),
+        ">" => compare(">", args, |o| o == Ordering::Greater),
+        "<=" => compare("<=", args, |o| o != Ordering::Greater),
+        ">=" => compare(">=", args, |o| o != Ordering::Less),
+        "/=" => not_equal(args),
+        "sqrt" => float_fn("sqrt", args, f64::sqrt),
+        "expt" => expt(args),
+        "exp" => float_fn("exp", args, f64::exp),
+        "log" => log(args),
+        "sin" => float_fn("sin", args, f64::sin),
+        "cos" => float_fn("cos", args, f64::cos),
+        "tan" => float_fn("tan", args, f64::tan),
+        "asin" => float_fn("asin", args, f64::asin),
+        "acos" => float_fn("acos", args, f64::acos),
+        "atan" => atan(args),
+        "floor" => round_with("floor", args, floor_div, f64::floor),
+        "ceiling" => round_with("ceiling", args, ceiling_div, f64::ceil),
+        "round" => round_with("round", args, round_div, f64::round_ties_even),
+        "truncate" => round_with("truncate", args, |n, d| n / d, f64::trunc),
+        "gcd" => gcd(args),
+        "lcm" => lcm(args),
+        "number->string" => number_to_string(args),
+        "string->number" => string_to_number(args),
+        "zerop" => zerop(args),
+        "evenp" => evenp(args),
+        "nanp" => nanp(args),
+        "logand" => bitwise("logand", args, -1, |a, b| a & b),
+        "logior" => bitwise("logior", args, 0, |a, b| a | b),
+        "logxor" => bitwise("logxor", args, 0, |a, b| a ^ b),
+        "lognot" => lognot(args),
+        "ash" => ash(args),
+        "logcount" => logcount(args),
+        "integer-length" => integer_length(args),
+        "format-radix" => format_radix(args),
+        "print" => printer::print(args),
+        "prin1" => printer::prin1(args),
+        "princ" => printer::princ(args),
+        "pprint" => printer::pprint(args),
+        "prin1-to-string" => printer::prin1_to_string(args),
+        "princ-to-string" => printer::princ_to_string(args),
+        "format" => format(args),
+        "condition-type" => condition_type(args),
+        "condition-objects" => condition_objects(args),
+        _ => Err(LispError::undefined_function(fn_name)),
     }
 }
 
-pub fn cons(args: &[LispObject]) -> Result<LispObject, &'static str> {
-    let (first, second) = match (args.get(0), args.get(1)) {
+/// Builds a cons pair, which shares the second argument, so it takes
+/// constant time. A pair whose cdr is a list is a list itself and is quoted
+/// like the lists `list` builds.
+pub fn cons(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let (first, second) = match (args.first(), args.get(1)) {
         (Some(f), Some(s)) => (f.clone(), s.clone()),
-        _ => return Err("Not enough arguments."),
+        _ => return Err(LispError::not_enough_arguments()),
     };
 
-    Ok(LispObject::cons(first, second))
+    let list = match second.ltype() {
+        LispType::Bool(false) | LispType::List(_) => true,
+        LispType::Cons(_) => second.is_quoted(),
+        _ => false,
+    };
+    let pair = LispObject::cons(first, second);
+    Ok(if list { pair.move_quoted() } else { pair })
 }
 
-pub fn list(args: &[LispObject]) -> Result<LispObject, &'static str> {
-    Ok(LispObject::new_with(LispType::List(args.into()), true))
+pub fn list(args: &[LispObject]) -> Result<LispObject, LispError> {
+    Ok(LispObject::new_with(LispType::list(args.into()), true))
 }
 
-pub fn add(args: &[LispObject]) -> Result<LispObject, &'static str> {
-    let (first, second) = match (args.get(0), args.get(1)) {
-        (Some(f), Some(s)) => (f.clone(), s.clone()),
-        _ => return Err("Not enough arguments,"),
+/// Splits a list into its elements and the object that ends it, which is
+/// nil unless the list ends in a cons pair. `None` if the object is not a
+/// list.
+fn decompose(obj: &LispObject) -> Option<(Vec<LispObject>, LispObject)> {
+    let mut elements = vec![];
+    let mut rest = obj.clone();
+    loop {
+        rest = match rest.ltype() {
+            LispType::Bool(false) => return Some((elements, rest)),
+            LispType::List(l) => {
+                elements.extend(l.iter().cloned());
+                return Some((elements, LispObject::nil()));
+            }
+            LispType::Cons(pair) => {
+                elements.push(pair.0.clone());
+                pair.1.clone()
+            }
+            _ if elements.is_empty() => return None,
+            _ => return Some((elements, rest)),
+        };
+    }
+}
+
+/// The inverse of `decompose`.
+pub fn make_list(mut elements: Vec<LispObject>, tail: LispObject) -> LispObject {
+    if !tail.is_true() {
+        return match elements.is_empty() {
+            true => LispObject::nil(),
+            false => LispObject::new_with(LispType::list(elements), true),
+        };
+    }
+    let mut res = tail;
+    while let Some(e) = elements.pop() {
+        res = LispObject::cons(e, res);
+    }
+    res
+}
+
+/// The argument at `index` of the builtin `name` split like `decompose`.
+fn list_arg(
+    name: &str,
+    args: &[LispObject],
+    index: usize,
+) -> Result<(Vec<LispObject>, LispObject), LispError> {
+    decompose(&args[index]).ok_or_else(|| {
+        LispError::type_error(
+            format!("Argument {} of {} is not a list", index + 1, name),
+            args[index].clone(),
+        )
+    })
+}
+
+/// The elements of the argument at `index`, which has to be a list that
+/// does not end in a cons pair.
+pub fn proper_list(
+    name: &str,
+    args: &[LispObject],
+    index: usize,
+) -> Result<Vec<LispObject>, LispError> {
+    match list_arg(name, args, index)? {
+        (elements, tail) if !tail.is_true() => Ok(elements),
+        _ => Err(LispError::type_error(
+            format!("Argument {} of {} is not a proper list", index + 1, name),
+            args[index].clone(),
+        )),
+    }
+}
+
+/// The argument at `index` as a count, which must not be negative.
+fn count_arg(name: &str, args: &[LispObject], index: usize) -> Result<usize, LispError> {
+    match args.get(index).map(|arg| arg.ltype()) {
+        None => Ok(1),
+        Some(LispType::Integer(n)) if *n >= 0 => Ok(*n as usize),
+        Some(_) => Err(LispError::type_error(
+            format!(
+                "Argument {} of {} is not a non-negative integer",
+                index + 1,
+                name
+            ),
+            args[index].clone(),
+        )),
+    }
+}
+
+/// The first element of a list and the rest of it, without copying the
+/// list. Both are nil for nil.
+fn split(
+    name: &str,
+    args: &[LispObject],
+    index: usize,
+) -> Result<(LispObject, LispObject), LispError> {
+    let list = &args[index];
+    match list.ltype() {
+        LispType::Bool(false) => Ok((LispObject::nil(), LispObject::nil())),
+        LispType::List(l) if l.len() > 1 => Ok((
+            l[0].clone(),
+            LispObject::new_with(LispType::List(l.tail()), true),
+        )),
+        LispType::List(l) => Ok((
+            l.first().cloned().unwrap_or_else(LispObject::nil),
+            LispObject::nil(),
+        )),
+        LispType::Cons(pair) => Ok((pair.0.clone(), pair.1.clone())),
+        _ => Err(LispError::type_error(
+            format!("Argument {} of {} is not a list", index + 1, name),
+            list.clone(),
+        )),
+    }
+}
+
+pub fn car(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("car", args, 1, 1)?;
+    Ok(split("car", args, 0)?.0)
+}
+
+pub fn cdr(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("cdr", args, 1, 1)?;
+    Ok(split("cdr", args, 0)?.1)
+}
+
+/// The accessors like `cadr`, which combine `car` and `cdr` from the right:
+/// `(cadr x)` is `(car (cdr x))`.
+fn cxr(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity(name, args, 1, 1)?;
+    let mut res = args[0].clone();
+    for op in name[1..name.len() - 1].chars().rev() {
+        let arg = [res];
+        res = match op {
+            'a' => car(&arg),
+            _ => cdr(&arg),
+        }
+        .map_err(|_| {
+            LispError::type_error(
+                format!("Argument 1 of {} is not a list", name),
+                args[0].clone(),
+            )
+        })?;
+    }
+    Ok(res)
+}
+
+/// The element at an index, or nil if the list is shorter.
+pub fn nth(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("nth", args, 2, 2)?;
+    let n = count_arg("nth", args, 0)?;
+    let (mut first, mut rest) = split("nth", args, 1)?;
+    for _ in 0..n {
+        // A list that ends in a cons pair ends before its last cdr.
+        match split("nth", std::slice::from_ref(&rest), 0) {
+            Ok(pair) if rest.is_true() => (first, rest) = pair,
+            _ => return Ok(LispObject::nil()),
+        }
+    }
+    Ok(first)
+}
+
+/// Joins lists. The last argument becomes the end of the result and may be
+/// any object.
+pub fn append(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let Some((last, lists)) = args.split_last() else {
+        return Ok(LispObject::nil());
+    };
+    let mut elements = vec![];
+    for i in 0..lists.len() {
+        elements.append(&mut proper_list("append", args, i)?);
+    }
+    match decompose(last) {
+        Some((mut rest, tail)) => {
+            elements.append(&mut rest);
+            Ok(make_list(elements, tail))
+        }
+        None => Ok(make_list(elements, last.clone())),
+    }
+}
+
+/// The last `n` elements of a list, 1 by default, with the end of the list.
+pub fn last(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("last", args, 1, 2)?;
+    let (mut elements, tail) = list_arg("last", args, 0)?;
+    let n = count_arg("last", args, 1)?;
+    let kept = elements.split_off(elements.len().saturating_sub(n));
+    Ok(make_list(kept, tail))
+}
+
+/// The list without its last `n` elements, 1 by default.
+pub fn butlast(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("butlast", args, 1, 2)?;
+    let (mut elements, _) = list_arg("butlast", args, 0)?;
+    let n = count_arg("butlast", args, 1)?;
+    elements.truncate(elements.len().saturating_sub(n));
+    Ok(make_list(elements, LispObject::nil()))
+}
+
+/// The rest of the list starting at the first element that is equal to the
+/// item, or nil.
+pub fn member(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("member", args, 2, 2)?;
+    let (mut elements, tail) = list_arg("member", args, 1)?;
+    match elements.iter().position(|e| e.equal(&args[0])) {
+        Some(i) => Ok(make_list(elements.split_off(i), tail)),
+        None => Ok(LispObject::nil()),
+    }
+}
+
+/// Finds the first entry of an association list whose key, or with
+/// `rassoc` whose value, is equal to the item. Entries that are nil are
+/// skipped.
+fn find_entry(name: &str, args: &[LispObject], by_value: bool) -> Result<LispObject, LispError> {
+    arity(name, args, 2, 2)?;
+    for entry in proper_list(name, args, 1)? {
+        if !entry.is_true() {
+            continue;
+        }
+        let part = match by_value {
+            true => cdr(std::slice::from_ref(&entry)),
+            false => car(std::slice::from_ref(&entry)),
+        }
+        .map_err(|_| {
+            LispError::type_error(format!("Entries of {} must be lists", name), entry.clone())
+        })?;
+        if part.equal(&args[0]) {
+            return Ok(entry);
+        }
+    }
+    Ok(LispObject::nil())
+}
+
+/// A copy of the list without the elements that are equal to the item.
+pub fn remove(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("remove", args, 2, 2)?;
+    let elements = proper_list("remove", args, 1)?;
+    let kept = elements
+        .into_iter()
+        .filter(|e| !e.equal(&args[0]))
+        .collect();
+    Ok(make_list(kept, LispObject::nil()))
+}
+
+/// A copy of the list in which only the last of equal elements is kept.
+pub fn delete_duplicates(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("delete-duplicates", args, 1, 1)?;
+    let elements = proper_list("delete-duplicates", args, 0)?;
+    let kept = elements
+        .iter()
+        .enumerate()
+        .filter(|(i, e)| !elements[i + 1..].iter().any(|later| later.equal(e)))
+        .map(|(_, e)| e.clone())
+        .collect();
+    Ok(make_list(kept, LispObject::nil()))
+}
+
+pub fn copy_list(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("copy-list", args, 1, 1)?;
+    let (elements, tail) = list_arg("copy-list", args, 0)?;
+    Ok(make_list(elements, tail))
+}
+
+pub fn null(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("null", args, 1, 1)?;
+    Ok(LispObject::bool(!args[0].is_true()))
+}
+
+pub fn equal(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("equal", args, 2, 2)?;
+    Ok(LispObject::bool(args[0].equal(&args[1])))
+}
+
+pub fn consp(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("consp", args, 1, 1)?;
+    let consp = match args[0].ltype() {
+        LispType::List(l) => !l.is_empty(),
+        LispType::Cons(_) => true,
+        _ => false,
+    };
+    Ok(LispObject::bool(consp))
+}
+
+/// A number argument of an arithmetic builtin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    fn to_f64(self) -> f64 {
+        match self {
+            Num::Int(n) => n as f64,
+            Num::Float(n) => n,
+        }
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_f64() == 0.
+    }
+
+    fn into_object(self) -> LispObject {
+        match self {
+            Num::Int(n) => LispObject::integer(n),
+            Num::Float(n) => LispObject::number(n),
+        }
+    }
+}
+
+/// The arguments of the builtin `name` as numbers. The error names the
+/// first argument that is not a number.
+fn numbers(name: &str, args: &[LispObject]) -> Result<Vec<Num>, LispError> {
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| match arg.ltype() {
+            LispType::Integer(n) => Ok(Num::Int(*n)),
+            LispType::Number(n) => Ok(Num::Float(*n)),
+            _ => Err(LispError::type_error(
+                format!("Argument {} of {} is not a number", i + 1, name),
+                arg.clone(),
+            )),
+        })
+        .collect()
+}
+
+/// The arguments of the builtin `name` as integers.
+fn integers(name: &str, args: &[LispObject]) -> Result<Vec<i64>, LispError> {
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| match arg.ltype() {
+            LispType::Integer(n) => Ok(*n),
+            _ => Err(LispError::type_error(
+                format!("Argument {} of {} is not an integer", i + 1, name),
+                arg.clone(),
+            )),
+        })
+        .collect()
+}
+
+/// Checks that `name` got at least `min` and at most `max` arguments.
+pub fn arity(name: &str, args: &[LispObject], min: usize, max: usize) -> Result<(), LispError> {
+    if args.len() < min || args.len() > max {
+        return Err(LispError::wrong_number_of_arguments(name, args.len()));
+    }
+    Ok(())
+}
+
+fn overflow(name: &str) -> LispError {
+    LispError::new(
+        ErrorType::ArithmeticError,
+        format!("Integer overflow in {}", name),
+    )
+}
+
+/// Combines two numbers. The result is an integer if both are integers and
+/// a float otherwise.
+fn arith(
+    name: &str,
+    a: Num,
+    b: Num,
+    int: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+) -> Result<Num, LispError> {
+    match (a, b) {
+        (Num::Int(a), Num::Int(b)) => int(a, b).map(Num::Int).ok_or_else(|| overflow(name)),
+        _ => Ok(Num::Float(float(a.to_f64(), b.to_f64()))),
+    }
+}
+
+/// Orders two numbers. Integers are compared exactly.
+fn cmp(a: Num, b: Num) -> Option<Ordering> {
+    match (a, b) {
+        (Num::Int(a), Num::Int(b)) => Some(a.cmp(&b)),
+        _ => a.to_f64().partial_cmp(&b.to_f64()),
+    }
+}
+
+pub fn add(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut sum = Num::Int(0);
+    for n in numbers("+", args)? {
+        sum = arith("+", sum, n, i64::checked_add, |a, b| a + b)?;
+    }
+    Ok(sum.into_object())
+}
+
+/// Subtracts the other arguments from the first, or negates a single one.
+pub fn subtract(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("-", args, 1, usize::MAX)?;
+    let nums = numbers("-", args)?;
+    let (mut res, rest) = match nums.len() {
+        1 => (Num::Int(0), &nums[..]),
+        _ => (nums[0], &nums[1..]),
+    };
+    for n in rest {
+        res = arith("-", res, *n, i64::checked_sub, |a, b| a - b)?;
+    }
+    Ok(res.into_object())
+}
+
+pub fn multiply(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut product = Num::Int(1);
+    for n in numbers("*", args)? {
+        product = arith("*", product, n, i64::checked_mul, |a, b| a * b)?;
+    }
+    Ok(product.into_object())
+}
+
+/// Divides the first argument by the others, or returns the reciprocal of a
+/// single one. Integers that divide evenly give an integer.
+pub fn divide(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("/", args, 1, usize::MAX)?;
+    let nums = numbers("/", args)?;
+    let (mut res, divisors) = match nums.len() {
+        1 => (Num::Int(1), 0..1),
+        len => (nums[0], 1..len),
+    };
+    for i in divisors {
+        if nums[i].is_zero() {
+            return Err(LispError::division_by_zero(args[i].clone()));
+        }
+        res = match (res, nums[i]) {
+            (Num::Int(n), Num::Int(d)) if n.checked_rem(d) == Some(0) => {
+                Num::Int(n.checked_div(d).ok_or_else(|| overflow("/"))?)
+            }
+            (n, d) => Num::Float(n.to_f64() / d.to_f64()),
+        };
+    }
+    Ok(res.into_object())
+}
+
+/// The two arguments of a division, checked for a zero divisor.
+fn division(name: &str, args: &[LispObject]) -> Result<(Num, Num), LispError> {
+    arity(name, args, 2, 2)?;
+    let nums = numbers(name, args)?;
+    if nums[1].is_zero() {
+        return Err(LispError::division_by_zero(args[1].clone()));
+    }
+    Ok((nums[0], nums[1]))
+}
+
+/// The remainder of the division rounded towards negative infinity, which
+/// has the sign of the divisor.
+pub fn modulo(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let (n, d) = division("mod", args)?;
+    let int = |n: i64, d: i64| {
+        let r = n.checked_rem(d)?;
+        Some(if r != 0 && (r < 0) != (d < 0) {
+            r + d
+        } else {
+            r
+        })
+    };
+    let res = arith("mod", n, d, int, |n, d| n - d * (n / d).floor())?;
+    Ok(res.into_object())
+}
+
+/// The remainder of the division rounded towards zero, which has the sign
+/// of the dividend.
+pub fn remainder(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let (n, d) = division("rem", args)?;
+    Ok(arith("rem", n, d, i64::checked_rem, |n, d| n % d)?.into_object())
+}
+
+/// The division rounded towards zero.
+pub fn quotient(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let (n, d) = division("quotient", args)?;
+    let res = arith("quotient", n, d, i64::checked_div, |n, d| (n / d).trunc())?;
+    Ok(res.into_object())
+}
+
+pub fn abs(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("abs", args, 1, 1)?;
+    match numbers("abs", args)?[0] {
+        Num::Int(n) => n
+            .checked_abs()
+            .map(LispObject::integer)
+            .ok_or_else(|| overflow("abs")),
+        Num::Float(n) => Ok(LispObject::number(n.abs())),
+    }
+}
+
+/// The argument that has the ordering `keep` to all others.
+fn extremum(name: &str, args: &[LispObject], keep: Ordering) -> Result<LispObject, LispError> {
+    arity(name, args, 1, usize::MAX)?;
+    let nums = numbers(name, args)?;
+    let mut best = nums[0];
+    for n in nums[1..].iter() {
+        if cmp(*n, best) == Some(keep) {
+            best = *n;
+        }
+    }
+    Ok(best.into_object())
+}
+
+pub fn min(args: &[LispObject]) -> Result<LispObject, LispError> {
+    extremum("min", args, Ordering::Less)
+}
+
+pub fn max(args: &[LispObject]) -> Result<LispObject, LispError> {
+    extremum("max", args, Ordering::Greater)
+}
+
+/// Whether every argument is in the relation `op` to the next one.
+pub fn compare<F>(name: &str, args: &[LispObject], op: F) -> Result<LispObject, LispError>
+where
+    F: Fn(Ordering) -> bool,
+{
+    arity(name, args, 1, usize::MAX)?;
+    let nums = numbers(name, args)?;
+    let res = nums.windows(2).all(|w| cmp(w[0], w[1]).is_some_and(&op));
+    Ok(LispObject::bool(res))
+}
+
+/// Whether no two arguments are equal.
+pub fn not_equal(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("/=", args, 1, usize::MAX)?;
+    let nums = numbers("/=", args)?;
+    let distinct = nums.iter().enumerate().all(|(i, a)| {
+        nums[i + 1..]
+            .iter()
+            .all(|b| cmp(*a, *b) != Some(Ordering::Equal))
+    });
+    Ok(LispObject::bool(distinct))
+}
+
+/// Applies a function of floats to the single argument.
+fn float_fn(name: &str, args: &[LispObject], f: fn(f64) -> f64) -> Result<LispObject, LispError> {
+    arity(name, args, 1, 1)?;
+    Ok(LispObject::number(f(numbers(name, args)?[0].to_f64())))
+}
+
+/// Raises the base to the power. An integer raised to a non-negative integer
+/// stays an integer.
+pub fn expt(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("expt", args, 2, 2)?;
+    let nums = numbers("expt", args)?;
+    match (nums[0], nums[1]) {
+        (Num::Int(base), Num::Int(power)) if power >= 0 => u32::try_from(power)
+            .ok()
+            .and_then(|power| base.checked_pow(power))
+            .map(LispObject::integer)
+            .ok_or_else(|| overflow("expt")),
+        (base, power) => Ok(LispObject::number(base.to_f64().powf(power.to_f64()))),
+    }
+}
+
+/// The natural logarithm, or the logarithm to the base of the second
+/// argument.
+pub fn log(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("log", args, 1, 2)?;
+    let nums = numbers("log", args)?;
+    let res = match nums.get(1) {
+        Some(base) => nums[0].to_f64().ln() / base.to_f64().ln(),
+        None => nums[0].to_f64().ln(),
+    };
+    Ok(LispObject::number(res))
+}
+
+/// The arc tangent of `y`, or of `y / x` in the quadrant of the point.
+pub fn atan(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("atan", args, 1, 2)?;
+    let nums = numbers("atan", args)?;
+    let res = match nums.get(1) {
+        Some(x) => nums[0].to_f64().atan2(x.to_f64()),
+        None => nums[0].to_f64().atan(),
     };
+    Ok(LispObject::number(res))
+}
+
+/// Divides the first argument by the optional second one and rounds the
+/// result to an integer. `int` rounds the quotient of two integers exactly,
+/// `float` rounds a float.
+fn round_with(
+    name: &str,
+    args: &[LispObject],
+    int: fn(i128, i128) -> i128,
+    float: fn(f64) -> f64,
+) -> Result<LispObject, LispError> {
+    arity(name, args, 1, 2)?;
+    let nums = numbers(name, args)?;
+    let divisor = nums.get(1).copied().unwrap_or(Num::Int(1));
+    if divisor.is_zero() {
+        return Err(LispError::division_by_zero(args[1].clone()));
+    }
+    let res = match (nums[0], divisor) {
+        (Num::Int(n), Num::Int(d)) => int(n as i128, d as i128),
+        (n, d) => {
+            let res = float(n.to_f64() / d.to_f64());
+            if !res.is_finite() {
+                return Err(LispError::type_error(
+                    format!("{} can not turn this into an integer", name),
+                    LispObject::number(res),
+                ));
+            }
+            res as i128
+        }
+    };
+    i64::try_from(res)
+        .map(LispObject::integer)
+        .map_err(|_| overflow(name))
+}
+
+fn floor_div(n: i128, d: i128) -> i128 {
+    n.div_euclid(d) - if d < 0 && n.rem_euclid(d) != 0 { 1 } else { 0 }
+}
+
+fn ceiling_div(n: i128, d: i128) -> i128 {
+    -floor_div(-n, d)
+}
 
-    if let (LispType::Number(n1), LispType::Number(n2)) = (first.get_type(), second.get_type()) {
-        Ok(LispObject::new_with(LispType::Number(n1 + n2), false))
+/// Rounds to the nearest integer and to the even one if both are as near.
+fn round_div(n: i128, d: i128) -> i128 {
+    let q = floor_div(n, d);
+    let r = (n - q * d).abs() * 2;
+    if r > d.abs() || (r == d.abs() && q % 2 != 0) {
+        q + 1
     } else {
-        Err("Arguments are of false types.")
+        q
+    }
+}
+
+fn gcd_of(a: i64, b: i64) -> u64 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The greatest common divisor of the arguments, 0 without arguments.
+pub fn gcd(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut res = 0;
+    for n in integers("gcd", args)? {
+        res = i64::try_from(gcd_of(res, n)).map_err(|_| overflow("gcd"))?;
+    }
+    Ok(LispObject::integer(res))
+}
+
+/// The least common multiple of the arguments, 1 without arguments.
+pub fn lcm(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut res: i64 = 1;
+    for n in integers("lcm", args)? {
+        if n == 0 {
+            return Ok(LispObject::integer(0));
+        }
+        let gcd = gcd_of(res, n) as i64;
+        res = (res / gcd)
+            .checked_mul(n)
+            .and_then(i64::checked_abs)
+            .ok_or_else(|| overflow("lcm"))?;
+    }
+    Ok(LispObject::integer(res))
+}
+
+/// The radix in the optional argument at `index`, 10 if it is missing.
+fn radix(name: &str, args: &[LispObject], index: usize) -> Result<u32, LispError> {
+    match args.get(index) {
+        None => Ok(10),
+        Some(arg) => match arg.ltype() {
+            LispType::Integer(r) if (2..=36).contains(r) => Ok(*r as u32),
+            _ => Err(LispError::type_error(
+                format!(
+                    "Argument {} of {} is not a radix from 2 to 36",
+                    index + 1,
+                    name
+                ),
+                arg.clone(),
+            )),
+        },
+    }
+}
+
+/// Writes an integer in the radix, without a prefix.
+pub fn to_radix(n: i64, radix: u32) -> String {
+    let mut digits = vec![];
+    let mut rest = n.unsigned_abs();
+    loop {
+        digits.push(std::char::from_digit((rest % radix as u64) as u32, radix).unwrap());
+        rest /= radix as u64;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+/// Writes a number as a string. Only integers can be written in a radix
+/// other than 10.
+pub fn number_to_string(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("number->string", args, 1, 2)?;
+    let n = numbers("number->string", args)?[0];
+    let radix = radix("number->string", args, 1)?;
+    match n {
+        Num::Int(n) => Ok(LispObject::string(to_radix(n, radix))),
+        Num::Float(n) if radix == 10 => Ok(LispObject::string(format_float(n))),
+        Num::Float(_) => Err(LispError::type_error(
+            "Argument 1 of number->string is not an integer",
+            args[0].clone(),
+        )),
     }
 }
 
-pub fn print(args: &[LispObject]) -> Result<LispObject, &'static str> {
-    let msg = match args.get(0) {
-        Some(n) => n,
-        None => return Err("Not enough arguments."),
+/// Reads a number from a string, or returns nil if it is not one.
+pub fn string_to_number(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string->number", args, 1, 2)?;
+    let s = match args[0].ltype() {
+        LispType::String(s) => s,
+        _ => {
+            return Err(LispError::type_error(
+                "Argument 1 of string->number is not a string",
+                args[0].clone(),
+            ))
+        }
     };
+    let radix = radix("string->number", args, 1)?;
+    if let Ok(n) = i64::from_str_radix(s, radix) {
+        return Ok(LispObject::integer(n));
+    }
+    let decimal = s.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    match s.parse::<f64>() {
+        Ok(n) if radix == 10 && decimal => Ok(LispObject::number(n)),
+        _ => Ok(LispObject::nil()),
+    }
+}
 
-    println!("{}", msg.get_string());
+pub fn zerop(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("zerop", args, 1, 1)?;
+    Ok(LispObject::bool(numbers("zerop", args)?[0].is_zero()))
+}
 
-    Ok(LispObject::nil())
+pub fn evenp(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("evenp", args, 1, 1)?;
+    Ok(LispObject::bool(integers("evenp", args)?[0] % 2 == 0))
+}
+
+pub fn nanp(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("nanp", args, 1, 1)?;
+    let nan = matches!(numbers("nanp", args)?[0], Num::Float(n) if n.is_nan());
+    Ok(LispObject::bool(nan))
+}
+
+/// Combines the bits of all arguments, starting with `identity`.
+fn bitwise(
+    name: &str,
+    args: &[LispObject],
+    identity: i64,
+    op: fn(i64, i64) -> i64,
+) -> Result<LispObject, LispError> {
+    let res = integers(name, args)?.into_iter().fold(identity, op);
+    Ok(LispObject::integer(res))
+}
+
+pub fn lognot(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("lognot", args, 1, 1)?;
+    Ok(LispObject::integer(!integers("lognot", args)?[0]))
+}
+
+/// Shifts the first argument left by the second, or right if it is
+/// negative. Shifting right keeps the sign.
+pub fn ash(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("ash", args, 2, 2)?;
+    let nums = integers("ash", args)?;
+    let (n, count) = (nums[0], nums[1]);
+    let res = if count >= 0 {
+        let shifted = u32::try_from(count)
+            .ok()
+            .and_then(|count| n.checked_shl(count))
+            .filter(|shifted| shifted >> count == n);
+        match shifted {
+            Some(shifted) => shifted,
+            None if n == 0 => 0,
+            None => return Err(overflow("ash")),
+        }
+    } else {
+        n >> count.unsigned_abs().min(63)
+    };
+    Ok(LispObject::integer(res))
+}
+
+/// The number of one bits of a positive integer, or of zero bits of a
+/// negative one.
+pub fn logcount(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("logcount", args, 1, 1)?;
+    let n = integers("logcount", args)?[0];
+    let bits = if n < 0 { !n } else { n };
+    Ok(LispObject::integer(bits.count_ones() as i64))
+}
+
+/// The number of bits needed to write the integer in two's complement,
+/// without the sign bit.
+pub fn integer_length(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("integer-length", args, 1, 1)?;
+    let n = integers("integer-length", args)?[0];
+    let bits = if n < 0 { !n } else { n };
+    Ok(LispObject::integer(64 - bits.leading_zeros() as i64))
+}
+
+/// Writes an integer in a radix with the prefix lisp uses for it: `#b`,
+/// `#o` or `#x`, and `#Nr` for other radixes.
+pub fn format_radix(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("format-radix", args, 2, 2)?;
+    let n = integers("format-radix", &args[..1])?[0];
+    let radix = radix("format-radix", args, 1)?;
+    let prefix = match radix {
+        2 => "#b".to_string(),
+        8 => "#o".to_string(),
+        16 => "#x".to_string(),
+        _ => format!("#{}r", radix),
+    };
+    Ok(LispObject::string(prefix + &to_radix(n, radix)))
+}
+
+fn get_condition(args: &[LispObject]) -> Result<Rc<LispError>, LispError> {
+    match args.first().map(|c| c.ltype()) {
+        Some(LispType::Condition(c)) => Ok(c.clone()),
+        Some(_) => Err(LispError::type_error(
+            "Expected a condition",
+            args[0].clone(),
+        )),
+        None => Err(LispError::not_enough_arguments()),
+    }
+}
+
+pub fn condition_type(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let condition = get_condition(args)?;
+    Ok(LispObject::symbol(condition.err_type()).move_quoted())
+}
+
+pub fn condition_objects(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let condition = get_condition(args)?;
+    if condition.objects().is_empty() {
+        return Ok(LispObject::nil());
+    }
+    list(condition.objects())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::{call, check};
 
     #[test]
     fn test_add() {
@@ -59,5 +1092,353 @@ mod tests {
                 .get_type(),
             LispType::Number(55.)
         );
+        let err = add(&[LispObject::number(1.), LispObject::symbol("a")]).unwrap_err();
+        assert_eq!(err.err_type(), &ErrorType::TypeError);
+        assert_eq!(err.objects(), &[LispObject::symbol("a")]);
+    }
+
+    /// Calls a builtin with the objects read from `args` as arguments.
+    /// Checks the printed result of every `(name args expected)` case.
+    #[test]
+    fn test_arithmetic() {
+        check(&[
+            ("+", "", "0"),
+            ("+", "1 2 3", "6"),
+            ("+", "1 2.5", "3.5"),
+            ("-", "5", "-5"),
+            ("-", "10 1 2", "7"),
+            ("-", "1.5", "-1.5"),
+            ("*", "", "1"),
+            ("*", "2 3 4", "24"),
+            ("*", "2 0.5", "1.0"),
+            ("/", "4", "0.25"),
+            ("/", "12 2 3", "2"),
+            ("/", "7 2", "3.5"),
+            ("/", "6.0 3", "2.0"),
+            ("mod", "7 3", "1"),
+            ("mod", "-7 3", "2"),
+            ("mod", "7 -3", "-2"),
+            ("mod", "7.5 2", "1.5"),
+            ("rem", "7 3", "1"),
+            ("rem", "-7 3", "-1"),
+            ("quotient", "7 2", "3"),
+            ("quotient", "-7 2", "-3"),
+            ("abs", "-2.5", "2.5"),
+            ("abs", "-3", "3"),
+            ("min", "3 1 2", "1"),
+            ("min", "3 1.5 2", "1.5"),
+            ("max", "3 1 2", "3"),
+        ]);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        check(&[
+            ("=", "1", "t"),
+            ("=", "1 1 1", "t"),
+            ("=", "1 1.0", "t"),
+            ("=", "1 1 2", "nil"),
+            ("<", "1 2 3", "t"),
+            ("<", "1 3 2", "nil"),
+            ("<", "1 1.5", "t"),
+            (">", "3 2 1", "t"),
+            ("<=", "1 1 2", "t"),
+            (">=", "2 2 3", "nil"),
+            ("/=", "1 2 3", "t"),
+            ("/=", "1 2 1", "nil"),
+            ("=", "9007199254740993 9007199254740992", "nil"),
+        ]);
+    }
+
+    #[test]
+    fn test_math() {
+        check(&[
+            ("sqrt", "16", "4.0"),
+            ("expt", "2 10", "1024"),
+            ("expt", "2 -1", "0.5"),
+            ("expt", "4 0.5", "2.0"),
+            ("exp", "0", "1.0"),
+            ("log", "1", "0.0"),
+            ("log", "8 2", "3.0"),
+            ("sin", "0", "0.0"),
+            ("cos", "0", "1.0"),
+            ("atan", "1 0", &std::f64::consts::FRAC_PI_2.to_string()),
+            ("acos", "1", "0.0"),
+            ("floor", "2.5", "2"),
+            ("floor", "-2.5", "-3"),
+            ("floor", "7 2", "3"),
+            ("floor", "-7 2", "-4"),
+            ("ceiling", "2.1", "3"),
+            ("ceiling", "7 2", "4"),
+            ("ceiling", "-7 2", "-3"),
+            ("round", "2.5", "2"),
+            ("round", "3.5", "4"),
+            ("round", "5 2", "2"),
+            ("round", "7 2", "4"),
+            ("round", "-7 2", "-4"),
+            ("round", "8 3", "3"),
+            ("truncate", "-2.7", "-2"),
+            ("truncate", "-7 2", "-3"),
+            ("gcd", "", "0"),
+            ("gcd", "12 18 -8", "2"),
+            ("lcm", "4 6", "12"),
+            ("lcm", "4 0", "0"),
+            ("number->string", "255", "\"255\""),
+            ("number->string", "255 16", "\"ff\""),
+            ("number->string", "-5 2", "\"-101\""),
+            ("number->string", "1.5", "\"1.5\""),
+            ("string->number", "\"42\"", "42"),
+            ("string->number", "\"ff\" 16", "255"),
+            ("string->number", "\"-1.5e3\"", "-1500.0"),
+            ("string->number", "\"nope\"", "nil"),
+            ("string->number", "\"inf\"", "nil"),
+            ("zerop", "0.0", "t"),
+            ("zerop", "1", "nil"),
+            ("evenp", "-4", "t"),
+            ("evenp", "3", "nil"),
+            ("nanp", "1", "nil"),
+        ]);
+        let nan = call("sqrt", "-1").unwrap();
+        assert_eq!(
+            call_builtin("nanp", &[nan]).unwrap(),
+            LispObject::bool(true)
+        );
+    }
+
+    #[test]
+    fn test_bitwise() {
+        check(&[
+            ("logand", "", "-1"),
+            ("logand", "12 10", "8"),
+            ("logior", "12 10 1", "15"),
+            ("logxor", "12 10", "6"),
+            ("lognot", "0", "-1"),
+            ("lognot", "-6", "5"),
+            ("ash", "1 10", "1024"),
+            ("ash", "-1 3", "-8"),
+            ("ash", "1024 -3", "128"),
+            ("ash", "-5 -1", "-3"),
+            ("ash", "-5 -100", "-1"),
+            ("ash", "0 100", "0"),
+            ("logcount", "255", "8"),
+            ("logcount", "-1", "0"),
+            ("logcount", "-8", "3"),
+            ("integer-length", "0", "0"),
+            ("integer-length", "255", "8"),
+            ("integer-length", "256", "9"),
+            ("integer-length", "-1", "0"),
+            ("integer-length", "-256", "8"),
+            ("format-radix", "5 2", "\"#b101\""),
+            ("format-radix", "255 16", "\"#xff\""),
+            ("format-radix", "-8 8", "\"#o-10\""),
+            ("format-radix", "35 36", "\"#36rz\""),
+        ]);
+    }
+
+    #[test]
+    fn test_lists() {
+        check(&[
+            ("cons", "1 (2 3)", "'(1 2 3)"),
+            ("cons", "1 nil", "'(1)"),
+            ("car", "(1 2 3)", "1"),
+            ("car", "nil", "nil"),
+            ("cdr", "(1 2 3)", "'(2 3)"),
+            ("cdr", "(1)", "nil"),
+            ("cdr", "nil", "nil"),
+            ("cadr", "(1 2 3)", "2"),
+            ("cddr", "(1 2 3)", "'(3)"),
+            ("caar", "((1 2) 3)", "1"),
+            ("caddr", "(1 2 3)", "3"),
+            ("cdddr", "(1 2 3)", "nil"),
+            ("cadr", "nil", "nil"),
+            ("nth", "1 (a b c)", "b"),
+            ("nth", "5 (a b c)", "nil"),
+            ("length", "nil", "0"),
+            ("length", "(1 2 3)", "3"),
+            ("append", "", "nil"),
+            ("append", "(1 2) nil (3) (4 5)", "'(1 2 3 4 5)"),
+            ("append", "nil nil", "nil"),
+            ("reverse", "(1 2 3)", "'(3 2 1)"),
+            ("reverse", "nil", "nil"),
+            ("last", "(1 2 3)", "'(3)"),
+            ("last", "(1 2 3) 2", "'(2 3)"),
+            ("last", "(1 2 3) 0", "nil"),
+            ("last", "nil", "nil"),
+            ("butlast", "(1 2 3)", "'(1 2)"),
+            ("butlast", "(1 2 3) 5", "nil"),
+            ("member", "2 (1 2 3)", "'(2 3)"),
+            ("member", "(1) (a (1) b)", "'((1) b)"),
+            ("member", "4 (1 2 3)", "nil"),
+            ("member", "1 nil", "nil"),
+            ("assoc", "b ((a 1) nil (b 2))", "(b 2)"),
+            ("assoc", "c ((a 1))", "nil"),
+            ("assoc", "a nil", "nil"),
+            ("rassoc", "(2) ((a 1) (b 2))", "(b 2)"),
+            ("remove", "1 (1 2 1 3)", "'(2 3)"),
+            ("remove", "1 nil", "nil"),
+            ("delete-duplicates", "(a b a c b)", "'(a c b)"),
+            ("delete-duplicates", "nil", "nil"),
+            ("copy-list", "(1 2)", "'(1 2)"),
+            ("copy-list", "nil", "nil"),
+            ("null", "nil", "t"),
+            ("null", "()", "t"),
+            ("null", "(1)", "nil"),
+            ("null", "0", "nil"),
+            ("consp", "(1)", "t"),
+            ("consp", "nil", "nil"),
+            ("consp", "a", "nil"),
+        ]);
+    }
+
+    /// `cons` and `cdr` share the list they get instead of copying it, so
+    /// building and walking a long list takes linear time.
+    #[test]
+    fn test_cons_car_cdr_scale() {
+        let n = 200_000;
+        let mut built = LispObject::nil();
+        for i in 0..n {
+            built = cons(&[LispObject::integer(i), built]).unwrap();
+        }
+        let numbers = (0..n).map(LispObject::integer).collect::<Vec<_>>();
+        for list in [built, list(&numbers).unwrap()] {
+            let mut sum = 0;
+            let mut rest = list.clone();
+            while rest.is_true() {
+                if let LispType::Integer(i) = car(std::slice::from_ref(&rest)).unwrap().ltype() {
+                    sum += i;
+                }
+                let next = cdr(std::slice::from_ref(&rest)).unwrap();
+                let shared = match (rest.ltype(), next.ltype()) {
+                    (LispType::Cons(a), LispType::Cons(b)) => {
+                        matches!(a.1.ltype(), LispType::Cons(a) if Rc::ptr_eq(a, b))
+                    }
+                    (LispType::Cons(_), _) => !next.is_true(),
+                    (LispType::List(a), LispType::List(b)) => Rc::ptr_eq(a.shared(), b.shared()),
+                    (LispType::List(a), _) => a.len() == 1,
+                    _ => false,
+                };
+                assert!(shared, "cdr copied the list");
+                rest = next;
+            }
+            assert_eq!(sum, n * (n - 1) / 2);
+        }
+    }
+
+    #[test]
+    fn test_cons_pairs() {
+        let sym = LispObject::symbol;
+        let pair = LispObject::cons(sym("a"), sym("b"));
+        let chain = LispObject::cons(sym("x"), pair.clone());
+        let list = |args: &[LispObject]| list(args).unwrap();
+        let call = |name, args: &[LispObject]| call_builtin(name, args).unwrap();
+        let call1 = |name, arg: &LispObject| call(name, std::slice::from_ref(arg));
+
+        assert_eq!(call("cons", &[sym("a"), sym("b")]), pair);
+        assert_eq!(call1("car", &pair), sym("a"));
+        assert_eq!(call1("cdr", &pair), sym("b"));
+        assert_eq!(call1("cdr", &chain), pair);
+        assert_eq!(call1("cddr", &chain), sym("b"));
+        assert_eq!(
+            call("nth", &[LispObject::integer(1), chain.clone()]),
+            sym("a")
+        );
+        assert_eq!(call1("last", &chain), pair);
+        assert_eq!(call1("butlast", &chain), list(&[sym("x")]));
+        assert_eq!(call("member", &[sym("a"), chain.clone()]), pair);
+        assert_eq!(call1("copy-list", &chain), chain);
+        assert_eq!(call1("consp", &pair), LispObject::bool(true));
+        assert_eq!(call1("null", &pair), LispObject::bool(false));
+        // The last argument of append becomes the end of the result.
+        assert_eq!(call("append", &[list(&[sym("x")]), pair.clone()]), chain);
+        assert_eq!(call("append", &[list(&[sym("a")]), sym("b")]), pair);
+        assert_eq!(call("append", &[LispObject::nil(), sym("b")]), sym("b"));
+        // Association lists are usually made of pairs.
+        let alist = list(&[pair.clone(), LispObject::cons(sym("c"), sym("d"))]);
+        assert_eq!(call("assoc", &[sym("a"), alist.clone()]), pair);
+        assert_eq!(
+            call("rassoc", &[sym("d"), alist]),
+            LispObject::cons(sym("c"), sym("d"))
+        );
+
+        let errors = [
+            (
+                "length",
+                chain.clone(),
+                "Argument 1 of length is not a proper list",
+            ),
+            (
+                "reverse",
+                pair.clone(),
+                "Argument 1 of reverse is not a proper list",
+            ),
+            ("car", sym("a"), "Argument 1 of car is not a list"),
+            (
+                "cadr",
+                LispObject::integer(1),
+                "Argument 1 of cadr is not a list",
+            ),
+        ];
+        for (name, arg, message) in errors {
+            let err = call_builtin(name, &[arg]).unwrap_err();
+            assert_eq!(err.err_type(), &ErrorType::TypeError);
+            assert_eq!(err.message(), message);
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let cases = [
+            ("+", "1 a", ErrorType::TypeError, "Argument 2 of +"),
+            ("<", "a 1", ErrorType::TypeError, "Argument 1 of <"),
+            ("-", "", ErrorType::WrongNumberOfArguments, "- got 0"),
+            ("mod", "1", ErrorType::WrongNumberOfArguments, "mod got 1"),
+            ("abs", "1 1", ErrorType::WrongNumberOfArguments, "abs got 2"),
+            ("/", "1 1 0", ErrorType::DivisionByZero, ""),
+            ("/", "0.0", ErrorType::DivisionByZero, ""),
+            ("rem", "1 0", ErrorType::DivisionByZero, ""),
+            ("floor", "1 0", ErrorType::DivisionByZero, ""),
+            (
+                "*",
+                "9223372036854775807 2",
+                ErrorType::ArithmeticError,
+                "Integer overflow",
+            ),
+            (
+                "expt",
+                "2 64",
+                ErrorType::ArithmeticError,
+                "Integer overflow",
+            ),
+            (
+                "floor",
+                "1e300",
+                ErrorType::ArithmeticError,
+                "Integer overflow",
+            ),
+            (
+                "gcd",
+                "4 2.0",
+                ErrorType::TypeError,
+                "Argument 2 of gcd is not an integer",
+            ),
+            ("evenp", "2.0", ErrorType::TypeError, "Argument 1 of evenp"),
+            (
+                "number->string",
+                "1 37",
+                ErrorType::TypeError,
+                "Argument 2 of",
+            ),
+            (
+                "number->string",
+                "1.5 2",
+                ErrorType::TypeError,
+                "Argument 1 of",
+            ),
+        ];
+        for (name, args, err_type, message) in cases {
+            let err = call(name, args).unwrap_err();
+            assert_eq!(err.err_type(), &err_type, "({} {})", name, args);
+            assert!(err.message().starts_with(message), "{}", err.message());
+        }
     }
 }
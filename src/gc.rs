@@ -0,0 +1,205 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use crate::error::LispError;
+use crate::lispobject::{Function, LispObject, LispType};
+use crate::objectmanager::{Cell, Env, Frame};
+
+/// Counters of the garbage collector.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    /// Frames that were only kept alive by cycles.
+    pub freed: usize,
+    /// Frames that are alive.
+    pub live: usize,
+}
+
+/// An object on the heap that can reference other objects.
+enum Node {
+    Frame(Env),
+    Cell(Cell),
+    Function(Rc<Function>),
+    List(Rc<Vec<LispObject>>),
+    Cons(Rc<(LispObject, LispObject)>),
+    Condition(Rc<LispError>),
+}
+
+impl Node {
+    fn of(obj: &LispObject) -> Option<Node> {
+        match obj.ltype() {
+            LispType::Function(f) => Some(Node::Function(f.clone())),
+            LispType::List(l) => Some(Node::List(l.shared().clone())),
+            LispType::Vector(l) => Some(Node::List(l.clone())),
+            LispType::Cons(c) => Some(Node::Cons(c.clone())),
+            LispType::Condition(c) => Some(Node::Condition(c.clone())),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> usize {
+        match self {
+            Node::Frame(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Cell(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Function(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::List(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Cons(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Condition(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Frame(rc) => Rc::strong_count(rc),
+            Node::Cell(rc) => Rc::strong_count(rc),
+            Node::Function(rc) => Rc::strong_count(rc),
+            Node::List(rc) => Rc::strong_count(rc),
+            Node::Cons(rc) => Rc::strong_count(rc),
+            Node::Condition(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    /// The objects this one holds a reference to, once per reference. `None`
+    /// if the object is borrowed and can not be inspected.
+    fn children(&self) -> Option<Vec<Node>> {
+        let mut children = vec![];
+        match self {
+            Node::Frame(env) => {
+                let frame = env.try_borrow().ok()?;
+                children.extend(frame.parent().map(Node::Frame));
+                children.extend(frame.cells().into_iter().map(Node::Cell));
+            }
+            Node::Cell(cell) => children.extend(Node::of(&*cell.try_borrow().ok()?)),
+            Node::Function(function) => {
+                if let Function::Lambda(lambda) | Function::Macro(lambda) = &**function {
+                    children.push(Node::Frame(lambda.env.clone()));
+                    children.extend(lambda.body.iter().filter_map(Node::of));
+                }
+            }
+            Node::List(list) => children.extend(list.iter().filter_map(Node::of)),
+            Node::Cons(pair) => {
+                children.extend([&pair.0, &pair.1].into_iter().filter_map(Node::of))
+            }
+            Node::Condition(condition) => {
+                let objects = condition.objects().iter();
+                let args = condition.backtrace().iter().flat_map(|f| f.args.iter());
+                children.extend(objects.chain(args).filter_map(Node::of));
+            }
+        }
+        Some(children)
+    }
+}
+
+/// Frees the frames in `heap` that are only reachable through cycles and
+/// returns how many were freed.
+///
+/// Every reference cycle passes through a frame, because only the variables
+/// of a frame can be changed after they were created. The collector counts
+/// the references between the objects reachable from the frames. An object
+/// with more references than that is referenced from outside: by the frame
+/// stack of the manager, by the evaluation stack or by Rust code. Those
+/// objects are the roots; frames that can not be reached from them are
+/// cleared, which breaks their cycles.
+pub fn collect(heap: &[Weak<RefCell<Frame>>]) -> usize {
+    let mut nodes: HashMap<usize, (Node, Vec<usize>)> = HashMap::new();
+    let mut pending = heap
+        .iter()
+        .filter_map(|frame| frame.upgrade().map(Node::Frame))
+        .collect::<Vec<Node>>();
+    let mut roots = vec![];
+
+    while let Some(node) = pending.pop() {
+        let id = node.id();
+        if nodes.contains_key(&id) {
+            continue;
+        }
+        let children = match node.children() {
+            Some(children) => children,
+            None => {
+                // A borrowed object is in use, so it is kept.
+                roots.push(id);
+                vec![]
+            }
+        };
+        let ids = children.iter().map(|c| c.id()).collect();
+        pending.extend(children);
+        nodes.insert(id, (node, ids));
+    }
+
+    // The references from outside of the heap are what is left after
+    // subtracting the internal ones. The map holds one reference itself.
+    let mut external = nodes
+        .iter()
+        .map(|(id, (node, _))| (*id, node.strong_count() as isize - 1))
+        .collect::<HashMap<usize, isize>>();
+    for (_, children) in nodes.values() {
+        for child in children {
+            if let Some(count) = external.get_mut(child) {
+                *count -= 1;
+            }
+        }
+    }
+    roots.extend(external.iter().filter(|(_, c)| **c > 0).map(|(id, _)| *id));
+
+    let mut reachable = HashSet::new();
+    while let Some(id) = roots.pop() {
+        if reachable.insert(id) {
+            roots.extend(nodes[&id].1.iter().copied());
+        }
+    }
+
+    let mut freed = 0;
+    for (id, (node, _)) in nodes.iter() {
+        if let Node::Frame(env) = node {
+            if !reachable.contains(id) {
+                let contents = std::mem::take(&mut *env.borrow_mut());
+                drop(contents);
+                freed += 1;
+            }
+        }
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objectmanager::Manager;
+    use crate::testutil::eval_str;
+
+    #[test]
+    fn test_gc_frees_cycles() {
+        let mut manager = Manager::default();
+        eval_str(
+            "(defun make-cycle () (set 'self (lambda () self)) nil)",
+            &mut manager,
+        );
+        let live = manager.gc_stats().live;
+        eval_str("(make-cycle) (make-cycle) (make-cycle)", &mut manager);
+        // Each call leaves its call frame and the frame of its block.
+        assert_eq!(manager.gc_stats().live, live + 6);
+        assert_eq!(manager.collect_garbage(), 6);
+        assert_eq!(manager.gc_stats().live, live);
+        assert_eq!(manager.collect_garbage(), 0);
+        assert_eq!(manager.gc_stats().collections, 2);
+    }
+
+    #[test]
+    fn test_gc_keeps_live_frames() {
+        let mut manager = Manager::default();
+        let res = eval_str(
+            "(set 'counter nil)
+             (defun make-counter (n) (lambda () (set 'n (add n 1)) n))
+             (set 'counter (make-counter 0))
+             (funcall counter)
+             (defun collect-inside (x) (gc) x)
+             (collect-inside (gc))
+             (funcall counter)",
+            &mut manager,
+        );
+        assert_eq!(res, LispObject::integer(2));
+        let res = eval_str("(gc-stats)", &mut manager);
+        assert_eq!(res.to_string(), "'(collections 2 freed 0 live 3)");
+    }
+}
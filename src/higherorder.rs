@@ -0,0 +1,464 @@
+use std::vec::IntoIter;
+
+use crate::error::LispError;
+use crate::functions::{append, arity, make_list};
+use crate::lispobject::LispObject;
+use crate::sequence::{sequence, Kind};
+
+/// The builtins that call a function they are given. They take any
+/// sequence, see `sequence`.
+pub const HIGHER_ORDER: &[&str] = &[
+    "map",
+    "mapcar",
+    "mapc",
+    "mapcan",
+    "filter",
+    "remove-if",
+    "reduce",
+    "every",
+    "some",
+    "find",
+    "find-if",
+    "position",
+    "count-if",
+    "sort",
+];
+
+/// What the evaluator has to do next for an `Iteration`.
+pub enum Next {
+    /// Call the function with the arguments and pass the value to
+    /// `Iteration::next`.
+    Call(LispObject, Vec<LispObject>),
+    /// The builtin returns the value.
+    Done(LispObject),
+}
+
+/// A call of a higher-order builtin that is in progress. The evaluator runs
+/// the calls it asks for, so the functions can be lambdas that signal
+/// conditions or leave through `throw` like any other call.
+pub struct Iteration {
+    name: String,
+    func: LispObject,
+    /// The type of the sequence the builtin got and returns.
+    kind: Kind,
+    state: State,
+}
+
+enum State {
+    /// The function is called once per element, or with the elements at the
+    /// same index of several lists. `args` are the arguments of the last
+    /// call.
+    Each {
+        calls: IntoIter<Vec<LispObject>>,
+        args: Vec<LispObject>,
+        index: usize,
+        results: Vec<LispObject>,
+        /// The first list, which `mapc` returns.
+        first: LispObject,
+    },
+    /// Combines the value so far with the next element.
+    Reduce {
+        items: IntoIter<LispObject>,
+        acc: Option<LispObject>,
+    },
+    /// Computes the key of every element to sort by.
+    Keys {
+        key: LispObject,
+        items: Vec<LispObject>,
+        keys: Vec<LispObject>,
+    },
+    /// A stable bottom-up merge sort of key and element pairs. The runs of
+    /// `width` elements at `start` and after it are merged from `src` into
+    /// `dst` by comparing the keys at `i` and `j`.
+    Sort {
+        src: Vec<(LispObject, LispObject)>,
+        dst: Vec<(LispObject, LispObject)>,
+        width: usize,
+        start: usize,
+        i: usize,
+        j: usize,
+    },
+}
+
+impl Iteration {
+    /// Checks the arguments of a call to the builtin `name`.
+    pub fn new(name: &str, args: Vec<LispObject>) -> Result<Iteration, LispError> {
+        // Everything but `sort` takes the function first.
+        let first = match name {
+            "sort" => 0,
+            _ => 1,
+        };
+        arity(name, &args, 2, usize::MAX)?;
+        let (kind, items) = sequence(name, &args, first)?;
+        let (func, state) = match name {
+            "map" | "mapcar" | "mapc" | "mapcan" | "every" | "some" => {
+                let mut sequences = vec![items];
+                for i in 2..args.len() {
+                    sequences.push(sequence(name, &args, i)?.1);
+                }
+                let len = sequences.iter().map(|s| s.len()).min().unwrap();
+                let calls = (0..len)
+                    .map(|i| sequences.iter().map(|s| s[i].clone()).collect())
+                    .collect();
+                (args[0].clone(), each(calls, args[1].clone()))
+            }
+            "filter" | "remove-if" | "find-if" | "count-if" => {
+                arity(name, &args, 2, 2)?;
+                let calls = items.into_iter().map(|e| vec![e]).collect();
+                (args[0].clone(), each(calls, args[1].clone()))
+            }
+            "find" | "position" => {
+                let [test] = keywords(name, &args, 2, [":test"])?;
+                let calls = items
+                    .into_iter()
+                    .map(|e| vec![args[0].clone(), e])
+                    .collect();
+                let test = test.unwrap_or_else(|| LispObject::symbol("equal"));
+                (test, each(calls, args[1].clone()))
+            }
+            "reduce" => {
+                let [initial] = keywords(name, &args, 2, [":initial-value"])?;
+                let items = items.into_iter();
+                let state = State::Reduce {
+                    items,
+                    acc: initial,
+                };
+                (args[0].clone(), state)
+            }
+            "sort" => {
+                let [key] = keywords(name, &args, 2, [":key"])?;
+                let state = match key {
+                    Some(key) => State::Keys {
+                        key: key.move_unquoted(),
+                        items,
+                        keys: vec![],
+                    },
+                    None => sort_state(items.iter().cloned().zip(items.clone()).collect()),
+                };
+                (args[1].clone(), state)
+            }
+            _ => unreachable!("{} is not a higher-order builtin", name),
+        };
+        Ok(Iteration {
+            name: name.to_string(),
+            func: func.move_unquoted(),
+            kind,
+            state,
+        })
+    }
+
+    /// Takes the value of the last call the iteration asked for, or `None`
+    /// when it starts, and returns what to do next.
+    pub fn next(&mut self, val: Option<LispObject>) -> Result<Next, LispError> {
+        match &mut self.state {
+            State::Each {
+                calls,
+                args,
+                index,
+                results,
+                first,
+            } => {
+                if let Some(val) = val {
+                    let element = args.last().unwrap().clone();
+                    match self.name.as_str() {
+                        "map" | "mapcar" | "mapcan" => results.push(val),
+                        "filter" if val.is_true() => results.push(element),
+                        "remove-if" if !val.is_true() => results.push(element),
+                        "count-if" if val.is_true() => results.push(element),
+                        "every" if !val.is_true() => return Ok(Next::Done(LispObject::nil())),
+                        "some" if val.is_true() => return Ok(Next::Done(val)),
+                        "find" | "find-if" if val.is_true() => return Ok(Next::Done(element)),
+                        "position" if val.is_true() => {
+                            return Ok(Next::Done(LispObject::integer(*index as i64)))
+                        }
+                        _ => {}
+                    }
+                    *index += 1;
+                }
+                if let Some(next) = calls.next() {
+                    *args = next.clone();
+                    return Ok(Next::Call(self.func.clone(), next));
+                }
+                let results = std::mem::take(results);
+                let res = match self.name.as_str() {
+                    "mapcar" => make_list(results, LispObject::nil()),
+                    "map" | "filter" | "remove-if" => self.kind.build(&self.name, results)?,
+                    "mapcan" => append(&results)?,
+                    "mapc" => first.clone(),
+                    "every" => LispObject::bool(true),
+                    "count-if" => LispObject::integer(results.len() as i64),
+                    _ => LispObject::nil(),
+                };
+                Ok(Next::Done(res))
+            }
+            State::Reduce { items, acc } => {
+                if val.is_some() {
+                    *acc = val;
+                }
+                loop {
+                    return match (acc.take(), items.next()) {
+                        (Some(acc), Some(item)) => {
+                            Ok(Next::Call(self.func.clone(), vec![acc, item]))
+                        }
+                        (Some(acc), None) => Ok(Next::Done(acc)),
+                        // Without an initial value the first element is the
+                        // start, and the function is called without
+                        // arguments for an empty list.
+                        (None, Some(item)) => {
+                            *acc = Some(item);
+                            continue;
+                        }
+                        (None, None) => Ok(Next::Call(self.func.clone(), vec![])),
+                    };
+                }
+            }
+            State::Keys { key, items, keys } => {
+                if let Some(val) = val {
+                    keys.push(val);
+                }
+                if let Some(item) = items.get(keys.len()) {
+                    return Ok(Next::Call(key.clone(), vec![item.clone()]));
+                }
+                let src = std::mem::take(keys).into_iter().zip(std::mem::take(items));
+                self.state = sort_state(src.collect());
+                self.next(None)
+            }
+            State::Sort {
+                src,
+                dst,
+                width,
+                start,
+                i,
+                j,
+            } => {
+                let len = src.len();
+                if let Some(val) = val {
+                    // The predicate was called with the element at `j` first,
+                    // which only goes first if it is strictly less, so equal
+                    // elements keep their order.
+                    if val.is_true() {
+                        dst.push(src[*j].clone());
+                        *j += 1;
+                    } else {
+                        dst.push(src[*i].clone());
+                        *i += 1;
+                    }
+                }
+                while *width < len {
+                    let mid = (*start + *width).min(len);
+                    let end = (*start + 2 * *width).min(len);
+                    if *i < mid && *j < end {
+                        let args = vec![src[*j].0.clone(), src[*i].0.clone()];
+                        return Ok(Next::Call(self.func.clone(), args));
+                    }
+                    dst.extend_from_slice(&src[*i..mid]);
+                    dst.extend_from_slice(&src[*j..end]);
+                    *start = end;
+                    if end == len {
+                        std::mem::swap(src, dst);
+                        dst.clear();
+                        *width *= 2;
+                        *start = 0;
+                    }
+                    *i = *start;
+                    *j = (*start + *width).min(len);
+                }
+                let sorted = std::mem::take(src).into_iter().map(|(_, e)| e).collect();
+                Ok(Next::Done(self.kind.build(&self.name, sorted)?))
+            }
+        }
+    }
+}
+
+fn each(calls: Vec<Vec<LispObject>>, first: LispObject) -> State {
+    State::Each {
+        calls: calls.into_iter(),
+        args: vec![],
+        index: 0,
+        results: vec![],
+        first,
+    }
+}
+
+/// The state of `sort` once the keys are known.
+fn sort_state(src: Vec<(LispObject, LispObject)>) -> State {
+    State::Sort {
+        dst: Vec::with_capacity(src.len()),
+        j: 1.min(src.len()),
+        src,
+        width: 1,
+        start: 0,
+        i: 0,
+    }
+}
+
+/// The values of the keyword arguments `names` that may follow `required`
+/// arguments.
+fn keywords<const N: usize>(
+    name: &str,
+    args: &[LispObject],
+    required: usize,
+    names: [&str; N],
+) -> Result<[Option<LispObject>; N], LispError> {
+    arity(name, args, required, usize::MAX)?;
+    let mut values = [(); N].map(|_| None);
+    for pair in args[required..].chunks(2) {
+        let index = pair[0]
+            .as_symbol()
+            .and_then(|keyword| names.iter().position(|n| *n == keyword));
+        match (index, pair.get(1)) {
+            (Some(index), Some(value)) => values[index] = Some(value.clone()),
+            (Some(_), None) => {
+                return Err(LispError::type_error(
+                    format!("Keyword argument of {} without a value", name),
+                    pair[0].clone(),
+                ))
+            }
+            (None, _) => {
+                return Err(LispError::type_error(
+                    format!("{} does not take this keyword argument", name),
+                    pair[0].clone(),
+                ))
+            }
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorType;
+    use crate::evaluator::eval;
+    use crate::objectmanager::{Engine, Manager};
+    use crate::testutil::eval_str;
+
+    /// Evaluates the forms with both engines and checks that the last one
+    /// prints as `expected`.
+    fn check(code: &str, expected: &str) {
+        for engine in [Engine::Tree, Engine::Vm] {
+            let mut manager = Manager::default();
+            manager.set_engine(engine);
+            let res = eval_str(code, &mut manager).to_string();
+            assert_eq!(res, expected, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_mapping() {
+        check("(mapcar 'car (quote ((1 2) (3 4))))", "'(1 3)");
+        check(
+            "(defun sq (x) (* x x)) (mapcar 'sq (quote (1 2 3)))",
+            "'(1 4 9)",
+        );
+        check(
+            "(mapcar (lambda (a b) (+ a b)) (quote (1 2 3)) (quote (10 20)))",
+            "'(11 22)",
+        );
+        check("(mapcar 'car nil)", "nil");
+        check(
+            "(set 'n 0) (mapc (lambda (x) (set 'n (+ n x))) (quote (1 2)))",
+            "'(1 2)",
+        );
+        check(
+            "(set 'n 0) (mapc (lambda (x) (set 'n (+ n x))) (quote (1 2))) n",
+            "3",
+        );
+        check(
+            "(mapcan (lambda (x) (list x x)) (quote (1 2)))",
+            "'(1 1 2 2)",
+        );
+        check("(filter 'evenp (quote (1 2 3 4)))", "'(2 4)");
+        check("(remove-if (function evenp) (quote (1 2 3 4)))", "'(1 3)");
+        check("(filter 'evenp nil)", "nil");
+    }
+
+    #[test]
+    fn test_reduce_and_search() {
+        check("(reduce '+ (quote (1 2 3 4)))", "10");
+        check(
+            "(reduce 'list (quote (1 2 3)) :initial-value 0)",
+            "'('('(0 1) 2) 3)",
+        );
+        check("(reduce '+ nil)", "0");
+        check("(reduce '+ nil :initial-value 5)", "5");
+        check("(reduce '+ (quote (7)))", "7");
+        check("(every 'evenp (quote (2 4)))", "t");
+        check("(every '< (quote (1 2)) (quote (2 1)))", "nil");
+        check(
+            "(some (lambda (x) (if (evenp x) (* x 10))) (quote (1 4 6)))",
+            "40",
+        );
+        check("(some 'evenp nil)", "nil");
+        check("(find-if 'evenp (quote (1 3 4 6)))", "4");
+        check("(position (quote (b)) (quote (a (b) c)))", "1");
+        check("(position 2 (quote (1 2 3)) :test '<)", "2");
+        check("(position 4 (quote (1 2 3)))", "nil");
+        check("(count-if 'evenp (quote (1 2 4)))", "2");
+    }
+
+    #[test]
+    fn test_sort() {
+        check(
+            "(sort (quote (5 3 9 1 3 7 2 8 6 4 0)) '<)",
+            "'(0 1 2 3 3 4 5 6 7 8 9)",
+        );
+        check("(sort nil '<)", "nil");
+        check("(sort (quote (1)) '<)", "'(1)");
+        // Elements with equal keys keep their order.
+        check(
+            "(defun second (x) (cadr x))
+             (sort (quote ((b 2) (a 1) (d 2) (c 1))) (lambda (a b) (< a b)) :key 'second)",
+            "'((a 1) (c 1) (b 2) (d 2))",
+        );
+    }
+
+    #[test]
+    fn test_sequence_arguments() {
+        check("(map (lambda (x) (* x 2)) #(1 2 3))", "#(2 4 6)");
+        check("(map '+ (quote (1 2)) #(10 20 30))", "'(11 22)");
+        check("(map 'reverse \"ab\")", "\"ab\"");
+        check(
+            "(filter (lambda (c) (equal c \"a\")) \"banana\")",
+            "\"aaa\"",
+        );
+        check("(remove-if 'evenp #(1 2 3))", "#(1 3)");
+        check("(find \"n\" \"banana\")", "\"n\"");
+        check("(find 3 #(1 2))", "nil");
+        check("(find 2 (quote (1 2 3)) :test '<)", "3");
+        check("(position \"é\" \"café\")", "3");
+        check("(position 2 #(1 2))", "1");
+        check("(sort #(3 1 2) '<)", "#(1 2 3)");
+        check("(sort \"hello\" 'equal)", "\"hello\"");
+        check("(reduce '+ #(1 2 3))", "6");
+        check("(mapcar 'evenp #(1 2))", "'(nil t)");
+    }
+
+    #[test]
+    fn test_higher_order_errors() {
+        let cases = [
+            ("(mapcar 'car)", ErrorType::WrongNumberOfArguments),
+            ("(filter 'evenp 1)", ErrorType::TypeError),
+            ("(map (lambda (c) 1) \"ab\")", ErrorType::TypeError),
+            ("(sort (quote (1)) '< :test '>)", ErrorType::TypeError),
+            (
+                "(reduce '+ (quote (1)) :initial-value)",
+                ErrorType::TypeError,
+            ),
+            (
+                "(mapcar 'undefined (quote (1)))",
+                ErrorType::UndefinedFunction,
+            ),
+        ];
+        for (code, err_type) in cases {
+            let form = crate::ast::ast(code).unwrap().remove(0);
+            let err = eval(form, &mut Manager::default()).unwrap_err();
+            assert_eq!(err.err_type(), &err_type, "{}", code);
+        }
+        // Errors of the function are signaled from inside the iteration.
+        check(
+            "(handler-case (mapcar (lambda (x) (error 'my-error x)) (quote (1)))
+               (error (c) (condition-objects c)))",
+            "'(1)",
+        );
+    }
+}
@@ -1,7 +1,36 @@
-#[derive(PartialEq, PartialOrd, Debug, Clone)]
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::compiler::Code;
+use crate::error::{LispError, Span};
+use crate::objectmanager::Env;
+use crate::printer::Printer;
+use crate::regex::Regex;
+
+#[derive(Debug, Clone)]
 pub struct LispObject {
     ltype: LispType,
     quoted: bool,
+    /// Where the reader found the object. Objects created while evaluating
+    /// have no span.
+    span: Option<Span>,
+    /// Where a variable reference finds its binding, see `resolver`.
+    address: Option<Address>,
+}
+
+/// The lexical address of a local variable: the number of frames to walk
+/// outwards from the current one and the index of the binding in that frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Address {
+    pub depth: usize,
+    pub index: usize,
+}
+
+impl PartialEq for LispObject {
+    fn eq(&self, other: &Self) -> bool {
+        self.ltype == other.ltype && self.quoted == other.quoted
+    }
 }
 
 impl LispObject {
@@ -12,10 +41,7 @@ impl LispObject {
         } else {
             false
         };
-        Self {
-            ltype: LispType::new(token),
-            quoted,
-        }
+        Self::new_with(LispType::new(token), quoted)
     }
 
     pub fn new_list(tokens: &[String], quoted: bool) -> LispObject {
@@ -23,10 +49,7 @@ impl LispObject {
             .iter()
             .map(|e| Self::new(e))
             .collect::<Vec<LispObject>>();
-        Self {
-            ltype: LispType::List(res),
-            quoted,
-        }
+        Self::new_with(LispType::list(res), quoted)
     }
 
     pub fn is_quoted(&self) -> bool {
@@ -37,24 +60,47 @@ impl LispObject {
         self.ltype.clone()
     }
 
-    pub fn get_string(&self) -> String {
-        match self.get_type() {
-            LispType::Number(n) => n.to_string(),
-            LispType::Symbol(s) => s,
-            LispType::Cons(c) => format!("({} . {})", c.0.get_string(), c.1.get_string()),
-            LispType::List(l) => {
-                format!(
-                    "({})",
-                    l.iter()
-                        .map(|e| e.get_string())
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                )
-            }
-            LispType::Bool(b) => if b { "t" } else { "nil" }.to_string(),
+    pub fn ltype(&self) -> &LispType {
+        &self.ltype
+    }
+
+    pub fn as_list(&self) -> Option<&[LispObject]> {
+        match &self.ltype {
+            LispType::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Takes the elements out of a list. They are only copied if the list
+    /// is shared.
+    pub fn into_list(self) -> Option<Vec<LispObject>> {
+        match self.into_type() {
+            LispType::List(l) => Some(l.into_vec()),
+            _ => None,
+        }
+    }
+
+    /// Takes the type out of the object without cloning it.
+    pub fn into_type(mut self) -> LispType {
+        std::mem::replace(&mut self.ltype, LispType::Bool(false))
+    }
+
+    pub fn as_symbol(&self) -> Option<&str> {
+        match &self.ltype {
+            LispType::Symbol(s) => Some(s),
+            _ => None,
         }
     }
 
+    pub fn is_function(&self) -> bool {
+        matches!(self.ltype, LispType::Function(_))
+    }
+
+    /// The object as `princ` prints it.
+    pub fn get_string(&self) -> String {
+        Printer::PRINC.print(self)
+    }
+
     pub fn set_quoted(&mut self) {
         self.quoted = true;
     }
@@ -64,75 +110,240 @@ impl LispObject {
         self
     }
 
-    pub fn new_with(ltype: LispType, quoted: bool) -> Self {
-        Self { ltype, quoted }
+    pub fn move_unquoted(mut self) -> Self {
+        self.quoted = false;
+        self
     }
 
-    pub fn nil() -> Self {
+    pub fn new_with(ltype: LispType, quoted: bool) -> Self {
         Self {
-            ltype: LispType::Bool(false),
-            quoted: false,
+            ltype,
+            quoted,
+            span: None,
+            address: None,
         }
     }
 
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn address(&self) -> Option<Address> {
+        self.address
+    }
+
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    pub fn nil() -> Self {
+        Self::new_with(LispType::Bool(false), false)
+    }
+
     pub fn symbol<T: ToString>(name: T) -> Self {
-        Self {
-            ltype: LispType::Symbol(name.to_string()),
-            quoted: false,
-        }
+        Self::new_with(LispType::Symbol(name.to_string()), false)
+    }
+
+    pub fn integer(num: i64) -> Self {
+        Self::new_with(LispType::Integer(num), false)
     }
 
     pub fn number(num: f64) -> Self {
-        Self {
-            ltype: LispType::Number(num),
-            quoted: false,
-        }
+        Self::new_with(LispType::Number(num), false)
+    }
+
+    pub fn string<T: ToString>(s: T) -> Self {
+        Self::new_with(LispType::String(s.to_string()), false)
     }
 
     pub fn list(list: &[LispObject]) -> Self {
-        Self {
-            ltype: LispType::List(list.into()),
-            quoted: false,
-        }
+        Self::new_with(LispType::list(list.into()), false)
+    }
+
+    pub fn vector(vector: Vec<LispObject>) -> Self {
+        Self::new_with(LispType::vector(vector), false)
     }
 
     pub fn cons(key: LispObject, val: LispObject) -> Self {
-        Self {
-            ltype: LispType::new_cons((key, val)),
-            quoted: false,
-        }
+        Self::new_with(LispType::new_cons((key, val)), false)
     }
 
     pub fn bool(b: bool) -> Self {
-        Self {
-            ltype: LispType::Bool(b),
-            quoted: false,
+        Self::new_with(LispType::Bool(b), false)
+    }
+
+    pub fn function(function: Function) -> Self {
+        Self::new_with(LispType::Function(Rc::new(function)), false)
+    }
+
+    pub fn condition(err: LispError) -> Self {
+        Self::new_with(LispType::Condition(Rc::new(err)), false)
+    }
+
+    /// Whether two objects have the same structure, regardless of whether
+    /// they are quoted. Numbers are only equal to numbers of the same type.
+    /// A list equals a chain of cons pairs with the same elements.
+    pub fn equal(&self, other: &LispObject) -> bool {
+        // Nested objects are compared from a work list, recursion would
+        // overflow the stack on deep structures.
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            if let (LispType::Vector(a), LispType::Vector(b)) = (&a.ltype, &b.ltype) {
+                if a.len() != b.len() {
+                    return false;
+                }
+                pending.extend(a.iter().zip(b.iter()));
+                continue;
+            }
+            match (a.list_parts(), b.list_parts()) {
+                (Some((a, a_tail)), Some((b, b_tail))) => {
+                    if a.len() != b.len() {
+                        return false;
+                    }
+                    pending.extend(a.into_iter().zip(b));
+                    match (a_tail, b_tail) {
+                        (None, None) => {}
+                        (Some(a), Some(b)) => pending.push((a, b)),
+                        _ => return false,
+                    }
+                }
+                (None, None) if a.ltype == b.ltype => {}
+                _ => return false,
+            }
         }
+        true
+    }
+
+    /// The elements of a list or a chain of cons pairs and the object that
+    /// ends the chain if it is not nil. `None` if the object is not a list.
+    fn list_parts(&self) -> Option<(Vec<&LispObject>, Option<&LispObject>)> {
+        let mut elements = vec![];
+        let mut rest = self;
+        loop {
+            match &rest.ltype {
+                LispType::Bool(false) => return Some((elements, None)),
+                LispType::List(l) => {
+                    elements.extend(l.iter());
+                    return Some((elements, None));
+                }
+                LispType::Cons(pair) => {
+                    elements.push(&pair.0);
+                    rest = &pair.1;
+                }
+                _ if elements.is_empty() => return None,
+                _ => return Some((elements, Some(rest))),
+            }
+        }
+    }
+
+    /// Everything except `nil` counts as true.
+    pub fn is_true(&self) -> bool {
+        match &self.ltype {
+            LispType::Bool(b) => *b,
+            LispType::List(l) => !l.is_empty(),
+            _ => true,
+        }
+    }
+
+    /// Turns data into code by clearing the quoted flag of the object and of
+    /// every list nested in it. Lists built with `list` are quoted, but a list
+    /// that is evaluated as code has to be evaluated again. Nested symbols keep
+    /// their flag, so `'x` inside of the code stays a literal symbol.
+    /// Chains of cons pairs that end in nil become lists.
+    pub fn into_code(self) -> Self {
+        let elements = match self.list_parts() {
+            Some((elements, None)) if !elements.is_empty() => elements
+                .into_iter()
+                .map(|e| e.clone().nested_code())
+                .collect(),
+            _ => return LispObject::new_with(self.into_type(), false),
+        };
+        LispObject::new_with(LispType::list(elements), false)
     }
-}
 
-impl ToString for LispObject {
-    fn to_string(&self) -> String {
-        if self.quoted {
-            format!("'{}", self.ltype.to_string())
+    fn nested_code(mut self) -> Self {
+        if let LispType::List(_) | LispType::Cons(_) = self.ltype {
+            self.into_code()
         } else {
-            self.ltype.to_string()
+            // The code may end up in other scopes than the one it was
+            // resolved in, so its variables are looked up by name.
+            self.address = None;
+            self
         }
     }
 }
 
-#[derive(PartialEq, PartialOrd, Debug, Clone)]
+impl Display for LispObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Printer::CODE.print(self))
+    }
+}
+
+impl Drop for LispObject {
+    fn drop(&mut self) {
+        // Nested lists are torn down iteratively. The default drop glue would
+        // recurse once per level and overflow the stack on deep structures.
+        // Lists that are still shared are left to their other owners.
+        let mut pending = vec![];
+        take_unshared(&mut self.ltype, &mut pending);
+        while let Some(mut obj) = pending.pop() {
+            take_unshared(&mut obj.ltype, &mut pending);
+        }
+    }
+}
+
+/// Moves the elements of a list or cons pair into `pending`, unless other
+/// objects share them.
+fn take_unshared(ltype: &mut LispType, pending: &mut Vec<LispObject>) {
+    match ltype {
+        LispType::List(ListView { items: l, .. }) | LispType::Vector(l) => {
+            if let Some(l) = Rc::get_mut(l) {
+                pending.append(l);
+            }
+        }
+        LispType::Cons(c) => {
+            if let Some(c) = Rc::get_mut(c) {
+                pending.push(std::mem::replace(&mut c.0, LispObject::nil()));
+                pending.push(std::mem::replace(&mut c.1, LispObject::nil()));
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub enum LispType {
+    Integer(i64),
+    /// A floating point number.
     Number(f64),
     Symbol(String),
-    List(Vec<LispObject>),
-    Cons(Box<(LispObject, LispObject)>),
+    String(String),
+    /// Lists, vectors and cons pairs are shared, cloning them only copies a
+    /// pointer.
+    List(ListView),
+    Cons(Rc<(LispObject, LispObject)>),
+    /// A vector evaluates to itself, its elements are not evaluated.
+    Vector(Rc<Vec<LispObject>>),
     Bool(bool),
+    Function(Rc<Function>),
+    Condition(Rc<LispError>),
+    /// A compiled regular expression.
+    Regex(Rc<Regex>),
 }
 
 impl LispType {
     pub fn new(token: &str) -> Self {
-        if let Ok(num) = token.parse::<f64>() {
+        if let Some(s) = token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+            Self::String(unescape(s))
+        } else if let Ok(num) = token.parse::<i64>() {
+            Self::Integer(num)
+        } else if let Ok(num) = token.parse::<f64>() {
             Self::Number(num)
         } else if token == "t" {
             Self::Bool(true)
@@ -144,36 +355,194 @@ impl LispType {
     }
 
     pub fn new_cons(pair: (LispObject, LispObject)) -> Self {
-        Self::Cons(Box::new(pair))
+        Self::Cons(Rc::new(pair))
+    }
+
+    pub fn list(list: Vec<LispObject>) -> Self {
+        Self::List(ListView::new(list))
+    }
+
+    pub fn vector(vector: Vec<LispObject>) -> Self {
+        Self::Vector(Rc::new(vector))
+    }
+
+    /// The elements of a list or a vector.
+    pub fn elements(&self) -> Option<&[LispObject]> {
+        match self {
+            Self::List(l) => Some(l),
+            Self::Vector(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// The elements of a list from `start` on. The rest of a list shares the
+/// elements with it, so `cdr` takes constant time.
+#[derive(Clone)]
+pub struct ListView {
+    items: Rc<Vec<LispObject>>,
+    start: usize,
+}
+
+impl ListView {
+    pub fn new(items: Vec<LispObject>) -> Self {
+        Self {
+            items: Rc::new(items),
+            start: 0,
+        }
+    }
+
+    /// The list without its first element.
+    pub fn tail(&self) -> Self {
+        Self {
+            items: self.items.clone(),
+            start: (self.start + 1).min(self.items.len()),
+        }
+    }
+
+    /// All shared elements, including the ones in front of the start.
+    pub fn shared(&self) -> &Rc<Vec<LispObject>> {
+        &self.items
+    }
+
+    /// Whether both views show the same elements of the same list.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.items, &other.items) && self.start == other.start
+    }
+
+    /// Takes the elements out of the list. They are only copied if the list
+    /// is shared or starts behind the first element.
+    pub fn into_vec(self) -> Vec<LispObject> {
+        match (self.start, Rc::try_unwrap(self.items)) {
+            (0, Ok(items)) => items,
+            (start, Ok(items)) => items.into_iter().skip(start).collect(),
+            (start, Err(items)) => items[start..].to_vec(),
+        }
     }
 }
 
-impl ToString for LispType {
-    fn to_string(&self) -> String {
+impl Deref for ListView {
+    type Target = [LispObject];
+
+    fn deref(&self) -> &[LispObject] {
+        &self.items[self.start..]
+    }
+}
+
+impl PartialEq for ListView {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Debug for ListView {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Display for LispType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Printer::CODE.print_type(self))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+    /// A function implemented in Rust, identified by its name.
+    Builtin(String),
+    Lambda(Lambda),
+    /// Receives its arguments unevaluated. The value it returns is evaluated
+    /// in place of the macro call.
+    Macro(Lambda),
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Number(n) => n.to_string(),
-            Self::Symbol(s) => s.to_string(),
-            Self::List(l) => {
-                let mut s = "(".to_string();
-                for o in l {
-                    s.push_str(o.to_string().as_str());
-                    s.push(' ');
-                }
-                s.pop();
-                s.push(')');
-                s
-            }
-            Self::Cons(c) => format!("({} {})", c.0.to_string(), c.1.to_string()),
-            Self::Bool(b) => {
-                if *b {
-                    "t".to_string()
-                } else {
-                    "nil".to_string()
-                }
-            }
+            Function::Builtin(name) => write!(f, "#<builtin {}>", name),
+            Function::Lambda(Lambda {
+                name: Some(name), ..
+            }) => write!(f, "#<function {}>", name),
+            Function::Lambda(_) => write!(f, "#<lambda>"),
+            Function::Macro(l) => write!(f, "#<macro {}>", l.name.as_deref().unwrap_or("")),
+        }
+    }
+}
+
+/// A function defined in lisp with `lambda`, `defun` or `defmacro`.
+#[derive(Clone)]
+pub struct Lambda {
+    pub name: Option<String>,
+    pub params: Params,
+    pub body: Vec<LispObject>,
+    /// The frame the function was defined in.
+    pub env: Env,
+    /// The compiled body, if functions run on the virtual machine.
+    pub code: Option<Rc<Code>>,
+}
+
+impl PartialEq for Lambda {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.params == other.params
+            && self.body == other.body
+            && Rc::ptr_eq(&self.env, &other.env)
+    }
+}
+
+impl Debug for Lambda {
+    // The environment is left out, it may contain the lambda itself.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lambda")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+/// Prints a float with a decimal point or an exponent, so it can be told
+/// apart from an integer.
+pub fn format_float(n: f64) -> String {
+    if !n.is_finite() {
+        n.to_string()
+    } else if n.abs() >= 1e16 {
+        format!("{:e}", n)
+    } else if n.fract() == 0. {
+        format!("{:.1}", n)
+    } else {
+        n.to_string()
+    }
+}
+
+/// Replaces the escape sequences of a string literal.
+fn unescape(s: &str) -> String {
+    let mut res = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some(c) => res.push(c),
+            None => res.push('\\'),
         }
     }
+    res
+}
+
+/// The parameter list of a lambda: `(a b &optional c &rest d)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    pub required: Vec<String>,
+    pub optional: Vec<String>,
+    pub rest: Option<String>,
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,8 +551,9 @@ mod tests {
     fn test_lispobject() {
         let tests = [
             ("'test", LispType::Symbol("test".to_string()), true),
-            ("'12", LispType::Number(12.), true),
-            ("33", LispType::Number(33.), false),
+            ("'12", LispType::Integer(12), true),
+            ("33", LispType::Integer(33), false),
+            ("\"a b\"", LispType::String("a b".to_string()), false),
             ("test", LispType::Symbol("test".to_string()), false),
         ];
 
@@ -198,7 +568,12 @@ mod tests {
     fn test_lisptype() {
         let tests = [
             ("33.3", LispType::Number(33.3)),
-            ("5", LispType::Number(5.)),
+            ("5", LispType::Integer(5)),
+            ("5.", LispType::Number(5.)),
+            (
+                "\"say \\\"hi\\\"\\n\"",
+                LispType::String("say \"hi\"\n".to_string()),
+            ),
             ("lisp", LispType::Symbol("lisp".to_string())),
         ];
         for (test, res) in tests {
@@ -212,12 +587,17 @@ mod tests {
             (LispObject::bool(true), "t"),
             (
                 LispObject::cons(LispObject::nil(), LispObject::symbol("test")),
-                "(nil test)",
+                "(nil . test)",
             ),
             (
                 LispObject::list(&[LispObject::nil(), LispObject::number(2.1)]),
                 "(nil 2.1)",
             ),
+            (
+                LispObject::list(&[LispObject::integer(2), LispObject::number(2.)]),
+                "(2 2.0)",
+            ),
+            (LispObject::string("a\"b"), "\"a\\\"b\""),
         ];
         for (test, exp) in tests {
             assert_eq!(test.to_string(), exp.to_string());
@@ -1,19 +1,37 @@
 #![allow(dead_code)]
-use std::env;
+use std::path::Path;
+use std::rc::Rc;
+use std::{env, fs};
 
 use ast::ast;
 use config::Config;
-use evaluator::eval;
+use error::LispError;
+use evaluator::{eval, eval_code};
 use lispobject::LispObject;
+use module::{compile_module, read_module, write_module};
 use objectmanager::Manager;
+use optimizer::optimize;
 
 mod ast;
+mod compiler;
 mod config;
 mod error;
 mod evaluator;
+mod format;
 mod functions;
+mod gc;
+mod higherorder;
 mod lispobject;
+mod module;
 mod objectmanager;
+mod optimizer;
+mod printer;
+mod regex;
+mod resolver;
+mod sequence;
+mod strings;
+#[cfg(test)]
+mod testutil;
 
 fn main() {
     let args = env::args().skip(1).collect::<Vec<String>>();
@@ -23,25 +41,57 @@ fn main() {
     let lisp = LispObject::new_list(&lisp, config.eval_input);
     // Instantiate the objectmanager
     let mut manager = Manager::default();
+    manager.set_max_depth(config.max_depth);
+    manager.set_namespace(config.namespace);
+    manager.set_engine(config.engine);
+    if let Err(err) = run(&config, lisp, &mut manager) {
+        eprintln!("[ERROR] {}", err);
+        for (i, frame) in err.backtrace().iter().enumerate() {
+            eprintln!("  {}: {}", i, frame);
+        }
+        std::process::exit(-1);
+    }
+}
+
+fn run(config: &Config, lisp: LispObject, manager: &mut Manager) -> Result<(), LispError> {
     // Convert the args to an instruction, that the interpreter can understand
     let instr = LispObject::list(&[
         LispObject::symbol("set"),
         LispObject::symbol("argv").move_quoted(),
         lisp.move_quoted(),
     ]);
+    eval(instr, manager)?;
 
-    let res = eval(instr, &mut manager);
-    if res.is_err() {
-        eprintln!("[ERROR] {}", res.unwrap_err());
-        std::process::exit(-1);
+    if config.is_compiled_file() {
+        let bytes = config.get_file_bytes()?.unwrap();
+        for code in read_module(&bytes)? {
+            eval_code(Rc::new(code), manager)?;
+        }
+        return Ok(());
     }
 
-    if let Some(code) = config.get_file_string() {
-        let ast = ast(code.as_str()).unwrap();
-        for block in ast {
-            eval(block, &mut manager).unwrap();
+    if let Some(code) = config.get_file_string()? {
+        if config.compile {
+            return compile_file(config.file.as_ref().unwrap(), &code);
+        }
+        for block in ast(code.as_str())? {
+            let block = optimize(block, manager);
+            if config.dump_optimized {
+                println!("{}", block);
+            }
+            eval(block, manager)?;
         }
     }
+    Ok(())
+}
+
+/// Writes the compiled source next to the file, with the extension `.dlc`.
+fn compile_file(file: &str, code: &str) -> Result<(), LispError> {
+    let bytes = write_module(&compile_module(code)?)?;
+    let path = Path::new(file).with_extension("dlc");
+    fs::write(&path, bytes).map_err(|err| {
+        LispError::runtime_error(format!("Could not write {}: {}", path.display(), err))
+    })
 }
 
 fn sort_input(args: &[String]) -> (Vec<String>, Vec<String>) {
@@ -49,9 +99,10 @@ fn sort_input(args: &[String]) -> (Vec<String>, Vec<String>) {
     let mut lisp = Vec::new();
     let mut args = args.iter();
     while let Some(arg) = args.next() {
-        if arg == "-f" {
+        if ["-f", "-d", "--max-depth"].contains(&arg.as_str()) {
             cmds.push(arg.to_string());
-            cmds.push(args.next().unwrap().to_string());
+            // Config::new reports a missing value.
+            cmds.extend(args.next().cloned());
         } else if arg.starts_with('-') {
             cmds.push(arg.to_string());
         } else {
@@ -78,4 +129,7 @@ fn test_sort_input() {
     let (s1, s2) = sort_input(&test);
     assert_eq!(s1, exp);
     assert_eq!(s2, other);
+    let (s1, s2) = sort_input(&["(a)".to_string(), "-d".to_string()]);
+    assert_eq!(s1, ["-d"]);
+    assert_eq!(s2, ["(a)"]);
 }
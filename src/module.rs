@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::ast;
+use crate::compiler::{compile, Code, Op, Proto};
+use crate::error::{LispError, Span};
+use crate::lispobject::{Address, LispObject, LispType, Params};
+use crate::objectmanager::Manager;
+use crate::optimizer::Optimizer;
+use crate::resolver::resolve;
+
+/// The first bytes of every compiled file.
+const MAGIC: &[u8; 4] = b"\0dlc";
+/// The version of the file format. Files of other versions are rejected, so
+/// it has to change whenever the format or the meaning of the instructions
+/// changes.
+pub const FORMAT_VERSION: u32 = 3;
+
+// Tags of the constants.
+const NUMBER: u8 = 0;
+const SYMBOL: u8 = 1;
+const LIST: u8 = 2;
+const CONS: u8 = 3;
+const BOOL: u8 = 4;
+const INTEGER: u8 = 5;
+const STRING: u8 = 6;
+const VECTOR: u8 = 7;
+
+// Flags of the constants.
+const QUOTED: u8 = 1;
+const HAS_SPAN: u8 = 2;
+const HAS_ADDRESS: u8 = 4;
+
+/// Compiles the top-level forms of a source file. Each form becomes the code
+/// that is run in the global frame to evaluate it. The forms are optimized
+/// against the builtins, as the functions the file defines are not known
+/// before it runs.
+pub fn compile_module(source: &str) -> Result<Vec<Code>, LispError> {
+    let mut manager = Manager::default();
+    let mut optimizer = Optimizer::new(&mut manager);
+    Ok(ast(source)?
+        .into_iter()
+        .map(|form| compile(&[resolve(optimizer.optimize(form))]))
+        .collect())
+}
+
+/// Writes compiled code in the `.dlc` format:
+///
+/// - the magic bytes and the format version,
+/// - the symbol table with every name, symbol and string the code refers to,
+/// - the top-level code objects. Each code object holds its constant pool,
+///   its instructions, the source map with the position of every
+///   instruction, and the protos and blocks nested in it.
+///
+/// Numbers are little endian.
+pub fn write_module(module: &[Code]) -> Result<Vec<u8>, LispError> {
+    let mut writer = Writer::default();
+    writer.uint(module.len());
+    for code in module {
+        writer.code(code)?;
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    let mut symbols = Writer::default();
+    symbols.uint(writer.symbols.len());
+    for symbol in writer.symbols.iter() {
+        symbols.uint(symbol.len());
+        symbols.bytes.extend(symbol.as_bytes());
+    }
+    bytes.append(&mut symbols.bytes);
+    bytes.append(&mut writer.bytes);
+    Ok(bytes)
+}
+
+/// Reads code written by `write_module`.
+pub fn read_module(bytes: &[u8]) -> Result<Vec<Code>, LispError> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(LispError::runtime_error("Not a compiled dlisp file"));
+    }
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(LispError::runtime_error(format!(
+            "Compiled file has format version {}, but dlisp {} reads version {}; compile it again",
+            version,
+            env!("CARGO_PKG_VERSION"),
+            FORMAT_VERSION
+        )));
+    }
+
+    let mut reader = Reader {
+        bytes,
+        pos: 8,
+        symbols: vec![],
+    };
+    for _ in 0..reader.uint()? {
+        let len = reader.uint()?;
+        let symbol = reader.take(len)?;
+        match String::from_utf8(symbol.to_vec()) {
+            Ok(symbol) => reader.symbols.push(symbol),
+            Err(_) => return Err(corrupt()),
+        }
+    }
+    let mut module = vec![];
+    for _ in 0..reader.uint()? {
+        module.push(reader.code()?);
+    }
+    if reader.pos != bytes.len() {
+        return Err(corrupt());
+    }
+    Ok(module)
+}
+
+fn corrupt() -> LispError {
+    LispError::runtime_error("Compiled file is corrupt")
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    symbols: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn u8(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn uint(&mut self, n: usize) {
+        self.bytes.extend((n as u64).to_le_bytes());
+    }
+
+    /// Writes the index of the string in the symbol table.
+    fn symbol(&mut self, symbol: &str) {
+        let index = match self.indices.get(symbol) {
+            Some(index) => *index,
+            None => {
+                self.symbols.push(symbol.to_string());
+                self.indices
+                    .insert(symbol.to_string(), self.symbols.len() - 1);
+                self.symbols.len() - 1
+            }
+        };
+        self.uint(index);
+    }
+
+    fn span(&mut self, span: Span) {
+        self.uint(span.line);
+        self.uint(span.column);
+    }
+
+    /// Writes an object and the objects in it, first to last. The objects
+    /// are kept on a work list, so deep nesting does not overflow the stack.
+    fn object(&mut self, obj: &LispObject) -> Result<(), LispError> {
+        let mut work = vec![obj];
+        while let Some(obj) = work.pop() {
+            let mut flags = 0;
+            if obj.is_quoted() {
+                flags |= QUOTED;
+            }
+            if obj.span().is_some() {
+                flags |= HAS_SPAN;
+            }
+            if obj.address().is_some() {
+                flags |= HAS_ADDRESS;
+            }
+            let tag = match obj.ltype() {
+                LispType::Integer(_) => INTEGER,
+                LispType::Number(_) => NUMBER,
+                LispType::Symbol(_) => SYMBOL,
+                LispType::String(_) => STRING,
+                LispType::List(_) => LIST,
+                LispType::Cons(_) => CONS,
+                LispType::Vector(_) => VECTOR,
+                LispType::Bool(_) => BOOL,
+                LispType::Function(_) | LispType::Condition(_) | LispType::Regex(_) => {
+                    return Err(LispError::type_error(
+                        "Can not write to a compiled file",
+                        obj.clone(),
+                    ))
+                }
+            };
+            self.u8(tag);
+            self.u8(flags);
+            if let Some(span) = obj.span() {
+                self.span(span);
+            }
+            if let Some(address) = obj.address() {
+                self.uint(address.depth);
+                self.uint(address.index);
+            }
+            match obj.ltype() {
+                LispType::Integer(n) => self.bytes.extend(n.to_le_bytes()),
+                LispType::Number(n) => self.bytes.extend(n.to_le_bytes()),
+                LispType::Symbol(s) | LispType::String(s) => self.symbol(s),
+                LispType::List(_) | LispType::Vector(_) => {
+                    let l = obj.ltype().elements().unwrap();
+                    self.uint(l.len());
+                    work.extend(l.iter().rev());
+                }
+                LispType::Cons(pair) => {
+                    work.push(&pair.1);
+                    work.push(&pair.0);
+                }
+                LispType::Bool(b) => self.u8(*b as u8),
+                LispType::Function(_) | LispType::Condition(_) | LispType::Regex(_) => {
+                    unreachable!()
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn names(&mut self, names: &[String]) {
+        self.uint(names.len());
+        for name in names {
+            self.symbol(name);
+        }
+    }
+
+    fn optional_name(&mut self, name: &Option<String>) {
+        match name {
+            Some(name) => {
+                self.u8(1);
+                self.symbol(name);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        match op {
+            Op::Const(i) => {
+                self.u8(0);
+                self.uint(i);
+            }
+            Op::Local(address, name) => {
+                self.u8(1);
+                self.uint(address.depth);
+                self.uint(address.index);
+                self.uint(name);
+            }
+            Op::Global(name) => {
+                self.u8(2);
+                self.uint(name);
+            }
+            Op::Function { name, form, skip } => {
+                self.u8(3);
+                self.uint(name);
+                self.uint(form);
+                self.uint(skip);
+            }
+            Op::Call(argc) => {
+                self.u8(4);
+                self.uint(argc);
+            }
+            Op::Pop => self.u8(5),
+            Op::Jump(target) => {
+                self.u8(6);
+                self.uint(target);
+            }
+            Op::JumpIfNot(target) => {
+                self.u8(7);
+                self.uint(target);
+            }
+            Op::Closure(proto) => {
+                self.u8(8);
+                self.uint(proto);
+            }
+            Op::Define { proto, is_macro } => {
+                self.u8(9);
+                self.uint(proto);
+                self.u8(is_macro as u8);
+            }
+            Op::Block(block) => {
+                self.u8(10);
+                self.uint(block);
+            }
+            Op::Eval(form) => {
+                self.u8(11);
+                self.uint(form);
+            }
+            Op::Return => self.u8(12),
+        }
+    }
+
+    fn code(&mut self, code: &Code) -> Result<(), LispError> {
+        self.uint(code.constants.len());
+        for constant in code.constants.iter() {
+            self.object(constant)?;
+        }
+        self.uint(code.ops.len());
+        for (op, span) in code.ops.iter().zip(code.spans.iter()) {
+            self.op(*op);
+            match span {
+                Some(span) => {
+                    self.u8(1);
+                    self.span(*span);
+                }
+                None => self.u8(0),
+            }
+        }
+        self.uint(code.protos.len());
+        for proto in code.protos.iter() {
+            self.optional_name(&proto.name);
+            self.names(&proto.params.required);
+            self.names(&proto.params.optional);
+            self.optional_name(&proto.params.rest);
+            self.uint(proto.body.len());
+            for form in proto.body.iter() {
+                self.object(form)?;
+            }
+            self.code(&proto.code)?;
+        }
+        self.uint(code.blocks.len());
+        for (name, block) in code.blocks.iter() {
+            self.symbol(name);
+            self.code(block)?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    symbols: Vec<String>,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LispError> {
+        let end = self.pos.checked_add(len).ok_or_else(corrupt)?;
+        let bytes = self.bytes.get(self.pos..end).ok_or_else(corrupt)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LispError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<usize, LispError> {
+        let n = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(n).map_err(|_| corrupt())
+    }
+
+    fn symbol(&mut self) -> Result<String, LispError> {
+        let index = self.uint()?;
+        self.symbols.get(index).cloned().ok_or_else(corrupt)
+    }
+
+    fn span(&mut self) -> Result<Span, LispError> {
+        Ok(Span {
+            line: self.uint()?,
+            column: self.uint()?,
+        })
+    }
+
+    /// Reads an object written by `Writer::object`. The lists, vectors and
+    /// cons pairs whose elements are still being read are kept on a stack,
+    /// so deep nesting does not overflow it.
+    fn object(&mut self) -> Result<LispObject, LispError> {
+        let mut open: Vec<(Header, Vec<LispObject>, usize)> = vec![];
+        loop {
+            let header = self.header()?;
+            let ltype = match header.tag {
+                NUMBER => LispType::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                INTEGER => LispType::Integer(i64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                SYMBOL => LispType::Symbol(self.symbol()?),
+                STRING => LispType::String(self.symbol()?),
+                LIST | VECTOR => match self.uint()? {
+                    0 if header.tag == LIST => LispType::list(vec![]),
+                    0 => LispType::vector(vec![]),
+                    len => {
+                        open.push((header, vec![], len));
+                        continue;
+                    }
+                },
+                CONS => {
+                    open.push((header, vec![], 2));
+                    continue;
+                }
+                BOOL => LispType::Bool(self.u8()? != 0),
+                _ => return Err(corrupt()),
+            };
+            let mut obj = header.finish(ltype);
+            // The object is the next element of the innermost open object,
+            // which is finished once it has all of them.
+            loop {
+                let Some((_, items, len)) = open.last_mut() else {
+                    return Ok(obj);
+                };
+                items.push(obj);
+                if items.len() < *len {
+                    break;
+                }
+                let (header, mut items, _) = open.pop().unwrap();
+                let ltype = match header.tag {
+                    LIST => LispType::list(items),
+                    VECTOR => LispType::vector(items),
+                    _ => {
+                        let cdr = items.pop().unwrap();
+                        LispType::new_cons((items.pop().unwrap(), cdr))
+                    }
+                };
+                obj = header.finish(ltype);
+            }
+        }
+    }
+
+    fn header(&mut self) -> Result<Header, LispError> {
+        let tag = self.u8()?;
+        let flags = self.u8()?;
+        let span = match flags & HAS_SPAN {
+            0 => None,
+            _ => Some(self.span()?),
+        };
+        let address = match flags & HAS_ADDRESS {
+            0 => None,
+            _ => Some(Address {
+                depth: self.uint()?,
+                index: self.uint()?,
+            }),
+        };
+        Ok(Header {
+            tag,
+            quoted: flags & QUOTED != 0,
+            span,
+            address,
+        })
+    }
+
+    fn names(&mut self) -> Result<Vec<String>, LispError> {
+        (0..self.uint()?).map(|_| self.symbol()).collect()
+    }
+
+    fn optional_name(&mut self) -> Result<Option<String>, LispError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.symbol().map(Some),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, LispError> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.uint()?),
+            1 => {
+                let address = Address {
+                    depth: self.uint()?,
+                    index: self.uint()?,
+                };
+                Op::Local(address, self.uint()?)
+            }
+            2 => Op::Global(self.uint()?),
+            3 => Op::Function {
+                name: self.uint()?,
+                form: self.uint()?,
+                skip: self.uint()?,
+            },
+            4 => Op::Call(self.uint()?),
+            5 => Op::Pop,
+            6 => Op::Jump(self.uint()?),
+            7 => Op::JumpIfNot(self.uint()?),
+            8 => Op::Closure(self.uint()?),
+            9 => Op::Define {
+                proto: self.uint()?,
+                is_macro: self.u8()? != 0,
+            },
+            10 => Op::Block(self.uint()?),
+            11 => Op::Eval(self.uint()?),
+            12 => Op::Return,
+            _ => return Err(corrupt()),
+        })
+    }
+
+    fn code(&mut self) -> Result<Code, LispError> {
+        let mut code = Code::default();
+        for _ in 0..self.uint()? {
+            code.constants.push(self.object()?);
+        }
+        for _ in 0..self.uint()? {
+            code.ops.push(self.op()?);
+            code.spans.push(match self.u8()? {
+                0 => None,
+                _ => Some(self.span()?),
+            });
+        }
+        for _ in 0..self.uint()? {
+            let name = self.optional_name()?;
+            let params = Params {
+                required: self.names()?,
+                optional: self.names()?,
+                rest: self.optional_name()?,
+            };
+            let mut body = vec![];
+            for _ in 0..self.uint()? {
+                body.push(self.object()?);
+            }
+            let proto = Rc::new(Proto {
+                name,
+                params,
+                body,
+                code: Rc::new(self.code()?),
+            });
+            code.protos.push(proto);
+        }
+        for _ in 0..self.uint()? {
+            let name = self.symbol()?;
+            code.blocks.push((name, Rc::new(self.code()?)));
+        }
+        check(&code)?;
+        Ok(code)
+    }
+}
+
+/// The tag and the flags in front of a constant.
+struct Header {
+    tag: u8,
+    quoted: bool,
+    span: Option<Span>,
+    address: Option<Address>,
+}
+
+impl Header {
+    fn finish(self, ltype: LispType) -> LispObject {
+        let mut obj = LispObject::new_with(ltype, self.quoted);
+        if let Some(span) = self.span {
+            obj = obj.with_span(span);
+        }
+        if let Some(address) = self.address {
+            obj = obj.with_address(address);
+        }
+        obj
+    }
+}
+
+/// Makes sure the instructions only refer to entries that exist and find
+/// the values they take on the stack, so the virtual machine can not run off
+/// its tables.
+fn check(code: &Code) -> Result<(), LispError> {
+    let constant = |i: usize| i < code.constants.len();
+    let symbol = |i: usize| constant(i) && code.constants[i].as_symbol().is_some();
+    let target = |t: usize| t < code.ops.len();
+    let valid = code.ops.iter().all(|op| match *op {
+        Op::Const(i) | Op::Eval(i) => constant(i),
+        Op::Local(_, name) | Op::Global(name) => symbol(name),
+        Op::Function { name, form, skip } => symbol(name) && constant(form) && target(skip),
+        Op::Jump(t) | Op::JumpIfNot(t) => target(t),
+        Op::Closure(proto) => proto < code.protos.len(),
+        Op::Define { proto, .. } => proto < code.protos.len() && code.protos[proto].name.is_some(),
+        Op::Block(block) => block < code.blocks.len(),
+        Op::Call(_) | Op::Pop | Op::Return => true,
+    });
+    if valid && check_stack(code) {
+        Ok(())
+    } else {
+        Err(corrupt())
+    }
+}
+
+/// Follows every path through the instructions with the number of values
+/// on the stack, which has to be the same on all paths to an instruction.
+/// Every path ends in a `Return`, and no instruction takes more values
+/// than there are.
+fn check_stack(code: &Code) -> bool {
+    let mut heights = vec![None; code.ops.len()];
+    let mut work: Vec<(usize, usize)> = vec![(0, 0)];
+    while let Some((pc, height)) = work.pop() {
+        match heights.get(pc) {
+            None => return false,
+            Some(Some(h)) if *h == height => continue,
+            Some(Some(_)) => return false,
+            Some(None) => heights[pc] = Some(height),
+        }
+        // The values the instruction takes and where execution continues,
+        // with the number of values there.
+        let (takes, next) = match code.ops[pc] {
+            Op::Const(_)
+            | Op::Local(..)
+            | Op::Global(_)
+            | Op::Closure(_)
+            | Op::Define { .. }
+            | Op::Block(_)
+            | Op::Eval(_) => (0, vec![(pc + 1, height + 1)]),
+            // A macro continues at `skip` with its expansion.
+            Op::Function { skip, .. } => (0, vec![(pc + 1, height + 1), (skip, height + 1)]),
+            Op::Call(argc) => (argc + 1, vec![(pc + 1, height.saturating_sub(argc))]),
+            Op::Pop => (1, vec![(pc + 1, height.saturating_sub(1))]),
+            Op::Jump(t) => (0, vec![(t, height)]),
+            Op::JumpIfNot(t) => {
+                let height = height.saturating_sub(1);
+                (1, vec![(pc + 1, height), (t, height)])
+            }
+            Op::Return => (1, vec![]),
+        };
+        if takes > height {
+            return false;
+        }
+        work.extend(next);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::{eval, eval_code};
+
+    const SOURCE: &str = "(defmacro twice (x) (list 'progn x x))
+         (defun count-to (n &optional step &rest more)
+           (if n (block inner (return-from count-to (list n step (quote (1 t)) #(n \"s\")))) nil))
+         (set 'f (lambda (x) (add x 1.5)))
+         (twice (funcall f 2))
+         (count-to 3)";
+
+    #[test]
+    fn test_module_round_trip() {
+        let module = compile_module(SOURCE).unwrap();
+        let bytes = write_module(&module).unwrap();
+        let read = read_module(&bytes).unwrap();
+        assert_eq!(read, module);
+        // The source map survives as well.
+        let spans = |code: &Code| code.protos[0].code.spans.clone();
+        assert_eq!(spans(&read[1]), spans(&module[1]));
+        assert_eq!(write_module(&read).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_module_deep_nesting() {
+        let depth = 20_000;
+        let source = format!("'{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let bytes = write_module(&compile_module(&source).unwrap()).unwrap();
+        let read = read_module(&bytes).unwrap();
+        assert_eq!(write_module(&read).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_module_runs_like_source() {
+        let mut manager = Manager::default();
+        let mut expected = vec![];
+        for form in ast(SOURCE).unwrap() {
+            expected.push(eval(form, &mut manager).unwrap());
+        }
+
+        let bytes = write_module(&compile_module(SOURCE).unwrap()).unwrap();
+        let mut manager = Manager::default();
+        let mut results = vec![];
+        for code in read_module(&bytes).unwrap() {
+            results.push(eval_code(Rc::new(code), &mut manager).unwrap());
+        }
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_module_rejects_other_versions() {
+        let mut bytes = write_module(&compile_module("(add 1 2)").unwrap()).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let err = read_module(&bytes).unwrap_err();
+        let version = format!("format version {}", FORMAT_VERSION + 1);
+        assert!(err.message().contains(&version));
+
+        assert!(read_module(b"(add 1 2)").is_err());
+        let bytes = write_module(&compile_module("(add 1 2)").unwrap()).unwrap();
+        let err = read_module(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.message(), "Compiled file is corrupt");
+    }
+
+    #[test]
+    fn test_module_rejects_malformed_code() {
+        let code = |ops: &[Op]| {
+            let mut code = compile_module("(f 1 2)").unwrap().remove(0);
+            code.spans = vec![None; ops.len()];
+            code.ops = ops.to_vec();
+            write_module(&[code]).unwrap()
+        };
+        let call = |argc| {
+            let function = Op::Function {
+                name: 0,
+                form: 1,
+                skip: 4,
+            };
+            vec![
+                function,
+                Op::Const(2),
+                Op::Const(3),
+                Op::Call(argc),
+                Op::Return,
+            ]
+        };
+        assert!(read_module(&code(&call(2))).is_ok());
+        let cases = [
+            call(5),
+            vec![Op::Return],
+            vec![Op::JumpIfNot(1), Op::Return],
+            vec![Op::Const(2), Op::Pop, Op::Pop, Op::Const(2), Op::Return],
+            // The paths to an instruction leave different numbers of values.
+            vec![Op::Const(2), Op::JumpIfNot(3), Op::Const(2), Op::Return],
+            // Execution runs past the last instruction.
+            vec![Op::Const(2), Op::Const(3)],
+        ];
+        for ops in cases {
+            let err = read_module(&code(&ops)).unwrap_err();
+            assert_eq!(err.message(), "Compiled file is corrupt");
+        }
+    }
+}
@@ -1,102 +1,404 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::{Rc, Weak},
+};
+
 use crate::{
-    functions::cons,
-    lispobject::{LispObject, LispType},
+    error::ErrorType,
+    gc::{self, GcStats},
+    lispobject::{Address, LispObject, LispType},
 };
 
-#[derive(Default, Debug)]
+/// Default for the maximum depth of the evaluation stack.
+pub const DEFAULT_MAX_DEPTH: usize = 100_000;
+
+/// Number of frames that are created before the garbage collector runs for
+/// the first time.
+const GC_THRESHOLD: usize = 10_000;
+
+/// A frame that can be shared between the frame stack and the closures that
+/// captured it.
+pub type Env = Rc<RefCell<Frame>>;
+
+/// Where `defun` stores functions and where the head of a call is looked up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Namespace {
+    /// Functions are ordinary values in the variable frames.
+    Unified,
+    /// Functions live in their own table, next to the variables.
+    Separate,
+}
+
+/// How the bodies of functions are run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// The evaluator walks the forms of the body.
+    Tree,
+    /// The body is compiled to bytecode, which runs on a virtual machine.
+    Vm,
+}
+
+#[derive(Debug)]
 pub struct Manager {
-    frames: Vec<Frame>,
+    frames: Vec<Env>,
+    hidden: Vec<Vec<Env>>,
+    functions: Frame,
+    /// Parents of the condition types created with `define-condition`.
+    conditions: HashMap<String, ErrorType>,
+    next_block: usize,
+    namespace: Namespace,
+    engine: Engine,
+    max_depth: usize,
+    /// Every frame that was created, to find the ones kept alive by cycles.
+    heap: Vec<Weak<RefCell<Frame>>>,
+    gc_threshold: usize,
+    gc_stats: GcStats,
+}
+
+impl Default for Manager {
+    fn default() -> Self {
+        let global = Env::default();
+        Self {
+            heap: vec![Rc::downgrade(&global)],
+            gc_threshold: GC_THRESHOLD,
+            gc_stats: GcStats::default(),
+            frames: vec![global],
+            hidden: Vec::new(),
+            functions: Frame::default(),
+            conditions: HashMap::new(),
+            next_block: 0,
+            namespace: Namespace::Unified,
+            engine: Engine::Tree,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
 }
 
 impl Manager {
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Sets how many pending evaluations may be nested before evaluation is
+    /// aborted with a `stack-overflow` error.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    pub fn set_namespace(&mut self, namespace: Namespace) {
+        self.namespace = namespace;
+    }
+
+    /// The innermost frame. Closures capture it as their environment.
+    pub fn current_env(&self) -> Env {
+        self.frames.last().unwrap().clone()
+    }
+
+    /// Pushes a frame that is nested inside the current one.
     pub fn new_frame(&mut self) {
-        self.frames.push(Frame::default());
+        let parent = self.current_env();
+        self.push_frame(parent);
     }
 
+    /// Pushes a frame whose enclosing scope is `parent`, which is the
+    /// environment of the called closure.
+    pub fn push_frame(&mut self, parent: Env) {
+        if self.heap.len() >= self.gc_threshold {
+            self.collect_garbage();
+            self.gc_threshold = (self.heap.len() * 2).max(GC_THRESHOLD);
+        }
+        let frame = Rc::new(RefCell::new(Frame {
+            parent: Some(parent),
+            ..Frame::default()
+        }));
+        self.heap.push(Rc::downgrade(&frame));
+        self.frames.push(frame);
+    }
+
+    /// Frees the frames that are only kept alive by reference cycles and
+    /// returns how many were freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        let freed = gc::collect(&self.heap);
+        self.heap.retain(|frame| frame.strong_count() > 0);
+        self.gc_stats.collections += 1;
+        self.gc_stats.freed += freed;
+        freed
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        let live = self
+            .heap
+            .iter()
+            .filter(|frame| frame.strong_count() > 0)
+            .count();
+        GcStats {
+            live,
+            ..self.gc_stats
+        }
+    }
+
+    /// Pops the innermost frame. The global frame is never popped.
     pub fn pop_frame(&mut self) {
-        self.frames.pop();
+        if self.frames.len() > 1 {
+            self.frames.pop();
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Pops frames until only `depth` frames are left.
+    pub fn truncate_frames(&mut self, depth: usize) {
+        self.frames.truncate(depth.max(1));
+    }
+
+    /// Hides every frame except the global one until `leave_global` is
+    /// called, so that lookups only see global variables.
+    pub fn enter_global(&mut self) {
+        let locals = self.frames.split_off(1);
+        self.hidden.push(locals);
+    }
+
+    pub fn leave_global(&mut self) {
+        if let Some(locals) = self.hidden.pop() {
+            self.frames.truncate(1);
+            self.frames.extend(locals);
+        }
     }
 
     pub fn get_val(&mut self, name: LispObject) -> Option<LispObject> {
-        for frame in self.frames.iter().rev() {
+        let mut env = self.frames.last().cloned();
+        while let Some(frame) = env {
+            let frame = frame.borrow();
             if let Some(val) = frame.get_val(name.clone()) {
                 return Some(val);
             }
+            env = frame.parent.clone();
         }
         None
     }
 
     pub fn set_val(&mut self, key: LispObject, value: LispObject) -> Option<()> {
-        // Loop through all frames and check if the current frame contains the
-        // variable. If it finds the variable: return.
-        for frame in self.frames.iter_mut().rev() {
+        // Walk through the enclosing frames and check if one of them already
+        // contains the variable. If it finds the variable: return.
+        let mut env = self.frames.last().cloned();
+        while let Some(frame) = env {
+            let mut frame = frame.borrow_mut();
             if frame.set_val(key.clone(), value.clone()).is_some() {
                 return Some(());
             }
+            env = frame.parent.clone();
         }
 
         // If we did't already return, there was no variable with that name and
         // it gets inserted into the current frame.
-        if let Some(frame) = self.frames.last_mut() {
-            return frame.set_val_force(key, value);
+        self.define_val(key, value)
+    }
+
+    /// Binds the variable in the current frame, shadowing outer bindings.
+    pub fn define_val(&mut self, key: LispObject, value: LispObject) -> Option<()> {
+        self.frames
+            .last()
+            .unwrap()
+            .borrow_mut()
+            .set_val_force(key, value)
+    }
+
+    /// Binds a parameter in the current frame, see `Frame::bind`.
+    pub fn bind_local(&mut self, name: &str, value: LispObject) {
+        self.frames.last().unwrap().borrow_mut().bind(name, value);
+    }
+
+    /// Reads the variable at a lexical address.
+    pub fn get_local(&self, address: Address) -> Option<LispObject> {
+        let mut env = self.frames.last().unwrap().clone();
+        for _ in 0..address.depth {
+            let parent = env.borrow().parent.clone()?;
+            env = parent;
+        }
+        let frame = env.borrow();
+        let cell = frame.slots.get(address.index)?;
+        let val = cell.borrow().clone();
+        Some(val)
+    }
+
+    /// Binds the variable in the global frame.
+    pub fn set_global(&mut self, key: LispObject, value: LispObject) -> Option<()> {
+        let mut global = self.frames[0].borrow_mut();
+        if global.set_val(key.clone(), value.clone()).is_some() {
+            return Some(());
         }
+        global.set_val_force(key, value)
+    }
+
+    pub fn get_function(&self, name: LispObject) -> Option<LispObject> {
+        self.functions.get_val(name)
+    }
+
+    /// A new id, which identifies one activation of a block.
+    pub fn new_block_id(&mut self) -> usize {
+        self.next_block += 1;
+        self.next_block
+    }
+
+    /// Binds the name of a block in the current frame.
+    pub fn establish_block(&mut self, name: &str, id: usize) {
+        let mut frame = self.frames.last().unwrap().borrow_mut();
+        frame.blocks.push((name.to_string(), id));
+    }
 
+    /// Finds the id of the lexically innermost block with that name.
+    pub fn lookup_block(&self, name: &str) -> Option<usize> {
+        let mut env = self.frames.last().cloned();
+        while let Some(frame) = env {
+            let frame = frame.borrow();
+            if let Some((_, id)) = frame.blocks.iter().rev().find(|(n, _)| n == name) {
+                return Some(*id);
+            }
+            env = frame.parent.clone();
+        }
         None
     }
+
+    /// Registers a condition type. Fails if `parent` is a subtype of `name`,
+    /// which would make the hierarchy cyclic.
+    pub fn define_condition(&mut self, name: &str, parent: ErrorType) -> Option<()> {
+        let kind = ErrorType::from_name(name);
+        if !matches!(kind, ErrorType::Custom(_)) || self.is_subtype(&parent, &kind) {
+            return None;
+        }
+        self.conditions.insert(name.to_string(), parent);
+        Some(())
+    }
+
+    pub fn condition_parent(&self, kind: &ErrorType) -> Option<ErrorType> {
+        match kind {
+            ErrorType::Custom(name) if self.conditions.contains_key(name) => {
+                self.conditions.get(name).cloned()
+            }
+            _ => kind.parent(),
+        }
+    }
+
+    /// Checks if `kind` is `ancestor` or one of its subtypes.
+    pub fn is_subtype(&self, kind: &ErrorType, ancestor: &ErrorType) -> bool {
+        let mut kind = Some(kind.clone());
+        while let Some(k) = kind {
+            if &k == ancestor {
+                return true;
+            }
+            kind = self.condition_parent(&k);
+        }
+        false
+    }
+
+    pub fn set_function(&mut self, name: LispObject, function: LispObject) -> Option<()> {
+        if self
+            .functions
+            .set_val(name.clone(), function.clone())
+            .is_some()
+        {
+            return Some(());
+        }
+        self.functions.set_val_force(name, function)
+    }
+}
+
+/// The name of a variable. Names are interned, so every frame that binds
+/// a variable shares one allocation of its name.
+pub type Symbol = Rc<str>;
+
+/// The storage of a single binding.
+pub type Cell = Rc<RefCell<LispObject>>;
+
+thread_local! {
+    static SYMBOLS: RefCell<HashSet<Symbol>> = RefCell::new(HashSet::new());
+}
+
+/// Returns the interned symbol with that name.
+pub fn intern(name: &str) -> Symbol {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        match symbols.get(name) {
+            Some(symbol) => symbol.clone(),
+            None => {
+                let symbol: Symbol = Rc::from(name);
+                symbols.insert(symbol.clone());
+                symbol
+            }
+        }
+    })
 }
 
 #[derive(Default, Debug)]
-struct Frame {
-    scoped_objects: Vec<LispObject>,
+pub struct Frame {
+    vars: HashMap<Symbol, Cell>,
+    /// The bindings of the parameters in the order of their lexical
+    /// addresses. They share their cells with `vars`.
+    slots: Vec<Cell>,
+    /// Names of the blocks established in this frame and their ids.
+    blocks: Vec<(String, usize)>,
+    parent: Option<Env>,
 }
 
 impl Frame {
+    /// Binds the variable of a cons pair `(name . value)`.
     pub fn push(&mut self, obj: LispObject) {
-        self.scoped_objects.push(obj);
+        if let LispType::Cons(pair) = obj.into_type() {
+            self.set_val_force(pair.0.clone(), pair.1.clone());
+        }
     }
 
     pub fn get_val(&self, name: LispObject) -> Option<LispObject> {
-        let var_name = match name.get_type() {
-            LispType::Symbol(s) => s,
-            _ => return None,
-        };
-
-        for obj in self.scoped_objects.iter() {
-            if let LispType::Cons(pair) = obj.get_type() {
-                if let LispType::Symbol(s) = pair.0.get_type() {
-                    if s == var_name {
-                        return Some(pair.1);
-                    }
-                }
-            }
-        }
-        None
+        let cell = self.vars.get(name.as_symbol()?)?;
+        Some(cell.borrow().clone())
     }
 
     pub fn set_val(&mut self, name: LispObject, new: LispObject) -> Option<()> {
-        let var_name = match name.get_type() {
-            LispType::Symbol(s) => s,
-            _ => return None,
-        };
-
-        for obj in self.scoped_objects.iter_mut() {
-            if let LispType::Cons(pair) = obj.get_type() {
-                if let LispType::Symbol(s) = pair.0.get_type() {
-                    if s == var_name {
-                        *obj = cons(&[pair.0, new]).unwrap();
-                        return Some(());
-                    }
-                }
-            }
-        }
+        let cell = self.vars.get(name.as_symbol()?)?;
+        *cell.borrow_mut() = new;
+        Some(())
+    }
 
-        //self.scoped_objects.push(cons(&[name, new]).unwrap());
+    pub fn parent(&self) -> Option<Env> {
+        self.parent.clone()
+    }
 
-        None
+    /// The cells of the variables, once for each reference the frame holds.
+    pub fn cells(&self) -> Vec<Cell> {
+        self.vars
+            .values()
+            .chain(self.slots.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Binds a parameter, which can be accessed by its index.
+    pub fn bind(&mut self, name: &str, value: LispObject) {
+        let cell = Rc::new(RefCell::new(value));
+        self.vars.insert(intern(name), cell.clone());
+        self.slots.push(cell);
     }
 
+    /// Binds the variable in this frame, replacing a binding with the same
+    /// name.
     pub fn set_val_force(&mut self, name: LispObject, new: LispObject) -> Option<()> {
-        self.scoped_objects.push(cons(&[name, new]).unwrap());
+        let symbol = intern(name.as_symbol()?);
+        self.vars.insert(symbol, Rc::new(RefCell::new(new)));
         Some(())
     }
 }
@@ -104,14 +406,16 @@ impl Frame {
 #[cfg(test)]
 mod tests {
     use std::panic;
+    use std::time::Instant;
 
     use super::*;
+    use crate::functions::cons;
 
     fn test_frame_default() -> Frame {
         let mut frame = Frame::default();
-        frame.push(cons(&[LispObject::symbol("test"), LispObject::number(22.)]).unwrap());
+        frame.push(cons(&[LispObject::symbol("test"), LispObject::integer(22)]).unwrap());
         frame.push(cons(&[LispObject::symbol("other-var"), LispObject::bool(true)]).unwrap());
-        frame.push(cons(&[LispObject::symbol("another-one"), LispObject::number(23.)]).unwrap());
+        frame.push(cons(&[LispObject::symbol("another-one"), LispObject::integer(23)]).unwrap());
         frame
     }
 
@@ -128,12 +432,12 @@ mod tests {
             panic!("Value could not be found.");
         }
 
-        if let LispType::Number(n) = frame
+        if let LispType::Integer(n) = frame
             .get_val(LispObject::symbol("another-one"))
             .unwrap()
             .get_type()
         {
-            assert_eq!(n, 23.);
+            assert_eq!(n, 23);
         } else {
             panic!("Value could not be found.");
         }
@@ -162,7 +466,7 @@ mod tests {
         manager.set_val(LispObject::symbol("test"), LispObject::nil());
         manager.set_val(LispObject::symbol("other"), LispObject::nil());
         manager.new_frame();
-        manager.set_val(LispObject::symbol("second"), LispObject::number(1.));
+        manager.set_val(LispObject::symbol("second"), LispObject::integer(1));
         assert_eq!(
             manager.get_val(LispObject::symbol("other")).unwrap(),
             LispObject::nil()
@@ -173,14 +477,45 @@ mod tests {
     fn test_frame_multiple_frames_out_of_scope() {
         let mut manager = Manager::default();
         manager.new_frame();
-        manager.set_val(LispObject::symbol("this"), LispObject::number(1.));
+        manager.set_val(LispObject::symbol("this"), LispObject::integer(1));
         manager.new_frame();
-        manager.set_val(LispObject::symbol("test"), LispObject::number(2.));
+        manager.set_val(LispObject::symbol("test"), LispObject::integer(2));
         assert_eq!(
             manager.get_val(LispObject::symbol("test")),
-            Some(LispObject::number(2.))
+            Some(LispObject::integer(2))
         );
         manager.pop_frame();
         assert_eq!(manager.get_val(LispObject::symbol("test")), None);
     }
+
+    #[test]
+    fn test_intern() {
+        assert!(Rc::ptr_eq(&intern("symbol"), &intern("symbol")));
+        assert!(!Rc::ptr_eq(&intern("symbol"), &intern("other")));
+    }
+
+    /// Measures lookups with few and with many globals. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_frame_lookup() {
+        let mut times = vec![];
+        for globals in [10, 10_000] {
+            let mut manager = Manager::default();
+            for i in 0..globals {
+                let name = LispObject::symbol(format!("var-{}", i));
+                manager.set_val(name, LispObject::integer(i as i64));
+            }
+            // The variable defined last, which a linear scan finds last.
+            let name = LispObject::symbol(format!("var-{}", globals - 1));
+            let start = Instant::now();
+            for _ in 0..100_000 {
+                assert!(manager.get_val(name.clone()).is_some());
+            }
+            let elapsed = start.elapsed();
+            println!("{} globals: {:?} for 100000 lookups", globals, elapsed);
+            times.push(elapsed);
+        }
+        assert!(times[1] < times[0] * 5);
+    }
 }
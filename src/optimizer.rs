@@ -0,0 +1,317 @@
+use crate::error::Span;
+use crate::evaluator::{lookup_function, SPECIAL_FORMS};
+use crate::functions::{call_builtin, PURE_BUILTINS};
+use crate::lispobject::{Function, LispObject, LispType};
+use crate::objectmanager::Manager;
+use crate::resolver::MAX_NESTING;
+
+/// Rewrites a form that is about to be evaluated into a simpler form with the
+/// same effect:
+///
+/// - calls to pure builtins with constant arguments are replaced by their
+///   value,
+/// - an `if` with a constant condition is replaced by the branch it takes,
+/// - a `progn` inside a `progn` is spliced into the outer one.
+///
+/// Which function a call refers to is looked up in the manager when the form
+/// is optimized. Folding stops at the first call that may change the
+/// functions, that is any call that is not to a pure builtin, so a call to a
+/// builtin the user redefined is never folded. Bodies of functions run later,
+/// so nothing in them is folded.
+pub fn optimize(obj: LispObject, manager: &mut Manager) -> LispObject {
+    Optimizer::new(manager).optimize(obj)
+}
+
+/// Optimizes a sequence of forms, which are evaluated in order.
+pub struct Optimizer<'a> {
+    manager: &'a mut Manager,
+    /// Whether the functions are still the ones in the manager. Calls are
+    /// only folded, and their arguments only rewritten, while this holds.
+    known: bool,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn new(manager: &'a mut Manager) -> Self {
+        Self {
+            manager,
+            known: true,
+        }
+    }
+
+    pub fn optimize(&mut self, obj: LispObject) -> LispObject {
+        self.form(obj, 0)
+    }
+
+    fn form(&mut self, obj: LispObject, nesting: usize) -> LispObject {
+        if obj.is_quoted() || nesting > MAX_NESTING {
+            return obj;
+        }
+        let name = match obj.as_list() {
+            Some([head, ..]) => match head.as_symbol() {
+                Some(name) => name.to_string(),
+                None => {
+                    self.known = false;
+                    return obj;
+                }
+            },
+            _ => return obj,
+        };
+
+        let span = obj.span();
+        let nesting = nesting + 1;
+        let mut list = obj.into_list().unwrap();
+        let args = list.split_off(1);
+        match name.as_str() {
+            "quote" | "define-condition" => list.extend(args),
+            "progn" => {
+                let mut body = vec![];
+                for arg in args {
+                    let arg = self.form(arg, nesting);
+                    match head_name(&arg) {
+                        Some("progn") => body.extend(arg.into_list().unwrap().into_iter().skip(1)),
+                        _ => body.push(arg),
+                    }
+                }
+                match body.len() {
+                    0 => return LispObject::nil(),
+                    1 => return body.pop().unwrap(),
+                    _ => {}
+                }
+                list.append(&mut body);
+            }
+            "if" if args.len() >= 2 => {
+                let mut args = args.into_iter();
+                let condition = self.form(args.next().unwrap(), nesting);
+                let then = args.next().unwrap();
+                let otherwise = args.collect::<Vec<LispObject>>();
+                if let Some(value) = constant_value(&condition) {
+                    return if value.is_true() {
+                        self.form(then, nesting)
+                    } else {
+                        let mut body = vec![LispObject::symbol("progn")];
+                        body.extend(otherwise);
+                        self.form(rebuild(body, span), nesting)
+                    };
+                }
+                // Either branch may run, so the functions are only known
+                // afterwards if both leave them alone.
+                let known = self.known;
+                list.push(condition);
+                list.push(self.form(then, nesting));
+                let known_after_then = self.known;
+                self.known = known;
+                for form in otherwise {
+                    list.push(self.form(form, nesting));
+                }
+                self.known &= known_after_then;
+            }
+            // A lambda is only called later.
+            "lambda" | "function" => {
+                let known = self.known;
+                list.extend(self.nested(args, 1, nesting));
+                self.known = known;
+            }
+            _ if SPECIAL_FORMS.contains(&name.as_str()) => {
+                let skip = match name.as_str() {
+                    "defun" | "defmacro" => 2,
+                    _ => 0,
+                };
+                list.extend(self.nested(args, skip, nesting));
+                self.known = false;
+            }
+            _ => return self.call(&name, list, args, span, nesting),
+        }
+        rebuild(list, span)
+    }
+
+    /// Optimizes the subforms of a special form, except for the first `skip`
+    /// ones, without folding anything.
+    fn nested(&mut self, args: Vec<LispObject>, skip: usize, nesting: usize) -> Vec<LispObject> {
+        args.into_iter()
+            .enumerate()
+            .map(|(i, arg)| {
+                self.known = false;
+                if i < skip {
+                    arg
+                } else {
+                    self.form(arg, nesting)
+                }
+            })
+            .collect()
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        mut list: Vec<LispObject>,
+        args: Vec<LispObject>,
+        span: Option<Span>,
+        nesting: usize,
+    ) -> LispObject {
+        // The arguments of a macro are not code, and once the functions are
+        // unknown any call may be a macro.
+        let builtin = match lookup_function(name, self.manager) {
+            Ok(func) if self.known => match func.ltype() {
+                LispType::Function(f) => match &**f {
+                    Function::Macro(_) => None,
+                    Function::Builtin(builtin) => Some(Some(builtin.clone())),
+                    Function::Lambda(_) => Some(None),
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let builtin = match builtin {
+            Some(builtin) => builtin,
+            None => {
+                self.known = false;
+                list.extend(args);
+                return rebuild(list, span);
+            }
+        };
+
+        let args = args
+            .into_iter()
+            .map(|arg| self.form(arg, nesting))
+            .collect::<Vec<LispObject>>();
+        match builtin {
+            Some(builtin) if PURE_BUILTINS.contains(&builtin.as_str()) => {
+                if self.known {
+                    if let Some(folded) = fold(&builtin, &args) {
+                        return match span {
+                            Some(span) => folded.with_span(span),
+                            None => folded,
+                        };
+                    }
+                }
+            }
+            _ => self.known = false,
+        }
+        list.extend(args);
+        rebuild(list, span)
+    }
+}
+
+/// The value of a call to a pure builtin as a form, if all arguments are
+/// constants and the call succeeds. Failing calls are left for the
+/// evaluator, which reports the error where it happens.
+fn fold(builtin: &str, args: &[LispObject]) -> Option<LispObject> {
+    let values = args
+        .iter()
+        .map(constant_value)
+        .collect::<Option<Vec<LispObject>>>()?;
+    let value = call_builtin(builtin, &values).ok()?;
+    match value.ltype() {
+        LispType::Integer(_)
+        | LispType::Number(_)
+        | LispType::String(_)
+        | LispType::Bool(_)
+        | LispType::Vector(_) => Some(value),
+        LispType::Symbol(_) | LispType::List(_) | LispType::Cons(_) => Some(value.move_quoted()),
+        LispType::Function(_) | LispType::Condition(_) | LispType::Regex(_) => None,
+    }
+}
+
+/// The value a form evaluates to, if it does not depend on anything.
+fn constant_value(obj: &LispObject) -> Option<LispObject> {
+    if obj.is_quoted() {
+        return Some(obj.clone());
+    }
+    match obj.ltype() {
+        LispType::Symbol(_) => None,
+        LispType::List(l) if !l.is_empty() => None,
+        LispType::List(_) => Some(LispObject::nil()),
+        ltype => Some(LispObject::new_with(ltype.clone(), false)),
+    }
+}
+
+fn head_name(obj: &LispObject) -> Option<&str> {
+    if obj.is_quoted() {
+        return None;
+    }
+    obj.as_list()?.first()?.as_symbol()
+}
+
+fn rebuild(list: Vec<LispObject>, span: Option<Span>) -> LispObject {
+    let obj = LispObject::new_with(LispType::list(list), false);
+    match span {
+        Some(span) => obj.with_span(span),
+        None => obj,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ast;
+    use crate::evaluator::eval;
+
+    /// Optimizes and evaluates the forms and returns the last optimized one.
+    /// Errors are ignored; only the definitions matter to later forms.
+    fn optimize_str(code: &str, manager: &mut Manager) -> String {
+        let mut res = String::new();
+        for form in ast(code).unwrap() {
+            let form = optimize(form, manager);
+            res = form.to_string();
+            eval(form, manager).ok();
+        }
+        res
+    }
+
+    #[test]
+    fn test_optimize_folds_builtins() {
+        let mut manager = Manager::default();
+        assert_eq!(optimize_str("(+ 1 (add 2 3))", &mut manager), "6");
+        assert_eq!(optimize_str("(list 1 'a nil)", &mut manager), "'(1 'a nil)");
+        assert_eq!(optimize_str("(+ 1 x)", &mut manager), "(+ 1 x)");
+        assert_eq!(
+            optimize_str("(quote (add 1 2))", &mut manager),
+            "(quote (add 1 2))"
+        );
+        // Errors are left for the evaluator.
+        assert_eq!(
+            optimize_str("(list (add 1 'a))", &mut manager),
+            "(list (add 1 'a))"
+        );
+    }
+
+    #[test]
+    fn test_optimize_branches_and_progn() {
+        let mut manager = Manager::default();
+        optimize_str("(defun f () 1) (defun g () 2) (defun h () 3)", &mut manager);
+        assert_eq!(optimize_str("(if t (f) (g))", &mut manager), "(f)");
+        assert_eq!(
+            optimize_str("(if () (f) (g) (h))", &mut manager),
+            "(progn (g) (h))"
+        );
+        assert_eq!(optimize_str("(if nil (f))", &mut manager), "nil");
+        assert_eq!(
+            optimize_str("(progn (f) (progn (g) (progn (h))) x)", &mut manager),
+            "(progn (f) (g) (h) x)"
+        );
+        // Function bodies are simplified, but not folded.
+        assert_eq!(
+            optimize_str("(defun f () (if t (add 1 2)))", &mut manager),
+            "(defun f nil (add 1 2))"
+        );
+    }
+
+    #[test]
+    fn test_optimize_respects_redefinitions() {
+        let mut manager = Manager::default();
+        optimize_str("(defun add (a b) (list a b))", &mut manager);
+        assert_eq!(optimize_str("(add 1 2)", &mut manager), "(add 1 2)");
+        // A call may redefine a builtin before the rest of the form runs.
+        let code = "(progn (list 1) (defun + (a b) a) (+ 1 2))";
+        assert_eq!(
+            optimize_str(code, &mut manager),
+            "(progn '(1) (defun + (a b) a) (+ 1 2))"
+        );
+        // The arguments of a macro are passed on as they are.
+        optimize_str("(defmacro m (x) x)", &mut manager);
+        assert_eq!(
+            optimize_str("(m (if t 1 2))", &mut manager),
+            "(m (if t 1 2))"
+        );
+    }
+}
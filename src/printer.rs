@@ -0,0 +1,592 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::error::LispError;
+use crate::functions::arity;
+use crate::lispobject::{format_float, LispObject, LispType};
+
+/// How objects are printed. `Display` prints objects as code and
+/// `get_string` like `princ`, both without labels for shared structure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Printer {
+    /// Whether objects are printed so the reader reads them back, which puts
+    /// strings in quotes.
+    pub escape: bool,
+    /// Whether quoted objects are printed with a quote, as the code that
+    /// evaluates to them. The print builtins leave the quotes out.
+    pub quote: bool,
+    /// Whether lists and vectors that occur more than once are labelled with
+    /// `#1=` and referred to with `#1#`. Objects can not be changed once they
+    /// are built, so they can share structure but never contain themselves.
+    pub circle: bool,
+    /// The width of the lines the pretty printer fills. Without a width
+    /// everything is printed on one line.
+    pub width: Option<usize>,
+}
+
+/// The width `pprint` fills.
+pub const LINE_WIDTH: usize = 80;
+
+impl Printer {
+    pub const CODE: Printer = Printer {
+        escape: true,
+        quote: true,
+        circle: false,
+        width: None,
+    };
+
+    pub const PRIN1: Printer = Printer {
+        escape: true,
+        quote: false,
+        circle: false,
+        width: None,
+    };
+
+    pub const PRINC: Printer = Printer {
+        escape: false,
+        quote: false,
+        circle: false,
+        width: None,
+    };
+
+    pub fn with_circle(self) -> Self {
+        Self {
+            circle: true,
+            ..self
+        }
+    }
+
+    pub fn with_width(self, width: usize) -> Self {
+        Self {
+            width: Some(width),
+            ..self
+        }
+    }
+
+    pub fn print(&self, obj: &LispObject) -> String {
+        self.print_with(obj.ltype(), quote(self.quote, obj))
+    }
+
+    /// Prints the object without the quote in front of it.
+    pub fn print_type(&self, obj: &LispType) -> String {
+        self.print_with(obj, "")
+    }
+
+    fn print_with(&self, obj: &LispType, quote: &str) -> String {
+        let mut builder = Builder {
+            escape: self.escape,
+            quote: self.quote,
+            labelled: self.find_labels(obj),
+            numbers: HashMap::new(),
+            tokens: vec![],
+        };
+        builder.build(obj, quote);
+        let mut out = String::new();
+        layout(&builder.tokens, self.width, &mut out);
+        out
+    }
+
+    /// Walks the object and collects the lists, vectors and cons pairs that
+    /// are reached more than once, which need a label with `circle`. The
+    /// walk uses a work list, so deep nesting does not overflow the stack.
+    fn find_labels(&self, obj: &LispType) -> HashSet<*const ()> {
+        let mut labelled = HashSet::new();
+        if !self.circle {
+            return labelled;
+        }
+        let mut visited = HashSet::new();
+        let mut work = vec![obj];
+        while let Some(obj) = work.pop() {
+            let Some(key) = identity(obj) else { continue };
+            if !visited.insert(key) {
+                labelled.insert(key);
+                continue;
+            }
+            match obj {
+                LispType::Cons(c) => {
+                    work.push(c.1.ltype());
+                    work.push(c.0.ltype());
+                }
+                _ => {
+                    let elements = obj.elements().unwrap();
+                    work.extend(elements.iter().rev().map(|e| e.ltype()));
+                }
+            }
+        }
+        labelled
+    }
+}
+
+fn quote(quote: bool, obj: &LispObject) -> &'static str {
+    if quote && obj.is_quoted() {
+        "'"
+    } else {
+        ""
+    }
+}
+
+/// The address of a non-empty list, vector or cons pair, which tells if two
+/// objects are the same.
+fn identity(obj: &LispType) -> Option<*const ()> {
+    match obj {
+        LispType::List(_) | LispType::Vector(_) => {
+            let elements = obj.elements().unwrap();
+            (!elements.is_empty()).then_some(elements.as_ptr() as _)
+        }
+        LispType::Cons(c) => Some(Rc::as_ptr(c) as _),
+        _ => None,
+    }
+}
+
+/// The printed object before it is broken into lines.
+enum Token {
+    Text(String),
+    /// Starts a list or vector. `open` is the paren, with the label and the
+    /// `#` of vectors in front of it. If the first item is a symbol the list
+    /// is printed like a call, with the arguments lined up after it. `len`
+    /// is the length of the block on one line and `items` the number of
+    /// items in it, both filled in when the block is closed.
+    Open {
+        open: String,
+        call: bool,
+        len: usize,
+        items: usize,
+    },
+    Close,
+}
+
+/// Fills in the lengths and item counts of the blocks.
+fn measure(tokens: &mut [Token]) {
+    // The open blocks with their index, the length of their items so far
+    // and the number of items.
+    let mut blocks: Vec<(usize, usize, usize)> = vec![];
+    for i in 0..tokens.len() {
+        let len = match &mut tokens[i] {
+            Token::Text(s) => s.chars().count(),
+            Token::Open { .. } => {
+                blocks.push((i, 0, 0));
+                continue;
+            }
+            Token::Close => {
+                let (start, len, count) = blocks.pop().unwrap();
+                let Token::Open {
+                    open,
+                    len: l,
+                    items,
+                    ..
+                } = &mut tokens[start]
+                else {
+                    unreachable!()
+                };
+                // The items are separated by spaces and followed by the paren.
+                *l = open.chars().count() + len + count.max(1);
+                *items = count;
+                *l
+            }
+        };
+        if let Some((_, sum, count)) = blocks.last_mut() {
+            *sum += len;
+            *count += 1;
+        }
+    }
+}
+
+/// A block that is being written.
+struct Block {
+    fits_line: bool,
+    /// Whether the head is printed on its own, followed by the arguments.
+    call: bool,
+    indent: usize,
+    /// The number of items written so far.
+    seen: usize,
+}
+
+/// Writes the tokens. The items of a block that does not fit in the width
+/// fill the lines, lined up after the paren or, in a call, after the head.
+fn layout(tokens: &[Token], width: Option<usize>, out: &mut String) {
+    let fits = |column: usize, len: usize| width.is_none_or(|w| column + len <= w);
+    let mut blocks: Vec<Block> = vec![];
+    let mut column = 0;
+    for token in tokens {
+        let len = match token {
+            Token::Text(s) => s.chars().count(),
+            Token::Open { len, .. } => *len,
+            Token::Close => {
+                blocks.pop();
+                out.push(')');
+                column += 1;
+                end_item(&mut blocks, &mut column, out);
+                continue;
+            }
+        };
+        if let Some(block) = blocks.last() {
+            // The first argument of a call follows the space after the head.
+            let first = if block.call { 2 } else { 1 };
+            if block.seen >= first && (block.fits_line || fits(column + 1, len)) {
+                out.push(' ');
+                column += 1;
+            } else if block.seen >= first {
+                out.push('\n');
+                out.push_str(&" ".repeat(block.indent));
+                column = block.indent;
+            }
+        }
+        match token {
+            Token::Text(s) => {
+                out.push_str(s);
+                column += len;
+                end_item(&mut blocks, &mut column, out);
+            }
+            Token::Open {
+                open, call, items, ..
+            } => {
+                let fits_line = fits(column, len);
+                out.push_str(open);
+                column += open.chars().count();
+                blocks.push(Block {
+                    fits_line,
+                    call: *call && *items > 1,
+                    indent: column,
+                    seen: 0,
+                });
+            }
+            Token::Close => unreachable!(),
+        }
+    }
+}
+
+/// Counts an item of the innermost block. After the head of a call comes
+/// a space and the arguments are lined up after it.
+fn end_item(blocks: &mut [Block], column: &mut usize, out: &mut String) {
+    let Some(block) = blocks.last_mut() else {
+        return;
+    };
+    if block.call && block.seen == 0 {
+        out.push(' ');
+        *column += 1;
+        block.indent = *column;
+    }
+    block.seen += 1;
+}
+
+struct Builder {
+    escape: bool,
+    quote: bool,
+    labelled: HashSet<*const ()>,
+    /// The labels that were printed so far.
+    numbers: HashMap<*const (), usize>,
+    tokens: Vec<Token>,
+}
+
+/// What is left to build, kept on a work list so deep nesting does not
+/// overflow the stack.
+enum Task<'a> {
+    Object(&'a LispObject),
+    /// The rest of a chain of cons pairs after the car.
+    Rest(&'a LispObject),
+    Close,
+}
+
+impl Builder {
+    /// Builds the tokens of the object, with `quote` in front of it.
+    fn build(&mut self, obj: &LispType, mark: &str) {
+        let mut work = vec![];
+        self.build_type(obj, mark, &mut work);
+        while let Some(task) = work.pop() {
+            match task {
+                Task::Object(obj) => {
+                    self.build_type(obj.ltype(), quote(self.quote, obj), &mut work)
+                }
+                Task::Rest(cdr) => self.build_rest(cdr, &mut work),
+                Task::Close => self.tokens.push(Token::Close),
+            }
+        }
+        measure(&mut self.tokens);
+    }
+
+    /// The label of a shared object goes in front of its quote, as in
+    /// `#1='(a b)`.
+    fn build_type<'a>(&mut self, obj: &'a LispType, quote: &str, work: &mut Vec<Task<'a>>) {
+        let mut label = String::new();
+        if let Some(key) = identity(obj).filter(|key| self.labelled.contains(key)) {
+            if let Some(n) = self.numbers.get(&key) {
+                self.tokens.push(Token::Text(format!("{}#{}#", quote, n)));
+                return;
+            }
+            let n = self.numbers.len() + 1;
+            self.numbers.insert(key, n);
+            label = format!("#{}=", n);
+        }
+        let prefix = label + quote;
+        let open = match obj {
+            LispType::List(l) if !l.is_empty() => "(",
+            LispType::Vector(_) => "#(",
+            LispType::Cons(_) => "(",
+            _ => {
+                self.tokens.push(Token::Text(prefix + &self.atom(obj)));
+                return;
+            }
+        };
+        self.tokens.push(Token::Open {
+            open: prefix + open,
+            call: first_is_symbol(obj),
+            len: 0,
+            items: 0,
+        });
+        work.push(Task::Close);
+        match obj {
+            LispType::Cons(c) => {
+                work.push(Task::Rest(&c.1));
+                work.push(Task::Object(&c.0));
+            }
+            _ => work.extend(obj.elements().unwrap().iter().rev().map(Task::Object)),
+        }
+    }
+
+    /// The items after the car of a cons pair. A chain that does not end in
+    /// nil or a list ends with a dot and its last cdr.
+    fn build_rest<'a>(&mut self, cdr: &'a LispObject, work: &mut Vec<Task<'a>>) {
+        let labelled = identity(cdr.ltype()).is_some_and(|key| self.labelled.contains(&key));
+        match cdr.ltype() {
+            LispType::Bool(false) => {}
+            LispType::List(l) if !labelled => work.extend(l.iter().rev().map(Task::Object)),
+            LispType::Cons(c) if !labelled => {
+                work.push(Task::Rest(&c.1));
+                work.push(Task::Object(&c.0));
+            }
+            _ => {
+                self.tokens.push(Token::Text(".".to_string()));
+                work.push(Task::Object(cdr));
+            }
+        }
+    }
+
+    fn atom(&self, ltype: &LispType) -> String {
+        match ltype {
+            LispType::Integer(n) => n.to_string(),
+            LispType::Number(n) => format_float(*n),
+            LispType::String(s) if self.escape => format!("\"{}\"", escape(s)),
+            LispType::Symbol(s) | LispType::String(s) => s.clone(),
+            LispType::Bool(true) => "t".to_string(),
+            // The empty list is nil.
+            LispType::Bool(false) | LispType::List(_) => "nil".to_string(),
+            LispType::Function(f) => f.to_string(),
+            LispType::Condition(c) => format!("#<condition {}>", c),
+            LispType::Regex(r) => r.to_string(),
+            LispType::Vector(_) | LispType::Cons(_) => unreachable!(),
+        }
+    }
+}
+
+/// Whether the object is a list that starts with a symbol.
+fn first_is_symbol(obj: &LispType) -> bool {
+    let first = match obj {
+        LispType::List(l) => l.first(),
+        LispType::Cons(c) => Some(&c.0),
+        _ => None,
+    };
+    first.is_some_and(|f| f.as_symbol().is_some())
+}
+
+/// Writes a string so that the reader reads it back unchanged.
+fn escape(s: &str) -> String {
+    let mut res = String::new();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+/// Writes to standard output and flushes it, so text without a newline
+/// shows up right away.
+pub fn write_stdout(s: &str) -> Result<(), LispError> {
+    write_out(&mut std::io::stdout(), s)
+}
+
+/// Writes to `out` and flushes it.
+pub fn write_out(out: &mut impl Write, s: &str) -> Result<(), LispError> {
+    out.write_all(s.as_bytes())
+        .and_then(|_| out.flush())
+        .map_err(|e| LispError::runtime_error(e.to_string()))
+}
+
+/// Prints an object so the reader reads it back.
+pub fn prin1(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("prin1", args, 1, 1)?;
+    write_stdout(&Printer::PRIN1.with_circle().print(&args[0]))?;
+    Ok(args[0].clone())
+}
+
+/// Prints an object for people to read, strings without quotes.
+pub fn princ(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("princ", args, 1, 1)?;
+    write_stdout(&Printer::PRINC.with_circle().print(&args[0]))?;
+    Ok(args[0].clone())
+}
+
+/// Prints an object like `prin1`, followed by a newline.
+pub fn print(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("print", args, 1, 1)?;
+    write_stdout(&(Printer::PRIN1.with_circle().print(&args[0]) + "\n"))?;
+    Ok(args[0].clone())
+}
+
+/// Prints an object like `print`, broken into lines of `LINE_WIDTH`.
+pub fn pprint(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("pprint", args, 1, 1)?;
+    let printer = Printer::PRIN1.with_circle().with_width(LINE_WIDTH);
+    write_stdout(&(printer.print(&args[0]) + "\n"))?;
+    Ok(LispObject::nil())
+}
+
+pub fn prin1_to_string(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("prin1-to-string", args, 1, 1)?;
+    Ok(LispObject::string(
+        Printer::PRIN1.with_circle().print(&args[0]),
+    ))
+}
+
+pub fn princ_to_string(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("princ-to-string", args, 1, 1)?;
+    Ok(LispObject::string(
+        Printer::PRINC.with_circle().print(&args[0]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ast;
+
+    fn read(code: &str) -> LispObject {
+        ast(code).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_printers() {
+        let pair = LispObject::cons(LispObject::integer(1), LispObject::string("b"));
+        let chain = LispObject::cons(LispObject::symbol("a"), pair.clone());
+        let onto_list = LispObject::cons(LispObject::integer(0), read("(1 2)"));
+        let cases = [
+            (read("\"a\\\"b\\n\""), "\"a\\\"b\\n\"", "a\"b\n"),
+            (
+                read("(1 2.0 \"s\" #(t nil))"),
+                "(1 2.0 \"s\" #(t nil))",
+                "(1 2.0 s #(t nil))",
+            ),
+            (pair, "(1 . \"b\")", "(1 . b)"),
+            (chain, "(a 1 . \"b\")", "(a 1 . b)"),
+            (onto_list, "(0 1 2)", "(0 1 2)"),
+            (LispObject::list(&[]), "nil", "nil"),
+            (read("'a"), "a", "a"),
+        ];
+        for (obj, prin1, princ) in cases {
+            assert_eq!(Printer::PRIN1.print(&obj), prin1);
+            assert_eq!(Printer::PRINC.print(&obj), princ);
+            assert_eq!(obj.get_string(), princ);
+        }
+        assert_eq!(read("'a").to_string(), "'a");
+        assert_eq!(read("('(1) 'b)").to_string(), "('(1) 'b)");
+    }
+
+    #[test]
+    fn test_print_shared_structure() {
+        let shared = read("(1 2)");
+        let vector = LispObject::vector(vec![shared.clone()]);
+        let obj = LispObject::list(&[shared.clone(), vector.clone(), shared, vector]);
+        let circle = Printer::PRIN1.with_circle();
+        assert_eq!(circle.print(&obj), "(#1=(1 2) #2=#(#1#) #1# #2#)");
+        assert_eq!(
+            Printer::PRIN1.print(&obj),
+            "((1 2) #((1 2)) (1 2) #((1 2)))"
+        );
+
+        // The shared cdr of a cons pair is printed after a dot.
+        let tail = LispObject::cons(LispObject::integer(2), LispObject::integer(3));
+        let obj = LispObject::list(&[LispObject::cons(LispObject::integer(1), tail.clone()), tail]);
+        assert_eq!(circle.print(&obj), "((1 . #1=(2 . 3)) #1#)");
+
+        // Equal objects that are not the same are not labelled.
+        assert_eq!(circle.print(&read("((1) (1))")), "((1) (1))");
+    }
+
+    #[test]
+    fn test_print_read_back() {
+        let cases = [
+            "(a (b . c) \"d\\\\\" #(1 2.5) (e f . g))",
+            "(#1=(x) #1# #2=#(#1#) #2#)",
+            "((a . #1=(b . c)) #1#)",
+            "(1e17 -2.5e20 1.7976931348623157e308 1.0 0.5 -3.0)",
+        ];
+        let circle = Printer::PRIN1.with_circle();
+        for code in cases {
+            let obj = read(code);
+            let printed = circle.print(&obj);
+            assert_eq!(printed, code);
+            assert!(read(&printed).equal(&obj), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_print_deep_nesting() {
+        let depth = 20_000;
+        let nest = || {
+            let mut obj = LispObject::nil();
+            for _ in 0..depth {
+                obj = LispObject::list(&[obj]);
+            }
+            obj
+        };
+        let obj = nest();
+        let expected = "(".repeat(depth) + "nil" + &")".repeat(depth);
+        assert_eq!(Printer::PRIN1.print(&obj), expected);
+        assert_eq!(Printer::PRIN1.with_circle().print(&obj), expected);
+        assert_eq!(Printer::PRIN1.with_width(LINE_WIDTH).print(&obj), expected);
+        assert!(obj.equal(&nest()));
+    }
+
+    #[test]
+    fn test_pprint() {
+        let code = "(defun f (a b) (let ((x (+ a b)) (y (* a b))) (list x y)))";
+        let cases = [
+            (80, code.to_string()),
+            (
+                40,
+                [
+                    "(defun f (a b)",
+                    "       (let ((x (+ a b)) (y (* a b)))",
+                    "            (list x y)))",
+                ]
+                .join("\n"),
+            ),
+            (
+                28,
+                [
+                    "(defun f (a b)",
+                    "       (let ((x (+ a b))",
+                    "             (y (* a b)))",
+                    "            (list x y)))",
+                ]
+                .join("\n"),
+            ),
+        ];
+        for (width, expected) in cases {
+            assert_eq!(
+                Printer::PRIN1.with_width(width).print(&read(code)),
+                expected
+            );
+        }
+        let numbers = read("(1 22 333 4444 55555 666666)");
+        let expected = "(1 22 333\n 4444 55555\n 666666)";
+        assert_eq!(Printer::PRIN1.with_width(12).print(&numbers), expected);
+    }
+}
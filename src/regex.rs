@@ -0,0 +1,970 @@
+use std::fmt::{self, Display, Formatter};
+use std::rc::Rc;
+
+use crate::error::{ErrorType, LispError};
+use crate::functions::{arity, make_list};
+use crate::lispobject::{LispObject, LispType};
+use crate::resolver::MAX_NESTING;
+use crate::strings::string_arg;
+
+/// Repetition counts above this are rejected, because `{n,m}` is compiled by
+/// copying the repeated expression.
+const MAX_REPEAT: usize = 1000;
+
+/// Patterns that compile to more instructions than this are rejected. Nested
+/// repetitions multiply, so the count alone does not bound the program.
+const MAX_PROGRAM: usize = 1 << 16;
+
+/// The matcher marks every pair of instruction and text position it tries,
+/// and refuses texts that would need more marks than this.
+const MAX_STATES: usize = 1 << 25;
+
+/// A compiled regular expression.
+///
+/// The supported syntax is: literal characters, `.`, classes like `[a-z]`
+/// and `[^0-9]`, the escapes `\d \w \s \D \W \S \b \B \n \t`, the anchors
+/// `^` and `$`, capturing groups `(...)`, non-capturing groups `(?:...)`,
+/// alternation `|` and the quantifiers `* + ? {n} {n,} {n,m}`, which are
+/// non-greedy when followed by `?`. Positions are counted in characters.
+#[derive(Debug)]
+pub struct Regex {
+    source: String,
+    program: Vec<Inst>,
+    classes: Vec<Class>,
+    groups: usize,
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Display for Regex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#<regex {:?}>", self.source)
+    }
+}
+
+/// An instruction of the matcher. Jump targets are indices into the program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Inst {
+    Char(char),
+    /// Any character except a newline.
+    Any,
+    Class(usize),
+    Start,
+    End,
+    /// A word boundary, or with `false` a position that is not one.
+    Boundary(bool),
+    /// Continues at the first target and backtracks to the second.
+    Split(usize, usize),
+    Jump(usize),
+    /// Saves the position in a capture slot.
+    Save(usize),
+    Match,
+}
+
+/// A character class. A character matches if it is in one of the items,
+/// or in none of them if the class is negated.
+#[derive(Debug, Clone, PartialEq)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassItem {
+    Range(char, char),
+    /// `\d`, `\w` and `\s`, or their negations.
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(from, to) => from <= c && c <= to,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => is_word(c) != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        self.items.iter().any(|item| item.matches(c)) != self.negated
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The parsed form of a pattern.
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Boundary(bool),
+    /// A group, which captures into the slots of its index if it has one.
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+/// Parses a pattern. The parse functions return a node with its height,
+/// the number of groups and repetitions it nests, which is limited to
+/// `MAX_NESTING` because the parser and the compiler recurse on them.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+    /// The number of groups that are open.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at position {}", message, self.pos)
+    }
+
+    /// The height of a node that nests one of height `height`.
+    fn nest(&self, height: usize) -> Result<usize, String> {
+        match height < MAX_NESTING {
+            true => Ok(height + 1),
+            false => Err(self.error("Regex is nested too deeply")),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<(Node, usize), String> {
+        let (node, mut height) = self.concat()?;
+        let mut branches = vec![node];
+        while self.eat('|') {
+            let (node, h) = self.concat()?;
+            branches.push(node);
+            height = height.max(h);
+        }
+        let node = match branches.len() {
+            1 => branches.pop().unwrap(),
+            _ => Node::Alt(branches),
+        };
+        Ok((node, height))
+    }
+
+    fn concat(&mut self) -> Result<(Node, usize), String> {
+        let mut nodes = vec![];
+        let mut height = 0;
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let (atom, h) = self.atom()?;
+            let (node, h) = self.quantifiers(atom, h)?;
+            nodes.push(node);
+            height = height.max(h);
+        }
+        Ok((Node::Concat(nodes), height))
+    }
+
+    fn quantifiers(&mut self, mut node: Node, mut height: usize) -> Result<(Node, usize), String> {
+        loop {
+            let start = self.pos;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.counts()? {
+                    Some(counts) => counts,
+                    None => return Ok((node, height)),
+                },
+                _ => return Ok((node, height)),
+            };
+            height = self.nest(height)?;
+            if self.pos == start {
+                self.pos += 1;
+            }
+            let greedy = !self.eat('?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    /// Parses `{n}`, `{n,}` or `{n,m}`. A brace that does not start one of
+    /// them is a literal character.
+    fn counts(&mut self) -> Result<Option<(usize, Option<usize>)>, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let min = self.number();
+        let max = if self.eat(',') { self.number() } else { min };
+        let min = match min {
+            Some(min) if self.eat('}') => min,
+            _ => {
+                self.pos = start;
+                return Ok(None);
+            }
+        };
+        if min.max(max.unwrap_or(0)) > MAX_REPEAT {
+            return Err(self.error("Repetition count is too large"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("Repetition counts are out of order"));
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits.parse().ok()
+    }
+
+    fn atom(&mut self) -> Result<(Node, usize), String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        let node = match c {
+            '(' => {
+                self.nest(self.depth)?;
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err(self.error("Unknown group type"));
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                self.depth += 1;
+                let (node, height) = self.alternation()?;
+                self.depth -= 1;
+                if !self.eat(')') {
+                    return Err(self.error("Unclosed group"));
+                }
+                return Ok((Node::Group(Box::new(node), index), self.nest(height)?));
+            }
+            ')' => return Err(self.error("Unmatched )")),
+            '*' | '+' | '?' => return Err(self.error("Nothing to repeat")),
+            '[' => self.class()?,
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '\\' => match self.escape()? {
+                Escape::Char(c) => Node::Char(c),
+                Escape::Class(item) => Node::Class(Class {
+                    negated: false,
+                    items: vec![item],
+                }),
+                Escape::Boundary(b) => Node::Boundary(b),
+            },
+            c => Node::Char(c),
+        };
+        Ok((node, 0))
+    }
+
+    /// Parses the escape after a backslash.
+    fn escape(&mut self) -> Result<Escape, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error("Trailing backslash")),
+        };
+        self.pos += 1;
+        Ok(match c {
+            'd' | 'D' => Escape::Class(ClassItem::Digit(c == 'D')),
+            'w' | 'W' => Escape::Class(ClassItem::Word(c == 'W')),
+            's' | 'S' => Escape::Class(ClassItem::Space(c == 'S')),
+            'b' | 'B' => Escape::Boundary(c == 'b'),
+            'n' => Escape::Char('\n'),
+            't' => Escape::Char('\t'),
+            'r' => Escape::Char('\r'),
+            c => Escape::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut items = vec![];
+        let mut first = true;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("Unclosed character class")),
+            };
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let from = match c {
+                '\\' => match self.escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(item) => {
+                        items.push(item);
+                        continue;
+                    }
+                    Escape::Boundary(_) => 'b',
+                },
+                c => c,
+            };
+            // A dash before the closing bracket is literal.
+            if self.peek() == Some('-') && !matches!(self.chars.get(self.pos + 1), Some(']') | None)
+            {
+                self.pos += 1;
+                let to = match self.peek() {
+                    Some('\\') => {
+                        self.pos += 1;
+                        match self.escape()? {
+                            Escape::Char(c) => c,
+                            _ => return Err(self.error("Invalid range")),
+                        }
+                    }
+                    Some(c) => {
+                        self.pos += 1;
+                        c
+                    }
+                    None => return Err(self.error("Unclosed character class")),
+                };
+                if to < from {
+                    return Err(self.error("Range is out of order"));
+                }
+                items.push(ClassItem::Range(from, to));
+            } else {
+                items.push(ClassItem::Range(from, from));
+            }
+        }
+        Ok(Node::Class(Class { negated, items }))
+    }
+}
+
+enum Escape {
+    Char(char),
+    Class(ClassItem),
+    Boundary(bool),
+}
+
+/// Turns the parsed pattern into instructions.
+struct Compiler {
+    program: Vec<Inst>,
+    classes: Vec<Class>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> usize {
+        self.program.push(inst);
+        self.program.len() - 1
+    }
+
+    /// Points the targets of the split or jump at `at` that are still 0 at
+    /// the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.program.len();
+        match &mut self.program[at] {
+            Inst::Jump(t) => *t = target,
+            Inst::Split(a, b) => {
+                if *a == 0 {
+                    *a = target;
+                } else {
+                    *b = target;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// A split that prefers the next instruction if `greedy`, and the one
+    /// it is patched to otherwise.
+    fn split(&mut self, greedy: bool) -> usize {
+        let next = self.program.len() + 1;
+        match greedy {
+            true => self.emit(Inst::Split(next, 0)),
+            false => self.emit(Inst::Split(0, next)),
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        if self.program.len() > MAX_PROGRAM {
+            return;
+        }
+        match node {
+            Node::Char(c) => {
+                self.emit(Inst::Char(*c));
+            }
+            Node::Any => {
+                self.emit(Inst::Any);
+            }
+            Node::Class(class) => {
+                self.classes.push(class.clone());
+                self.emit(Inst::Class(self.classes.len() - 1));
+            }
+            Node::Start => {
+                self.emit(Inst::Start);
+            }
+            Node::End => {
+                self.emit(Inst::End);
+            }
+            Node::Boundary(b) => {
+                self.emit(Inst::Boundary(*b));
+            }
+            Node::Group(node, index) => {
+                if let Some(index) = index {
+                    self.emit(Inst::Save(2 * index));
+                }
+                self.node(node);
+                if let Some(index) = index {
+                    self.emit(Inst::Save(2 * index + 1));
+                }
+            }
+            Node::Concat(nodes) => nodes.iter().for_each(|node| self.node(node)),
+            Node::Alt(branches) => {
+                let mut ends = vec![];
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let split = self.split(true);
+                        self.node(branch);
+                        ends.push(self.emit(Inst::Jump(0)));
+                        self.patch(split);
+                    } else {
+                        self.node(branch);
+                    }
+                }
+                ends.into_iter().for_each(|end| self.patch(end));
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.node(node);
+                }
+                match max {
+                    None => {
+                        let split = self.split(*greedy);
+                        self.node(node);
+                        self.emit(Inst::Jump(split));
+                        self.patch(split);
+                    }
+                    Some(max) => {
+                        let mut splits = vec![];
+                        for _ in *min..*max {
+                            splits.push(self.split(*greedy));
+                            self.node(node);
+                        }
+                        splits.into_iter().for_each(|split| self.patch(split));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The capture slots of a match: the start and end of the whole match and
+/// of every group, `None` for groups that did not take part.
+type Slots = Vec<Option<usize>>;
+
+/// Work of the backtracking matcher.
+enum Job {
+    Run(usize, usize),
+    /// Restores a capture slot when backtracking past the `Save` that set it.
+    Restore(usize, Option<usize>),
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, LispError> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+            depth: 0,
+        };
+        let node = parser
+            .alternation()
+            .and_then(|(node, _)| match parser.peek() {
+                Some(_) => Err(parser.error("Unmatched )")),
+                None => Ok(node),
+            });
+        let node = node.map_err(|message| {
+            LispError::new(
+                ErrorType::ParsingError,
+                format!("Invalid regex: {}", message),
+            )
+            .with_object(LispObject::string(pattern))
+        })?;
+        let mut compiler = Compiler {
+            program: vec![],
+            classes: vec![],
+        };
+        compiler.emit(Inst::Save(0));
+        compiler.node(&node);
+        compiler.emit(Inst::Save(1));
+        compiler.emit(Inst::Match);
+        if compiler.program.len() > MAX_PROGRAM {
+            return Err(too_large(pattern, "Regex is too large"));
+        }
+        Ok(Regex {
+            source: pattern.to_string(),
+            program: compiler.program,
+            classes: compiler.classes,
+            groups: parser.groups,
+        })
+    }
+
+    /// Tries to match at `start`. If `full` is set the match has to end at
+    /// the end of the text. `visited` marks the states that were already
+    /// tried; a state that failed once fails again, which keeps the search
+    /// linear in the size of the program and the text.
+    fn run(&self, text: &[char], start: usize, full: bool, visited: &mut Visited) -> Option<Slots> {
+        let mut slots = vec![None; 2 * self.groups + 2];
+        let mut jobs = vec![Job::Run(0, start)];
+        while let Some(job) = jobs.pop() {
+            let (mut pc, pos) = match job {
+                Job::Run(pc, pos) => (pc, pos),
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            loop {
+                if !visited.mark(pc * (text.len() + 1) + pos) {
+                    break;
+                }
+                let c = text.get(pos).copied();
+                match self.program[pc] {
+                    Inst::Char(expected) if c == Some(expected) => {
+                        jobs.push(Job::Run(pc + 1, pos + 1));
+                        break;
+                    }
+                    Inst::Any if c.is_some_and(|c| c != '\n') => {
+                        jobs.push(Job::Run(pc + 1, pos + 1));
+                        break;
+                    }
+                    Inst::Class(i) if c.is_some_and(|c| self.classes[i].matches(c)) => {
+                        jobs.push(Job::Run(pc + 1, pos + 1));
+                        break;
+                    }
+                    Inst::Start if pos == 0 => pc += 1,
+                    Inst::End if pos == text.len() => pc += 1,
+                    Inst::Boundary(b) => {
+                        let before = pos > 0 && is_word(text[pos - 1]);
+                        let after = c.is_some_and(is_word);
+                        if (before != after) != b {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    Inst::Split(first, second) => {
+                        jobs.push(Job::Run(second, pos));
+                        pc = first;
+                    }
+                    Inst::Jump(target) => pc = target,
+                    Inst::Save(slot) => {
+                        jobs.push(Job::Restore(slot, slots[slot]));
+                        slots[slot] = Some(pos);
+                        pc += 1;
+                    }
+                    Inst::Match if !full || pos == text.len() => return Some(slots),
+                    _ => break,
+                }
+            }
+        }
+        None
+    }
+
+    fn visited(&self, text: &[char]) -> Result<Visited, LispError> {
+        match self.program.len().checked_mul(text.len() + 1) {
+            Some(states) if states <= MAX_STATES => Ok(Visited {
+                marks: vec![0; states],
+                search: 1,
+            }),
+            _ => Err(too_large(&self.source, "Regex is too large for the text")),
+        }
+    }
+
+    /// Whether the whole text matches.
+    pub fn is_match(&self, text: &[char]) -> Result<bool, LispError> {
+        Ok(self.run(text, 0, true, &mut self.visited(text)?).is_some())
+    }
+
+    /// The first match.
+    fn search(&self, text: &[char]) -> Result<Option<Slots>, LispError> {
+        Ok(self.search_from(text, 0, &mut self.visited(text)?))
+    }
+
+    /// The first match that starts at `from` or later.
+    fn search_from(&self, text: &[char], from: usize, visited: &mut Visited) -> Option<Slots> {
+        (from..=text.len()).find_map(|start| self.run(text, start, false, visited))
+    }
+
+    /// The matches that do not overlap, from left to right. An empty match
+    /// right after the end of the previous match is skipped.
+    fn matches(&self, text: &[char]) -> Result<Vec<Slots>, LispError> {
+        let mut res: Vec<Slots> = vec![];
+        let mut from = 0;
+        let mut visited = self.visited(text)?;
+        while from <= text.len() {
+            // The states on the path of the previous match are marked too.
+            visited.next_search();
+            let slots = match self.search_from(text, from, &mut visited) {
+                Some(slots) => slots,
+                None => break,
+            };
+            let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+            let previous_end = res.last().map(|m| m[1].unwrap());
+            from = if start == end { end + 1 } else { end };
+            if start == end && previous_end == Some(start) {
+                continue;
+            }
+            res.push(slots);
+        }
+        Ok(res)
+    }
+}
+
+fn too_large(pattern: &str, message: &str) -> LispError {
+    LispError::new(ErrorType::ParsingError, message).with_object(LispObject::string(pattern))
+}
+
+/// The states of the matcher that were tried, marked with the number of the
+/// search that tried them. The searches for all the matches in a text share
+/// the buffer, and a new search does not have to clear it.
+struct Visited {
+    marks: Vec<u32>,
+    search: u32,
+}
+
+impl Visited {
+    /// Marks a state and returns whether it was not marked yet.
+    fn mark(&mut self, state: usize) -> bool {
+        let seen = self.marks[state] == self.search;
+        self.marks[state] = self.search;
+        !seen
+    }
+
+    /// Starts a search in which no state is marked.
+    fn next_search(&mut self) {
+        if self.search == u32::MAX {
+            self.marks.fill(0);
+            self.search = 0;
+        }
+        self.search += 1;
+    }
+}
+
+/// The argument at `index` as a regex. Strings are compiled.
+fn regex_arg(name: &str, args: &[LispObject], index: usize) -> Result<Rc<Regex>, LispError> {
+    match args[index].ltype() {
+        LispType::Regex(regex) => Ok(regex.clone()),
+        LispType::String(pattern) => Ok(Rc::new(Regex::new(pattern)?)),
+        _ => Err(LispError::type_error(
+            format!(
+                "Argument {} of {} is not a regex or a string",
+                index + 1,
+                name
+            ),
+            args[index].clone(),
+        )),
+    }
+}
+
+fn text_arg(name: &str, args: &[LispObject], index: usize) -> Result<Vec<char>, LispError> {
+    Ok(string_arg(name, args, index)?.chars().collect())
+}
+
+fn substring(text: &[char], start: usize, end: usize) -> LispObject {
+    LispObject::string(text[start..end].iter().collect::<String>())
+}
+
+/// Calls the regex builtin `name`.
+pub fn call_regex_builtin(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    match name {
+        "regex-compile" => {
+            arity(name, args, 1, 1)?;
+            let regex = regex_arg(name, args, 0)?;
+            Ok(LispObject::new_with(LispType::Regex(regex), false))
+        }
+        // Whether the whole string matches.
+        "regex-match" => {
+            arity(name, args, 2, 2)?;
+            let regex = regex_arg(name, args, 0)?;
+            Ok(LispObject::bool(regex.is_match(&text_arg(name, args, 1)?)?))
+        }
+        // The index of the first match, or nil.
+        "regex-search" => {
+            arity(name, args, 2, 2)?;
+            let regex = regex_arg(name, args, 0)?;
+            let text = text_arg(name, args, 1)?;
+            Ok(match regex.search(&text)? {
+                Some(slots) => LispObject::integer(slots[0].unwrap() as i64),
+                None => LispObject::nil(),
+            })
+        }
+        // The first match followed by its groups, or nil.
+        "regex-match-groups" => {
+            arity(name, args, 2, 2)?;
+            let regex = regex_arg(name, args, 0)?;
+            let text = text_arg(name, args, 1)?;
+            let slots = match regex.search(&text)? {
+                Some(slots) => slots,
+                None => return Ok(LispObject::nil()),
+            };
+            let groups = slots
+                .chunks(2)
+                .map(|group| match group {
+                    [Some(start), Some(end)] => substring(&text, *start, *end),
+                    _ => LispObject::nil(),
+                })
+                .collect();
+            Ok(make_list(groups, LispObject::nil()))
+        }
+        // Replaces every match. `\1` to `\9` in the replacement insert a
+        // group, `\0` the whole match and `\\` a backslash.
+        "regex-replace" => {
+            arity(name, args, 3, 3)?;
+            let regex = regex_arg(name, args, 0)?;
+            let text = text_arg(name, args, 1)?;
+            let replacement = text_arg(name, args, 2)?;
+            let mut res = String::new();
+            let mut last = 0;
+            for slots in regex.matches(&text)? {
+                let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+                res.extend(&text[last..start]);
+                let mut chars = replacement.iter();
+                while let Some(c) = chars.next() {
+                    match (c, chars.clone().next()) {
+                        ('\\', Some(d)) if d.is_ascii_digit() => {
+                            chars.next();
+                            let group = d.to_digit(10).unwrap() as usize;
+                            if let Some([Some(s), Some(e)]) = slots.get(2 * group..2 * group + 2) {
+                                res.extend(&text[*s..*e]);
+                            }
+                        }
+                        ('\\', Some('\\')) => {
+                            chars.next();
+                            res.push('\\');
+                        }
+                        (c, _) => res.push(*c),
+                    }
+                }
+                last = end;
+            }
+            res.extend(&text[last..]);
+            Ok(LispObject::string(res))
+        }
+        // The parts between the matches. Empty matches do not split.
+        "regex-split" => {
+            arity(name, args, 2, 2)?;
+            let regex = regex_arg(name, args, 0)?;
+            let text = text_arg(name, args, 1)?;
+            let mut parts = vec![];
+            let mut last = 0;
+            for slots in regex.matches(&text)? {
+                let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+                if start < end {
+                    parts.push(substring(&text, last, start));
+                    last = end;
+                }
+            }
+            parts.push(substring(&text, last, text.len()));
+            Ok(make_list(parts, LispObject::nil()))
+        }
+        _ => unreachable!("{} is not a regex builtin", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::functions::call_builtin;
+    use crate::testutil::{call, check};
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern)
+            .unwrap()
+            .is_match(&text.chars().collect::<Vec<char>>())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_regex_syntax() {
+        let cases = [
+            ("abc", "abc", true),
+            ("abc", "abd", false),
+            ("a.c", "aéc", true),
+            ("a.c", "a\nc", false),
+            ("[a-c]+", "abcab", true),
+            ("[^a-c]+", "xyz", true),
+            ("[^a-c]+", "xaz", false),
+            ("[]a]+", "a]a", true),
+            ("[a-]+", "a-a", true),
+            ("[\\d.]+", "3.14", true),
+            ("\\d+\\s\\w+", "42 ümlaut_1", true),
+            ("\\D", "1", false),
+            ("\\S+", "a b", false),
+            ("a|bc|d", "bc", true),
+            ("(ab)+", "ababab", true),
+            ("(?:ab)+c", "ababc", true),
+            ("a*", "", true),
+            ("a+", "", false),
+            ("colou?r", "color", true),
+            ("a{3}", "aaa", true),
+            ("a{3}", "aaaa", false),
+            ("a{2,}", "aaaaa", true),
+            ("a{2,3}", "a", false),
+            ("a{2,3}", "aaa", true),
+            ("a{,2}", "a{,2}", true),
+            ("x{", "x{", true),
+            ("^a$", "a", true),
+            ("a\\.b", "a.b", true),
+            ("a\\.b", "axb", false),
+            ("(a*)*b", "aaab", true),
+            ("(a|)+", "aa", true),
+            ("\\bfoo\\b", "foo", true),
+            ("a\\Bb", "ab", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                is_match(pattern, text),
+                expected,
+                "{} on {:?}",
+                pattern,
+                text
+            );
+        }
+        // Nested quantifiers do not take exponential time.
+        let text = "a".repeat(40);
+        assert!(!is_match("(a*)*b", &text));
+        assert!(!is_match("(a|aa)+b", &text));
+    }
+
+    #[test]
+    fn test_regex_builtins() {
+        check(&[
+            ("regex-match", "\"\\\\d+\" \"123\"", "t"),
+            ("regex-match", "\"\\\\d+\" \"12a\"", "nil"),
+            ("regex-search", "\"wörld\" \"hällo wörld\"", "6"),
+            ("regex-search", "\"x\" \"abc\"", "nil"),
+            ("regex-search", "\"^b\" \"ab\"", "nil"),
+            (
+                "regex-match-groups",
+                "\"(\\\\w+)@(\\\\w+)(!)?\" \"mail me@host now\"",
+                "'(\"me@host\" \"me\" \"host\" nil)",
+            ),
+            ("regex-match-groups", "\"a\" \"b\"", "nil"),
+            // Greedy and non-greedy quantifiers.
+            (
+                "regex-match-groups",
+                "\"<(.+)>\" \"<a><b>\"",
+                "'(\"<a><b>\" \"a><b\")",
+            ),
+            (
+                "regex-match-groups",
+                "\"<(.+?)>\" \"<a><b>\"",
+                "'(\"<a>\" \"a\")",
+            ),
+            ("regex-match-groups", "\"a{2,3}?\" \"aaaa\"", "'(\"aa\")"),
+            (
+                "regex-replace",
+                "\"(\\\\w+)=(\\\\w+)\" \"a=1, b=2\" \"\\\\2:\\\\1\"",
+                "\"1:a, 2:b\"",
+            ),
+            ("regex-replace", "\"x*\" \"abc\" \"-\"", "\"-a-b-c-\""),
+            (
+                "regex-replace",
+                "\"é\" \"été\" \"\\\\\\\\\"",
+                "\"\\\\t\\\\\"",
+            ),
+            (
+                "regex-split",
+                "\",\\\\s*\" \"a, b,c\"",
+                "'(\"a\" \"b\" \"c\")",
+            ),
+            ("regex-split", "\",\" \",a,\"", "'(\"\" \"a\" \"\")"),
+            ("regex-split", "\"x*\" \"ab\"", "'(\"ab\")"),
+        ]);
+    }
+
+    #[test]
+    fn test_compiled_regex() {
+        let regex = call("regex-compile", "\"(\\\\d+)-(\\\\d+)\"").unwrap();
+        assert_eq!(regex.to_string(), "#<regex \"(\\\\d+)-(\\\\d+)\">");
+        let text = LispObject::string("from 10-20");
+        let res = call_builtin("regex-match-groups", &[regex.clone(), text]).unwrap();
+        assert_eq!(res.to_string(), "'(\"10-20\" \"10\" \"20\")");
+        // A compiled regex is compiled again to the same regex.
+        assert_eq!(
+            call_builtin("regex-compile", std::slice::from_ref(&regex)).unwrap(),
+            regex
+        );
+    }
+
+    #[test]
+    fn test_regex_errors() {
+        let cases = [
+            ("(a", "Invalid regex: Unclosed group"),
+            ("a)", "Invalid regex: Unmatched )"),
+            ("*a", "Invalid regex: Nothing to repeat"),
+            ("[a", "Invalid regex: Unclosed character class"),
+            ("[z-a]", "Invalid regex: Range is out of order"),
+            ("a\\", "Invalid regex: Trailing backslash"),
+            (
+                "a{3,2}",
+                "Invalid regex: Repetition counts are out of order",
+            ),
+            ("a{5000}", "Invalid regex: Repetition count is too large"),
+            ("(?=a)", "Invalid regex: Unknown group type"),
+            (
+                &"(".repeat(1 << 14),
+                "Invalid regex: Regex is nested too deeply",
+            ),
+            (
+                &format!("a{}", "*".repeat(300)),
+                "Invalid regex: Regex is nested too deeply",
+            ),
+        ];
+        for (pattern, message) in cases {
+            let err = Regex::new(pattern).unwrap_err();
+            assert_eq!(err.err_type(), &ErrorType::ParsingError);
+            assert!(err.message().starts_with(message), "{}", err.message());
+        }
+        let err = Regex::new("((a{1000}){1000}){1000}").unwrap_err();
+        assert_eq!(err.err_type(), &ErrorType::ParsingError);
+        assert_eq!(err.message(), "Regex is too large");
+        let text = "a".repeat(1 << 22);
+        let err = call("regex-search", &format!("\"(a|b)*c\" \"{}\"", text)).unwrap_err();
+        assert_eq!(err.err_type(), &ErrorType::ParsingError);
+        assert_eq!(err.message(), "Regex is too large for the text");
+        let err = call("regex-match", "1 \"a\"").unwrap_err();
+        assert_eq!(err.err_type(), &ErrorType::TypeError);
+    }
+}
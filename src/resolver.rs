@@ -0,0 +1,218 @@
+use crate::error::Span;
+use crate::lispobject::{Address, LispObject, LispType};
+
+/// Forms nested deeper than this are left unresolved. Their variables are
+/// looked up by name, which is always correct, so the limit only keeps the
+/// recursion of the resolver off deeply nested code.
+pub const MAX_NESTING: usize = 256;
+
+/// Annotates every reference to a parameter inside a function body with the
+/// lexical address of its binding. The scopes mirror the frames the
+/// evaluator creates: one for the parameters of each lambda and clause, and
+/// an empty one for each block. Free variables are left alone and looked up
+/// by name at runtime.
+pub fn resolve(obj: LispObject) -> LispObject {
+    resolve_form(obj, &mut vec![], 0)
+}
+
+fn resolve_form(obj: LispObject, scopes: &mut Vec<Vec<String>>, nesting: usize) -> LispObject {
+    if obj.is_quoted() || nesting > MAX_NESTING {
+        return obj;
+    }
+
+    if let Some(name) = obj.as_symbol() {
+        for (depth, scope) in scopes.iter().rev().enumerate() {
+            if let Some(index) = scope.iter().rposition(|n| n == name) {
+                return obj.with_address(Address { depth, index });
+            }
+        }
+        return obj;
+    }
+
+    let span = obj.span();
+    let mut list = match obj.ltype() {
+        LispType::List(_) => obj.into_list().unwrap(),
+        _ => return obj,
+    };
+    let nesting = nesting + 1;
+    let head = list
+        .first()
+        .and_then(|h| h.as_symbol())
+        .map(|h| h.to_string());
+
+    match head.as_deref() {
+        Some("quote") | Some("define-condition") => {}
+        Some("function") => {
+            if list.get(1).is_some_and(|arg| arg.as_symbol().is_none()) {
+                list = resolve_from(list, 1, scopes, nesting);
+            }
+        }
+        Some("lambda") => {
+            let params = list.get(1).map(param_names).unwrap_or_default();
+            scopes.push(params);
+            list = resolve_from(list, 2, scopes, nesting);
+            scopes.pop();
+        }
+        // The body of a function is wrapped in a block.
+        Some("defun") | Some("defmacro") => {
+            let params = list.get(2).map(param_names).unwrap_or_default();
+            scopes.push(params);
+            scopes.push(vec![]);
+            list = resolve_from(list, 3, scopes, nesting);
+            scopes.pop();
+            scopes.pop();
+        }
+        Some("block") => {
+            scopes.push(vec![]);
+            list = resolve_from(list, 2, scopes, nesting);
+            scopes.pop();
+        }
+        Some("return-from") => list = resolve_from(list, 2, scopes, nesting),
+        // Clauses are `(name (params) body...)` and called like lambdas.
+        Some("handler-case") | Some("restart-case") => {
+            let clauses = list.split_off(2.min(list.len()));
+            list = resolve_from(list, 1, scopes, nesting);
+            for clause in clauses {
+                let clause = map_list(clause, |mut clause| {
+                    let params = clause.get(1).map(param_names).unwrap_or_default();
+                    scopes.push(params);
+                    clause = resolve_from(clause, 2, scopes, nesting);
+                    scopes.pop();
+                    clause
+                });
+                list.push(clause);
+            }
+        }
+        // Bindings are `(type handler)`, of which only the handler is
+        // evaluated.
+        Some("handler-bind") => {
+            if list.len() > 1 {
+                let bindings = list.remove(1);
+                let bindings = map_list(bindings, |bindings| {
+                    bindings
+                        .into_iter()
+                        .map(|b| map_list(b, |b| resolve_from(b, 1, scopes, nesting)))
+                        .collect()
+                });
+                list.insert(1, bindings);
+            }
+            list = resolve_from(list, 2, scopes, nesting);
+        }
+        Some(_) => list = resolve_from(list, 1, scopes, nesting),
+        None => list = resolve_from(list, 0, scopes, nesting),
+    }
+    rebuild(LispType::list(list), span)
+}
+
+/// Resolves the elements of a list, starting at index `start`.
+fn resolve_from(
+    list: Vec<LispObject>,
+    start: usize,
+    scopes: &mut Vec<Vec<String>>,
+    nesting: usize,
+) -> Vec<LispObject> {
+    list.into_iter()
+        .enumerate()
+        .map(|(i, e)| {
+            if i < start {
+                e
+            } else {
+                resolve_form(e, scopes, nesting)
+            }
+        })
+        .collect()
+}
+
+/// Applies `f` to the elements of an unquoted list. Other objects are
+/// returned as they are.
+fn map_list<F>(obj: LispObject, f: F) -> LispObject
+where
+    F: FnOnce(Vec<LispObject>) -> Vec<LispObject>,
+{
+    if obj.is_quoted() {
+        return obj;
+    }
+    let span = obj.span();
+    match obj.ltype() {
+        LispType::List(_) => rebuild(LispType::list(f(obj.into_list().unwrap())), span),
+        _ => obj,
+    }
+}
+
+fn rebuild(ltype: LispType, span: Option<Span>) -> LispObject {
+    let obj = LispObject::new_with(ltype, false);
+    match span {
+        Some(span) => obj.with_span(span),
+        None => obj,
+    }
+}
+
+/// The names of a parameter list in the order they are bound.
+fn param_names(params: &LispObject) -> Vec<String> {
+    match params.as_list() {
+        Some(l) => l
+            .iter()
+            .filter_map(|p| p.as_symbol())
+            .filter(|p| *p != "&optional" && *p != "&rest")
+            .map(|p| p.to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ast;
+
+    /// Collects the addresses of every symbol with that name.
+    fn addresses(obj: &LispObject, name: &str, found: &mut Vec<Option<Address>>) {
+        if obj.as_symbol() == Some(name) {
+            found.push(obj.address());
+        }
+        if let LispType::List(l) = obj.get_type() {
+            for e in l.iter() {
+                addresses(e, name, found);
+            }
+        }
+    }
+
+    fn resolve_str(code: &str, name: &str) -> Vec<Option<Address>> {
+        let obj = resolve(ast(code).unwrap().remove(0));
+        let mut found = vec![];
+        addresses(&obj, name, &mut found);
+        found
+    }
+
+    #[test]
+    fn test_resolve_params() {
+        let found = resolve_str("(lambda (a &optional b &rest c) (add b c))", "c");
+        assert_eq!(found, [None, Some(Address { depth: 0, index: 2 })]);
+        let found = resolve_str("(lambda (x) (lambda (y) (add x y)))", "x");
+        assert_eq!(found, [None, Some(Address { depth: 1, index: 0 })]);
+        // The body of a defun is inside a block.
+        let found = resolve_str("(defun f (x) x)", "x");
+        assert_eq!(found, [None, Some(Address { depth: 1, index: 0 })]);
+    }
+
+    #[test]
+    fn test_resolve_free_and_quoted() {
+        assert_eq!(resolve_str("(add x 1)", "x"), [None]);
+        assert_eq!(resolve_str("(lambda (x) (add y 'x))", "y"), [None]);
+        assert_eq!(resolve_str("(lambda (x) (quote x))", "x"), [None, None]);
+    }
+
+    #[test]
+    fn test_resolve_clauses() {
+        let found = resolve_str("(lambda (c) (handler-case (f c) (error (c) c)))", "c");
+        let outer = Some(Address { depth: 0, index: 0 });
+        let clause = Some(Address { depth: 0, index: 0 });
+        assert_eq!(found, [None, outer, None, clause]);
+        let found = resolve_str(
+            "(lambda (h) (block b (handler-bind ((error h)) (f h))))",
+            "h",
+        );
+        let inner = Some(Address { depth: 1, index: 0 });
+        assert_eq!(found, [None, inner, inner]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::error::LispError;
+use crate::functions::{arity, make_list, proper_list};
+use crate::lispobject::{LispObject, LispType};
+
+/// The types of sequences. The builtins that take sequences return the same
+/// type of sequence as they got.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    List,
+    Vector,
+    /// The elements of a string are strings of a single character.
+    String,
+}
+
+/// The type and the elements of the argument at `index`, which has to be a
+/// proper list, a vector or a string.
+pub fn sequence(
+    name: &str,
+    args: &[LispObject],
+    index: usize,
+) -> Result<(Kind, Vec<LispObject>), LispError> {
+    match args[index].ltype() {
+        LispType::String(s) => Ok((Kind::String, s.chars().map(LispObject::string).collect())),
+        LispType::Vector(v) => Ok((Kind::Vector, v.to_vec())),
+        LispType::List(_) | LispType::Cons(_) | LispType::Bool(false) => {
+            Ok((Kind::List, proper_list(name, args, index)?))
+        }
+        _ => Err(LispError::type_error(
+            format!("Argument {} of {} is not a sequence", index + 1, name),
+            args[index].clone(),
+        )),
+    }
+}
+
+impl Kind {
+    /// Builds a sequence of this type. The elements of a string have to be
+    /// strings, which are joined.
+    pub fn build(self, name: &str, elements: Vec<LispObject>) -> Result<LispObject, LispError> {
+        match self {
+            Kind::List => Ok(make_list(elements, LispObject::nil())),
+            Kind::Vector => Ok(LispObject::vector(elements)),
+            Kind::String => {
+                let mut res = String::new();
+                for e in elements {
+                    match e.ltype() {
+                        LispType::String(s) => res.push_str(s),
+                        _ => {
+                            return Err(LispError::type_error(
+                                format!("{} can only put strings into a string", name),
+                                e,
+                            ))
+                        }
+                    }
+                }
+                Ok(LispObject::string(res))
+            }
+        }
+    }
+}
+
+/// The argument at `index` as an index into a sequence of `len` elements,
+/// which may also point right behind the last element if `end` is set.
+pub fn index_arg(
+    name: &str,
+    args: &[LispObject],
+    index: usize,
+    len: usize,
+    end: bool,
+) -> Result<usize, LispError> {
+    match args[index].ltype() {
+        LispType::Integer(i) if *i >= 0 && (*i as usize) < len + end as usize => Ok(*i as usize),
+        _ => Err(LispError::type_error(
+            format!("Argument {} of {} is not a valid index", index + 1, name),
+            args[index].clone(),
+        )),
+    }
+}
+
+pub fn vector(args: &[LispObject]) -> Result<LispObject, LispError> {
+    Ok(LispObject::vector(args.to_vec()))
+}
+
+pub fn length(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("length", args, 1, 1)?;
+    Ok(LispObject::integer(sequence_len("length", args, 0)? as i64))
+}
+
+/// The number of elements of the sequence at `index`. Only chains of cons
+/// pairs are walked to count them.
+fn sequence_len(name: &str, args: &[LispObject], index: usize) -> Result<usize, LispError> {
+    match args[index].ltype() {
+        LispType::String(s) => Ok(s.chars().count()),
+        LispType::Vector(v) => Ok(v.len()),
+        LispType::List(l) => Ok(l.len()),
+        _ => Ok(sequence(name, args, index)?.1.len()),
+    }
+}
+
+/// The element at an index.
+pub fn elt(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("elt", args, 2, 2)?;
+    let len = sequence_len("elt", args, 0)?;
+    let i = index_arg("elt", args, 1, len, false)?;
+    match args[0].ltype() {
+        LispType::String(s) => Ok(LispObject::string(s.chars().nth(i).unwrap())),
+        LispType::Vector(v) => Ok(v[i].clone()),
+        LispType::List(l) => Ok(l[i].clone()),
+        _ => Ok(sequence("elt", args, 0)?.1.swap_remove(i)),
+    }
+}
+
+/// The elements from `start` up to `end`, or to the end of the sequence if
+/// `end` is nil or missing.
+pub fn subseq(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("subseq", args, 2, 3)?;
+    let (kind, mut elements) = sequence("subseq", args, 0)?;
+    let end = match args.get(2) {
+        Some(end) if end.is_true() => index_arg("subseq", args, 2, elements.len(), true)?,
+        _ => elements.len(),
+    };
+    let start = index_arg("subseq", args, 1, end, true)?;
+    elements.truncate(end);
+    kind.build("subseq", elements.split_off(start))
+}
+
+/// Joins sequences into one of the type of the first.
+pub fn concatenate(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut res = vec![];
+    let mut res_kind = Kind::List;
+    for i in 0..args.len() {
+        let (kind, mut elements) = sequence("concatenate", args, i)?;
+        if i == 0 {
+            res_kind = kind;
+        }
+        res.append(&mut elements);
+    }
+    res_kind.build("concatenate", res)
+}
+
+pub fn reverse(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("reverse", args, 1, 1)?;
+    let (kind, mut elements) = sequence("reverse", args, 0)?;
+    elements.reverse();
+    kind.build("reverse", elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorType;
+    use crate::testutil::{check, check_errors};
+
+    #[test]
+    fn test_sequences() {
+        check(&[
+            ("length", "\"grüße\"", "5"),
+            ("length", "#(1 2)", "2"),
+            ("length", "#()", "0"),
+            ("length", "(1 2 3)", "3"),
+            ("length", "nil", "0"),
+            ("length", "(a . (b c))", "3"),
+            ("elt", "(a b c) 1", "b"),
+            ("elt", "#(a b c) 2", "c"),
+            ("elt", "(a . (b c)) 2", "c"),
+            ("elt", "\"äbc\" 0", "\"ä\""),
+            ("subseq", "\"hello world\" 6", "\"world\""),
+            ("subseq", "\"héllo\" 1 3", "\"él\""),
+            ("subseq", "#(1 2 3 4) 1 3", "#(2 3)"),
+            ("subseq", "(1 2 3) 1 nil", "'(2 3)"),
+            ("subseq", "(1 2 3) 3", "nil"),
+            ("concatenate", "", "nil"),
+            ("concatenate", "\"ab\" \"\" \"cd\"", "\"abcd\""),
+            ("concatenate", "\"ab\" (\"c\") #(\"d\")", "\"abcd\""),
+            ("concatenate", "#(1) (2 3) \"x\"", "#(1 2 3 \"x\")"),
+            ("concatenate", "(1) #(2)", "'(1 2)"),
+            ("reverse", "\"abç\"", "\"çba\""),
+            ("reverse", "#(1 2 3)", "#(3 2 1)"),
+            ("reverse", "(1 2 3)", "'(3 2 1)"),
+            ("vector", "1 a", "#(1 a)"),
+        ]);
+    }
+
+    #[test]
+    fn test_sequence_errors() {
+        check_errors(
+            &ErrorType::TypeError,
+            &[
+                ("length", "1", "Argument 1 of length is not a sequence"),
+                (
+                    "length",
+                    "(1 . 2)",
+                    "Argument 1 of length is not a proper list",
+                ),
+                ("elt", "(a b) 2", "Argument 2 of elt is not a valid index"),
+                ("elt", "\"\" 0", "Argument 2 of elt is not a valid index"),
+                (
+                    "subseq",
+                    "#(1 2) 3",
+                    "Argument 2 of subseq is not a valid index",
+                ),
+                (
+                    "subseq",
+                    "#(1 2) 2 1",
+                    "Argument 2 of subseq is not a valid index",
+                ),
+                (
+                    "concatenate",
+                    "\"a\" (1)",
+                    "concatenate can only put strings",
+                ),
+            ],
+        );
+    }
+}
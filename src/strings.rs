@@ -0,0 +1,327 @@
+use crate::error::LispError;
+use crate::functions::{arity, make_list, proper_list};
+use crate::lispobject::{LispObject, LispType};
+use crate::sequence::index_arg;
+
+/// The argument at `index`, which has to be a string.
+pub fn string_arg<'a>(
+    name: &str,
+    args: &'a [LispObject],
+    index: usize,
+) -> Result<&'a str, LispError> {
+    match args[index].ltype() {
+        LispType::String(s) => Ok(s),
+        _ => Err(LispError::type_error(
+            format!("Argument {} of {} is not a string", index + 1, name),
+            args[index].clone(),
+        )),
+    }
+}
+
+/// Like `string_arg`, but for the optional argument at `index`.
+fn optional_string<'a>(
+    name: &str,
+    args: &'a [LispObject],
+    index: usize,
+) -> Result<Option<&'a str>, LispError> {
+    match args.get(index) {
+        Some(_) => string_arg(name, args, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The number of characters, which is not the number of bytes.
+pub fn string_length(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-length", args, 1, 1)?;
+    let s = string_arg("string-length", args, 0)?;
+    Ok(LispObject::integer(s.chars().count() as i64))
+}
+
+/// The characters from `start` up to `end`, or to the end of the string if
+/// `end` is nil or missing.
+pub fn substring(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("substring", args, 2, 3)?;
+    let chars = string_arg("substring", args, 0)?
+        .chars()
+        .collect::<Vec<char>>();
+    let end = match args.get(2) {
+        Some(end) if end.is_true() => index_arg("substring", args, 2, chars.len(), true)?,
+        _ => chars.len(),
+    };
+    let start = index_arg("substring", args, 1, end, true)?;
+    Ok(LispObject::string(
+        chars[start..end].iter().collect::<String>(),
+    ))
+}
+
+pub fn string_append(args: &[LispObject]) -> Result<LispObject, LispError> {
+    let mut res = String::new();
+    for i in 0..args.len() {
+        res.push_str(string_arg("string-append", args, i)?);
+    }
+    Ok(LispObject::string(res))
+}
+
+/// Splits a string at a separator, or at whitespace without one. Splitting
+/// at whitespace drops empty parts.
+pub fn string_split(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-split", args, 1, 2)?;
+    let s = string_arg("string-split", args, 0)?;
+    let parts = match optional_string("string-split", args, 1)? {
+        Some("") => {
+            return Err(LispError::type_error(
+                "The separator of string-split is empty",
+                args[1].clone(),
+            ))
+        }
+        Some(separator) => s.split(separator).map(LispObject::string).collect(),
+        None => s.split_whitespace().map(LispObject::string).collect(),
+    };
+    Ok(make_list(parts, LispObject::nil()))
+}
+
+/// Joins a list of strings, with a separator between them if one is given.
+pub fn string_join(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-join", args, 1, 2)?;
+    let parts = proper_list("string-join", args, 0)?;
+    let separator = optional_string("string-join", args, 1)?.unwrap_or("");
+    let parts = (0..parts.len())
+        .map(|i| string_arg("string-join", &parts, i).map(str::to_string))
+        .collect::<Result<Vec<String>, LispError>>()
+        .map_err(|_| LispError::type_error("string-join can only join strings", args[0].clone()))?;
+    Ok(LispObject::string(parts.join(separator)))
+}
+
+/// Removes whitespace, or the characters of the second argument, from both
+/// ends.
+pub fn string_trim(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-trim", args, 1, 2)?;
+    let s = string_arg("string-trim", args, 0)?;
+    let trimmed = match optional_string("string-trim", args, 1)? {
+        Some(chars) => s.trim_matches(|c| chars.contains(c)),
+        None => s.trim(),
+    };
+    Ok(LispObject::string(trimmed))
+}
+
+/// `string-upcase` and `string-downcase`, which may change the length.
+fn change_case(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity(name, args, 1, 1)?;
+    let s = string_arg(name, args, 0)?;
+    Ok(LispObject::string(match name {
+        "string-upcase" => s.to_uppercase(),
+        _ => s.to_lowercase(),
+    }))
+}
+
+/// The character index of the first occurrence of the second string in the
+/// first, or nil.
+pub fn string_contains(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-contains", args, 2, 2)?;
+    let s = string_arg("string-contains", args, 0)?;
+    let part = string_arg("string-contains", args, 1)?;
+    Ok(char_index(s, s.find(part)))
+}
+
+/// `string-prefix?` and `string-suffix?`, which take the prefix or suffix
+/// first.
+fn affix(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity(name, args, 2, 2)?;
+    let affix = string_arg(name, args, 0)?;
+    let s = string_arg(name, args, 1)?;
+    Ok(LispObject::bool(match name {
+        "string-prefix?" => s.starts_with(affix),
+        _ => s.ends_with(affix),
+    }))
+}
+
+/// Replaces every occurrence of the second string with the third.
+pub fn string_replace(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-replace", args, 3, 3)?;
+    let s = string_arg("string-replace", args, 0)?;
+    let from = string_arg("string-replace", args, 1)?;
+    let to = string_arg("string-replace", args, 2)?;
+    if from.is_empty() {
+        return Err(LispError::type_error(
+            "string-replace can not replace an empty string",
+            args[1].clone(),
+        ));
+    }
+    Ok(LispObject::string(s.replace(from, to)))
+}
+
+/// The index of the first occurrence of a character, given as a string of
+/// one character, or nil.
+pub fn string_index(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string-index", args, 2, 2)?;
+    let s = string_arg("string-index", args, 0)?;
+    let mut chars = string_arg("string-index", args, 1)?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(char_index(s, s.find(c))),
+        _ => Err(LispError::type_error(
+            "Argument 2 of string-index is not a single character",
+            args[1].clone(),
+        )),
+    }
+}
+
+/// The characters as strings of one character.
+pub fn string_to_list(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string->list", args, 1, 1)?;
+    let s = string_arg("string->list", args, 0)?;
+    let chars = s.chars().map(LispObject::string).collect();
+    Ok(make_list(chars, LispObject::nil()))
+}
+
+pub fn string_to_symbol(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("string->symbol", args, 1, 1)?;
+    let s = string_arg("string->symbol", args, 0)?;
+    Ok(LispObject::symbol(s).move_quoted())
+}
+
+/// The name of a symbol. `nil` and `t` are symbols as well.
+pub fn symbol_to_string(args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity("symbol->string", args, 1, 1)?;
+    match args[0].ltype() {
+        LispType::Symbol(_) | LispType::Bool(_) => Ok(LispObject::string(args[0].get_string())),
+        _ => Err(LispError::type_error(
+            "Argument 1 of symbol->string is not a symbol",
+            args[0].clone(),
+        )),
+    }
+}
+
+/// `string=` and `string<`, which compare each argument with the next one.
+/// Strings are ordered by their characters.
+fn compare(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    arity(name, args, 1, usize::MAX)?;
+    let strings = (0..args.len())
+        .map(|i| string_arg(name, args, i))
+        .collect::<Result<Vec<&str>, LispError>>()?;
+    let holds = strings.windows(2).all(|pair| match name {
+        "string=" => pair[0] == pair[1],
+        _ => pair[0] < pair[1],
+    });
+    Ok(LispObject::bool(holds))
+}
+
+/// Turns the byte index `find` returned into a character index.
+fn char_index(s: &str, byte_index: Option<usize>) -> LispObject {
+    match byte_index {
+        Some(i) => LispObject::integer(s[..i].chars().count() as i64),
+        None => LispObject::nil(),
+    }
+}
+
+/// Calls the string builtin `name`.
+pub fn call_string_builtin(name: &str, args: &[LispObject]) -> Result<LispObject, LispError> {
+    match name {
+        "string-length" => string_length(args),
+        "substring" => substring(args),
+        "string-append" => string_append(args),
+        "string-split" => string_split(args),
+        "string-join" => string_join(args),
+        "string-trim" => string_trim(args),
+        "string-upcase" | "string-downcase" => change_case(name, args),
+        "string-contains" => string_contains(args),
+        "string-prefix?" | "string-suffix?" => affix(name, args),
+        "string-replace" => string_replace(args),
+        "string-index" => string_index(args),
+        "string->list" => string_to_list(args),
+        "string->symbol" => string_to_symbol(args),
+        "symbol->string" => symbol_to_string(args),
+        "string=" | "string<" => compare(name, args),
+        _ => unreachable!("{} is not a string builtin", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ErrorType;
+    use crate::testutil::{check, check_errors};
+
+    #[test]
+    fn test_strings() {
+        check(&[
+            ("string-length", "\"\"", "0"),
+            ("string-length", "\"naïve 日本\"", "8"),
+            ("substring", "\"日本語です\" 1 3", "\"本語\""),
+            ("substring", "\"héllo\" 2", "\"llo\""),
+            ("substring", "\"abc\" 3", "\"\""),
+            ("string-append", "", "\"\""),
+            ("string-append", "\"ä\" \"b\" \"\"", "\"äb\""),
+            ("string-split", "\"  a b\\tc \"", "'(\"a\" \"b\" \"c\")"),
+            ("string-split", "\"a,,b\" \",\"", "'(\"a\" \"\" \"b\")"),
+            ("string-split", "\"a→b\" \"→\"", "'(\"a\" \"b\")"),
+            ("string-split", "\"\"", "nil"),
+            ("string-join", "(\"a\" \"b\") \", \"", "\"a, b\""),
+            ("string-join", "(\"a\" \"b\")", "\"ab\""),
+            ("string-join", "nil \"-\"", "\"\""),
+            ("string-trim", "\" \\t x y \\n\"", "\"x y\""),
+            ("string-trim", "\"--x-\" \"-\"", "\"x\""),
+            ("string-upcase", "\"straße\"", "\"STRASSE\""),
+            ("string-downcase", "\"ÀB\"", "\"àb\""),
+            ("string-contains", "\"héllo\" \"llo\"", "2"),
+            ("string-contains", "\"abc\" \"\"", "0"),
+            ("string-contains", "\"abc\" \"d\"", "nil"),
+            ("string-prefix?", "\"hé\" \"héllo\"", "t"),
+            ("string-prefix?", "\"lo\" \"héllo\"", "nil"),
+            ("string-suffix?", "\"lo\" \"héllo\"", "t"),
+            ("string-replace", "\"a-b-c\" \"-\" \"→\"", "\"a→b→c\""),
+            ("string-index", "\"añb\" \"b\"", "2"),
+            ("string-index", "\"abc\" \"x\"", "nil"),
+            ("string->list", "\"añ\"", "'(\"a\" \"ñ\")"),
+            ("string->list", "\"\"", "nil"),
+            ("string->symbol", "\"foo\"", "'foo"),
+            ("symbol->string", "'foo", "\"foo\""),
+            ("symbol->string", "nil", "\"nil\""),
+            ("string=", "\"é\" \"é\"", "t"),
+            ("string=", "\"a\" \"a\" \"b\"", "nil"),
+            ("string<", "\"a\" \"b\" \"é\"", "t"),
+            ("string<", "\"b\" \"a\"", "nil"),
+            ("string<", "\"a\" \"a\"", "nil"),
+        ]);
+    }
+
+    #[test]
+    fn test_string_errors() {
+        check_errors(
+            &ErrorType::TypeError,
+            &[
+                (
+                    "string-length",
+                    "a",
+                    "Argument 1 of string-length is not a string",
+                ),
+                ("string-append", "\"a\" 1", "Argument 2 of string-append"),
+                (
+                    "substring",
+                    "\"ab\" 3",
+                    "Argument 2 of substring is not a valid index",
+                ),
+                (
+                    "substring",
+                    "\"ab\" 0 5",
+                    "Argument 3 of substring is not a valid index",
+                ),
+                (
+                    "string-split",
+                    "\"ab\" \"\"",
+                    "The separator of string-split is empty",
+                ),
+                (
+                    "string-join",
+                    "(\"a\" 1)",
+                    "string-join can only join strings",
+                ),
+                (
+                    "string-index",
+                    "\"ab\" \"ab\"",
+                    "Argument 2 of string-index",
+                ),
+                ("symbol->string", "1", "Argument 1 of symbol->string"),
+                ("string<", "\"a\" 1", "Argument 2 of string<"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use crate::ast::ast;
+use crate::error::{ErrorType, LispError};
+use crate::evaluator::eval;
+use crate::functions::call_builtin;
+use crate::lispobject::LispObject;
+use crate::objectmanager::Manager;
+
+/// Calls a builtin with the arguments read from `args`.
+pub fn call(name: &str, args: &str) -> Result<LispObject, LispError> {
+    call_builtin(name, &ast(args).unwrap())
+}
+
+/// Calls the builtins of `(name, args, expected)` cases and compares the
+/// printed results.
+pub fn check(cases: &[(&str, &str, &str)]) {
+    for (name, args, expected) in cases {
+        let res = call(name, args).unwrap();
+        assert_eq!(res.to_string(), *expected, "({} {})", name, args);
+    }
+}
+
+/// Calls the builtins of `(name, args, message)` cases and checks that they
+/// fail with `err_type` and a message that starts with `message`.
+pub fn check_errors(err_type: &ErrorType, cases: &[(&str, &str, &str)]) {
+    for (name, args, message) in cases {
+        let err = call(name, args).unwrap_err();
+        assert_eq!(err.err_type(), err_type, "({} {})", name, args);
+        assert!(err.message().starts_with(message), "{}", err.message());
+    }
+}
+
+/// Evaluates the forms of `code` and returns the value of the last one.
+pub fn eval_str(code: &str, manager: &mut Manager) -> LispObject {
+    let mut res = LispObject::nil();
+    for form in ast(code).unwrap() {
+        res = eval(form, manager).unwrap();
+    }
+    res
+}